@@ -0,0 +1,140 @@
+// Compares the interval based Schedule with the previous one-byte-per-slot
+// arrays. Run with `cargo bench --bench schedule`.
+use std::hint::black_box;
+use std::time::Instant;
+
+// Without the test harness the module's unit tests are dropped, leaving their helpers unused
+#[cfg_attr(test, allow(dead_code))]
+#[path = "../src/schedule.rs"]
+pub mod schedule;
+use schedule::Schedule;
+
+/// The previous representation: one owner byte per slot, scanned on every lookup.
+struct SlotArray {
+    slots: Vec<u8>,
+}
+
+impl SlotArray {
+    fn is_free(&self, start: usize, end: usize) -> bool {
+        end <= self.slots.len() && self.slots[start..end].iter().all(|&x| x == 0)
+    }
+
+    fn insert(&mut self, start: usize, end: usize, owner: u8) -> bool {
+        if !self.is_free(start, end) {
+            return false;
+        }
+        self.slots[start..end].fill(owner);
+        true
+    }
+
+    fn first_free(&self, len: usize, from: usize) -> Option<usize> {
+        (from..=self.slots.len().saturating_sub(len)).find(|&start| self.is_free(start, start + len))
+    }
+
+    fn bookings_in(&self, start: usize, end: usize) -> Vec<(usize, usize, u8)> {
+        let mut bookings: Vec<(usize, usize, u8)> = Vec::new();
+        let mut i = start;
+        // Step back to the start of a run that began before the range
+        while i > 0 && self.slots[i] != 0 && self.slots[i - 1] == self.slots[i] {
+            i -= 1;
+        }
+        while i < end {
+            let owner = self.slots[i];
+            let run_start = i;
+            while i < self.slots.len() && self.slots[i] == owner {
+                i += 1;
+            }
+            if owner != 0 {
+                bookings.push((run_start, i, owner));
+            }
+        }
+        bookings
+    }
+}
+
+/// Small deterministic generator so runs are comparable without extra dependencies.
+struct Lcg(u64);
+
+impl Lcg {
+    fn next(&mut self, bound: usize) -> usize {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        ((self.0 >> 33) % bound as u64) as usize
+    }
+}
+
+fn time<F: FnMut()>(label: &str, iterations: u32, mut f: F) -> f64 {
+    let start = Instant::now();
+    for _ in 0..iterations {
+        f();
+    }
+    let ns = start.elapsed().as_nanos() as f64 / iterations as f64;
+    println!("  {:<28} {:>10.1} ns/op", label, ns);
+    ns
+}
+
+fn run(num_slots: usize, iterations: u32) {
+    println!("{} slots per day:", num_slots);
+    let mut rng = Lcg(num_slots as u64);
+    let mut array = SlotArray { slots: vec![0; num_slots] };
+    let mut schedule = Schedule::new(num_slots as u16);
+
+    // Fill roughly half the day with bookings of 1-8 slots, separated by gaps
+    let mut slot = 0;
+    while slot < num_slots {
+        let len = 1 + rng.next(8);
+        let end = (slot + len).min(num_slots);
+        let owner = 1 + rng.next(255) as u8;
+        assert_eq!(array.insert(slot, end, owner), schedule.insert(slot as u16, end as u16, owner));
+        slot = end + 1 + rng.next(8);
+    }
+    assert_eq!(array.slots, schedule.to_slots());
+
+    let ranges: Vec<(usize, usize)> = (0..1024).map(|_| {
+        let start = rng.next(num_slots);
+        (start, (start + 1 + rng.next(8)).min(num_slots))
+    }).collect();
+    for &(start, end) in &ranges {
+        assert_eq!(array.is_free(start, end), schedule.is_free(start as u16, end as u16));
+        assert_eq!(array.first_free(6, start), schedule.first_free(6, start as u16).map(|s| s as usize));
+    }
+
+    let mut i = 0;
+    let array_free = time("is_free (array)", iterations, || {
+        let (start, end) = ranges[i % ranges.len()];
+        black_box(array.is_free(start, end));
+        i += 1;
+    });
+    let schedule_free = time("is_free (intervals)", iterations, || {
+        let (start, end) = ranges[i % ranges.len()];
+        black_box(schedule.is_free(start as u16, end as u16));
+        i += 1;
+    });
+    let array_first = time("first_free(6) (array)", iterations, || {
+        black_box(array.first_free(6, ranges[i % ranges.len()].0));
+        i += 1;
+    });
+    let schedule_first = time("first_free(6) (intervals)", iterations, || {
+        black_box(schedule.first_free(6, ranges[i % ranges.len()].0 as u16));
+        i += 1;
+    });
+    let window = (num_slots / 4).max(1);
+    let array_list = time("bookings_in (array)", iterations, || {
+        let start = ranges[i % ranges.len()].0.min(num_slots - window);
+        black_box(array.bookings_in(start, start + window));
+        i += 1;
+    });
+    let schedule_list = time("bookings_in (intervals)", iterations, || {
+        let start = ranges[i % ranges.len()].0.min(num_slots - window) as u16;
+        black_box(schedule.bookings_in(start, start + window as u16));
+        i += 1;
+    });
+    println!("  speedup: is_free {:.1}x, first_free {:.1}x, bookings_in {:.1}x",
+        array_free / schedule_free, array_first / schedule_first, array_list / schedule_list);
+}
+
+fn main() {
+    // Today's half-hour slots, then 5 minute and 1 minute granularity
+    for num_slots in [16, 288, 1440] {
+        run(num_slots, 200_000);
+    }
+}
@@ -0,0 +1,682 @@
+//! Generates the message types from src/messages.idl: the Rust types, which
+//! derive the codec and are included by message.rs from OUT_DIR/messages.rs,
+//! and the Python module, written to OUT_DIR/message_gen.py. The copy in
+//! src/message_gen.py is checked in so the Python client runs without a build;
+//! `cargo run --bin gen_python` refreshes it and tests/golden.rs fails while it
+//! is stale. See the top of the IDL file for its syntax.
+use std::fmt::Write;
+use std::{env, fs, path::Path};
+
+const IDL: &str = "src/messages.idl";
+
+fn main() {
+    println!("cargo:rerun-if-changed={}", IDL);
+    println!("cargo:rerun-if-changed=build.rs");
+    let source = fs::read_to_string(IDL).unwrap_or_else(|e| panic!("{}: {}", IDL, e));
+    let idl = Parser::new(&source).and_then(|mut parser| parser.file()).unwrap_or_else(|e| panic!("{}:{}", IDL, e));
+    let out_dir = env::var("OUT_DIR").unwrap();
+    fs::write(Path::new(&out_dir).join("messages.rs"), rust(&idl)).unwrap();
+    fs::write(Path::new(&out_dir).join("message_gen.py"), python(&idl)).unwrap();
+}
+
+// ----- The IDL -----
+
+enum Ty {
+    /// u8, i8, bool, u16 or u32
+    Int(String),
+    String,
+    List(Box<Ty>),
+    Option(Box<Ty>),
+    Tuple(Vec<Ty>),
+    Array(Box<Ty>, usize),
+    /// A struct or enum of the file
+    Named(String),
+}
+
+/// `@name` or `@name(value)` ahead of a field.
+struct Attr {
+    name: String,
+    value: Option<String>,
+}
+
+struct Field {
+    docs: Vec<String>,
+    attrs: Vec<Attr>,
+    /// `None` in tuple variants
+    name: Option<String>,
+    ty: Ty,
+}
+
+enum Fields {
+    Unit,
+    Named(Vec<Field>),
+    Tuple(Vec<Field>),
+}
+
+struct Variant {
+    docs: Vec<String>,
+    name: String,
+    fields: Fields,
+}
+
+enum Kind {
+    Struct(Vec<Field>),
+    Enum(Vec<Variant>),
+}
+
+struct Item {
+    docs: Vec<String>,
+    name: String,
+    kind: Kind,
+}
+
+struct Idl {
+    version_docs: Vec<String>,
+    version: u8,
+    items: Vec<Item>,
+}
+
+impl Item {
+    /// Enums whose variants carry nothing, which are plain values in both languages.
+    fn is_unit_enum(&self) -> bool {
+        matches!(&self.kind, Kind::Enum(variants) if variants.iter().all(|variant| matches!(variant.fields, Fields::Unit)))
+    }
+}
+
+impl Field {
+    fn attr(&self, name: &str) -> Option<&Attr> {
+        self.attrs.iter().find(|attr| attr.name == name)
+    }
+
+    fn attr_value(&self, name: &str) -> Option<&str> {
+        self.attr(name).and_then(|attr| attr.value.as_deref())
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Doc(String),
+    Ident(String),
+    Number(String),
+    Punct(char),
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    pos: usize,
+}
+
+const ATTRIBUTES: [&str; 5] = ["rest", "count", "present_if", "none", "since"];
+
+impl Parser {
+    fn new(source: &str) -> Result<Self, String> {
+        let mut tokens = Vec::new();
+        for (i, line) in source.lines().enumerate() {
+            let line_no = i + 1;
+            let mut chars = line.char_indices().peekable();
+            while let Some(&(start, c)) = chars.peek() {
+                if c.is_whitespace() {
+                    chars.next();
+                } else if let Some(doc) = line[start..].strip_prefix("///") {
+                    tokens.push((line_no, Token::Doc(doc.to_string())));
+                    break;
+                } else if line[start..].starts_with("//") {
+                    break;
+                } else if c.is_ascii_alphanumeric() || c == '_' {
+                    let mut end = start;
+                    while let Some(&(i, c)) = chars.peek() && (c.is_ascii_alphanumeric() || c == '_') {
+                        end = i + c.len_utf8();
+                        chars.next();
+                    }
+                    let word = line[start..end].to_string();
+                    tokens.push((line_no, if c.is_ascii_digit() { Token::Number(word) } else { Token::Ident(word) }));
+                } else if "{}()<>[],:;@".contains(c) {
+                    tokens.push((line_no, Token::Punct(c)));
+                    chars.next();
+                } else {
+                    return Err(format!("{}: unexpected {:?}", line_no, c));
+                }
+            }
+        }
+        Ok(Self { tokens, pos: 0 })
+    }
+
+    fn error<T>(&self, expected: &str) -> Result<T, String> {
+        match self.tokens.get(self.pos) {
+            Some((line, token)) => Err(format!("{}: expected {}, found {:?}", line, expected, token)),
+            None => Err(format!(" expected {}, found the end of the file", expected)),
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(_, token)| token)
+    }
+
+    fn eat(&mut self, punct: char) -> bool {
+        if self.peek() == Some(&Token::Punct(punct)) {
+            self.pos += 1;
+            return true;
+        }
+        false
+    }
+
+    fn expect(&mut self, punct: char) -> Result<(), String> {
+        if self.eat(punct) { Ok(()) } else { self.error(&format!("`{}`", punct)) }
+    }
+
+    fn ident(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Some(Token::Ident(ident)) => {
+                let ident = ident.clone();
+                self.pos += 1;
+                Ok(ident)
+            }
+            _ => self.error("a name"),
+        }
+    }
+
+    fn number(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Some(Token::Number(number)) => {
+                let number = number.clone();
+                self.pos += 1;
+                Ok(number)
+            }
+            _ => self.error("a number"),
+        }
+    }
+
+    fn docs(&mut self) -> Vec<String> {
+        let mut docs = Vec::new();
+        while let Some(Token::Doc(doc)) = self.peek() {
+            docs.push(doc.clone());
+            self.pos += 1;
+        }
+        docs
+    }
+
+    fn file(&mut self) -> Result<Idl, String> {
+        let mut version = None;
+        let mut items = Vec::new();
+        while self.pos < self.tokens.len() {
+            let docs = self.docs();
+            match self.ident()?.as_str() {
+                "version" => {
+                    let number = self.number()?;
+                    version = Some((docs, number.parse().map_err(|_| format!(" version {} is not a u8", number))?));
+                    self.expect(';')?;
+                }
+                "struct" => {
+                    let name = self.ident()?;
+                    self.expect('{')?;
+                    let fields = self.named_fields('}')?;
+                    items.push(Item { docs, name, kind: Kind::Struct(fields) });
+                }
+                "enum" => {
+                    let name = self.ident()?;
+                    self.expect('{')?;
+                    let mut variants = Vec::new();
+                    while !self.eat('}') {
+                        variants.push(self.variant()?);
+                        if !self.eat(',') {
+                            self.expect('}')?;
+                            break;
+                        }
+                    }
+                    items.push(Item { docs, name, kind: Kind::Enum(variants) });
+                }
+                _ => {
+                    self.pos -= 1;
+                    return self.error("`version`, `struct` or `enum`");
+                }
+            }
+        }
+        let (version_docs, version) = version.ok_or(" no `version`")?;
+        Ok(Idl { version_docs, version, items })
+    }
+
+    fn variant(&mut self) -> Result<Variant, String> {
+        let docs = self.docs();
+        let name = self.ident()?;
+        let fields = if self.eat('{') {
+            Fields::Named(self.named_fields('}')?)
+        } else if self.eat('(') {
+            let mut fields = Vec::new();
+            while !self.eat(')') {
+                fields.push(Field { docs: Vec::new(), attrs: Vec::new(), name: None, ty: self.ty()? });
+                if !self.eat(',') {
+                    self.expect(')')?;
+                    break;
+                }
+            }
+            Fields::Tuple(fields)
+        } else {
+            Fields::Unit
+        };
+        Ok(Variant { docs, name, fields })
+    }
+
+    /// `name: type` fields up to `close`, which is consumed.
+    fn named_fields(&mut self, close: char) -> Result<Vec<Field>, String> {
+        let mut fields = Vec::new();
+        while !self.eat(close) {
+            let docs = self.docs();
+            let mut attrs = Vec::new();
+            while self.eat('@') {
+                let name = self.ident()?;
+                if !ATTRIBUTES.contains(&name.as_str()) {
+                    self.pos -= 1;
+                    return self.error(&format!("one of the attributes {:?}", ATTRIBUTES));
+                }
+                let value = if self.eat('(') {
+                    let value = match self.peek() {
+                        Some(Token::Number(_)) => self.number()?,
+                        _ => self.ident()?,
+                    };
+                    self.expect(')')?;
+                    Some(value)
+                } else {
+                    None
+                };
+                attrs.push(Attr { name, value });
+            }
+            let name = self.ident()?;
+            self.expect(':')?;
+            fields.push(Field { docs, attrs, name: Some(name), ty: self.ty()? });
+            if !self.eat(',') {
+                self.expect(close)?;
+                break;
+            }
+        }
+        Ok(fields)
+    }
+
+    fn ty(&mut self) -> Result<Ty, String> {
+        if self.eat('(') {
+            let mut types = vec![self.ty()?];
+            while self.eat(',') {
+                types.push(self.ty()?);
+            }
+            self.expect(')')?;
+            return Ok(Ty::Tuple(types));
+        }
+        if self.eat('[') {
+            let item = self.ty()?;
+            self.expect(';')?;
+            let len = self.number()?;
+            self.expect(']')?;
+            return Ok(Ty::Array(Box::new(item), len.parse().unwrap()));
+        }
+        let name = self.ident()?;
+        Ok(match name.as_str() {
+            "u8" | "i8" | "bool" | "u16" | "u32" => Ty::Int(name),
+            "string" => Ty::String,
+            "list" | "option" => {
+                self.expect('<')?;
+                let item = Box::new(self.ty()?);
+                self.expect('>')?;
+                if name == "list" { Ty::List(item) } else { Ty::Option(item) }
+            }
+            _ => Ty::Named(name),
+        })
+    }
+}
+
+// ----- Rust -----
+
+fn rust_type(ty: &Ty) -> String {
+    match ty {
+        Ty::Int(name) => name.clone(),
+        Ty::String => "String".to_string(),
+        Ty::List(item) => format!("Vec<{}>", rust_type(item)),
+        Ty::Option(item) => format!("Option<{}>", rust_type(item)),
+        Ty::Tuple(types) => format!("({})", types.iter().map(rust_type).collect::<Vec<_>>().join(", ")),
+        Ty::Array(item, len) => format!("[{}; {}]", rust_type(item), len),
+        Ty::Named(name) => name.clone(),
+    }
+}
+
+fn rust_docs(out: &mut String, docs: &[String], indent: &str) {
+    for doc in docs {
+        writeln!(out, "{}///{}", indent, doc).unwrap();
+    }
+}
+
+fn rust_fields(out: &mut String, fields: &[Field], indent: &str, visibility: &str) {
+    for field in fields {
+        rust_docs(out, &field.docs, indent);
+        for attr in &field.attrs {
+            match &attr.value {
+                Some(value) => writeln!(out, "{}#[codec({} = {})]", indent, attr.name, value).unwrap(),
+                None => writeln!(out, "{}#[codec({})]", indent, attr.name).unwrap(),
+            }
+        }
+        writeln!(out, "{}{}{}: {},", indent, visibility, field.name.as_ref().unwrap(), rust_type(&field.ty)).unwrap();
+    }
+}
+
+fn rust(idl: &Idl) -> String {
+    let mut out = String::from("// Generated by build.rs from src/messages.idl, do not edit.\n\n");
+    rust_docs(&mut out, &idl.version_docs, "");
+    writeln!(out, "pub const PROTOCOL_VERSION: u8 = {};", idl.version).unwrap();
+    for item in &idl.items {
+        out.push('\n');
+        rust_docs(&mut out, &item.docs, "");
+        match &item.kind {
+            Kind::Struct(fields) => {
+                writeln!(out, "#[derive(Debug, Clone, PartialEq, Eq, Hash, Encode, Decode, Serialize, Deserialize)]").unwrap();
+                writeln!(out, "pub struct {} {{", item.name).unwrap();
+                rust_fields(&mut out, fields, "    ", "pub ");
+            }
+            Kind::Enum(variants) => {
+                if variants.iter().any(|variant| variant.name.contains('_')) {
+                    writeln!(out, "#[allow(non_camel_case_types)]").unwrap();
+                }
+                if item.is_unit_enum() {
+                    // The first variant is what a field added in a later version decodes as
+                    writeln!(out, "#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Encode, Decode, Serialize, Deserialize)]").unwrap();
+                } else {
+                    writeln!(out, "#[derive(Debug, Clone, PartialEq, Eq, Hash, Encode, Decode, Serialize, Deserialize)]").unwrap();
+                }
+                let default = item.is_unit_enum().then_some(0);
+                writeln!(out, "pub enum {} {{", item.name).unwrap();
+                for (i, variant) in variants.iter().enumerate() {
+                    rust_docs(&mut out, &variant.docs, "    ");
+                    match &variant.fields {
+                        Fields::Unit if default == Some(i) => writeln!(out, "    #[default]\n    {},", variant.name).unwrap(),
+                        Fields::Unit => writeln!(out, "    {},", variant.name).unwrap(),
+                        Fields::Tuple(fields) => {
+                            let types: Vec<String> = fields.iter().map(|field| rust_type(&field.ty)).collect();
+                            writeln!(out, "    {}({}),", variant.name, types.join(", ")).unwrap();
+                        }
+                        Fields::Named(fields) => {
+                            writeln!(out, "    {} {{", variant.name).unwrap();
+                            rust_fields(&mut out, fields, "        ", "");
+                            writeln!(out, "    }},").unwrap();
+                        }
+                    }
+                }
+            }
+        }
+        writeln!(out, "}}").unwrap();
+    }
+    out
+}
+
+// ----- Python -----
+
+/// `FacilityNotFound` as `FACILITY_NOT_FOUND`, the way Python names enum members.
+fn upper_snake(name: &str) -> String {
+    let mut snake = String::new();
+    let mut previous_lower = false;
+    for c in name.chars() {
+        if c.is_ascii_uppercase() && previous_lower {
+            snake.push('_');
+        }
+        previous_lower = c.is_ascii_lowercase() || c.is_ascii_digit();
+        snake.push(c.to_ascii_uppercase());
+    }
+    snake
+}
+
+fn is_u8(ty: &Ty) -> bool {
+    matches!(ty, Ty::Int(name) if name == "u8")
+}
+
+fn python_type(ty: &Ty) -> String {
+    match ty {
+        Ty::Int(name) if name == "bool" => "bool".to_string(),
+        Ty::Int(_) => "int".to_string(),
+        Ty::String => "str".to_string(),
+        Ty::List(item) | Ty::Array(item, _) if is_u8(item) => "bytes".to_string(),
+        Ty::List(item) | Ty::Array(item, _) => format!("List[{}]", python_type(item)),
+        Ty::Option(item) => format!("Optional[{}]", python_type(item)),
+        Ty::Tuple(types) => format!("Tuple[{}]", types.iter().map(python_type).collect::<Vec<_>>().join(", ")),
+        Ty::Named(name) => name.clone(),
+    }
+}
+
+/// What a field missing from older versions decodes as, like `Default::default()`.
+fn python_default(idl: &Idl, ty: &Ty) -> String {
+    match ty {
+        Ty::Int(name) if name == "bool" => "False".to_string(),
+        Ty::Int(_) => "0".to_string(),
+        Ty::String => "\"\"".to_string(),
+        Ty::List(item) if is_u8(item) => "b\"\"".to_string(),
+        Ty::List(_) => "[]".to_string(),
+        Ty::Array(item, len) if is_u8(item) => format!("bytes({})", len),
+        Ty::Option(_) => "None".to_string(),
+        Ty::Named(name) if idl.items.iter().any(|item| item.name == *name && item.is_unit_enum()) => format!("{}(0)", name),
+        _ => panic!("{}: no default for a {} field, which `@since` needs", IDL, rust_type(ty)),
+    }
+}
+
+/// Statements writing `value` of type `ty`, `count` overriding the width of a list count.
+fn python_encode(out: &mut String, ty: &Ty, value: &str, count: Option<&str>, indent: &str, depth: usize) {
+    let count_call = |len: String| match count {
+        Some(width) => format!("writer.count({}, \"{}\")", len, width),
+        None => format!("writer.count({})", len),
+    };
+    match ty {
+        Ty::Int(name) => writeln!(out, "{}writer.{}({})", indent, name, value).unwrap(),
+        Ty::String => writeln!(out, "{}writer.string({})", indent, value).unwrap(),
+        Ty::List(item) if is_u8(item) => {
+            writeln!(out, "{}{}", indent, count_call(format!("len({})", value))).unwrap();
+            writeln!(out, "{}writer.raw({})", indent, value).unwrap();
+        }
+        Ty::List(item) => {
+            writeln!(out, "{}{}", indent, count_call(format!("len({})", value))).unwrap();
+            python_items(out, item, value, indent, depth);
+        }
+        Ty::Array(item, _) if is_u8(item) => writeln!(out, "{}writer.raw({})", indent, value).unwrap(),
+        Ty::Array(item, _) => python_items(out, item, value, indent, depth),
+        Ty::Option(item) => {
+            writeln!(out, "{}writer.bool({} is not None)", indent, value).unwrap();
+            writeln!(out, "{}if {} is not None:", indent, value).unwrap();
+            python_encode(out, item, value, None, &format!("{}    ", indent), depth);
+        }
+        Ty::Tuple(types) => {
+            for (i, ty) in types.iter().enumerate() {
+                python_encode(out, ty, &format!("{}[{}]", value, i), None, indent, depth);
+            }
+        }
+        Ty::Named(_) => writeln!(out, "{}{}.encode(writer)", indent, value).unwrap(),
+    }
+}
+
+/// Writes each item of `value` without a count.
+fn python_items(out: &mut String, item: &Ty, value: &str, indent: &str, depth: usize) {
+    if is_u8(item) {
+        writeln!(out, "{}writer.raw({})", indent, value).unwrap();
+        return;
+    }
+    let name = format!("item{}", depth);
+    writeln!(out, "{}for {} in {}:", indent, name, value).unwrap();
+    python_encode(out, item, &name, None, &format!("{}    ", indent), depth + 1);
+}
+
+/// An expression reading a value of type `ty`, `count` overriding the width of a list count.
+fn python_decode(ty: &Ty, count: Option<&str>) -> String {
+    let count_call = match count {
+        Some(width) => format!("reader.count(\"{}\")", width),
+        None => "reader.count()".to_string(),
+    };
+    match ty {
+        Ty::Int(name) => format!("reader.{}()", name),
+        Ty::String => "reader.string()".to_string(),
+        Ty::List(item) if is_u8(item) => format!("reader.take({})", count_call),
+        Ty::List(item) => format!("[{} for _ in range({})]", python_decode(item, None), count_call),
+        Ty::Array(item, len) if is_u8(item) => format!("reader.take({})", len),
+        Ty::Array(item, len) => format!("[{} for _ in range({})]", python_decode(item, None), len),
+        Ty::Option(item) => format!("({} if reader.bool() else None)", python_decode(item, None)),
+        Ty::Tuple(types) => format!("({})", types.iter().map(|ty| python_decode(ty, None)).collect::<Vec<_>>().join(", ")),
+        Ty::Named(name) => format!("{}.decode(reader)", name),
+    }
+}
+
+fn python_comments(out: &mut String, docs: &[String], indent: &str) {
+    for doc in docs {
+        writeln!(out, "{}#{}", indent, doc).unwrap();
+    }
+}
+
+fn python_docstring(out: &mut String, docs: &[String], indent: &str) {
+    match docs {
+        [] => {}
+        [doc] => writeln!(out, "{}\"\"\"{}\"\"\"", indent, doc.trim()).unwrap(),
+        _ => {
+            writeln!(out, "{}\"\"\"{}", indent, docs[0].trim()).unwrap();
+            for doc in &docs[1..] {
+                writeln!(out, "{}{}", indent, doc.trim()).unwrap();
+            }
+            writeln!(out, "{}\"\"\"", indent).unwrap();
+        }
+    }
+}
+
+fn python_field_name(field: &Field, i: usize) -> String {
+    field.name.clone().unwrap_or_else(|| format!("field{}", i))
+}
+
+/// A dataclass with `fields`, encoded after `tag` when it is an enum variant.
+fn python_class(out: &mut String, idl: &Idl, name: &str, base: &str, docs: &[String], fields: &[Field], tag: Option<usize>) {
+    writeln!(out, "\n\n@dataclass\nclass {}({}):", name, base).unwrap();
+    python_docstring(out, docs, "    ");
+    if let Some(tag) = tag {
+        writeln!(out, "    TAG = {}", tag).unwrap();
+    }
+    for (i, field) in fields.iter().enumerate() {
+        python_comments(out, &field.docs, "    ");
+        writeln!(out, "    {}: {}", python_field_name(field, i), python_type(&field.ty)).unwrap();
+    }
+
+    let encode = if tag.is_some() { "encode_fields" } else { "encode" };
+    writeln!(out, "\n    def {}(self, writer: Writer) -> None:", encode).unwrap();
+    if fields.is_empty() {
+        writeln!(out, "        pass").unwrap();
+    }
+    for (i, field) in fields.iter().enumerate() {
+        let value = format!("self.{}", python_field_name(field, i));
+        let (indent, depth) = match field.attr_value("since") {
+            Some(since) => {
+                writeln!(out, "        if writer.version >= {}:", since).unwrap();
+                ("            ", 0)
+            }
+            None => ("        ", 0),
+        };
+        let count = field.attr_value("count");
+        if field.attr("rest").is_some() {
+            let Ty::List(item) = &field.ty else { panic!("{}: @rest on {}, which is not a list", IDL, value) };
+            writeln!(out, "{}if writer.self_delimiting():", indent).unwrap();
+            python_encode(out, &field.ty, &value, count, &format!("{}    ", indent), depth);
+            writeln!(out, "{}else:", indent).unwrap();
+            python_items(out, item, &value, &format!("{}    ", indent), depth);
+        } else if field.attr("present_if").is_some() {
+            let Ty::Option(item) = &field.ty else { panic!("{}: @present_if on {}, which is not an option", IDL, value) };
+            writeln!(out, "{}if {} is not None:", indent, value).unwrap();
+            python_encode(out, item, &value, None, &format!("{}    ", indent), depth);
+        } else if let Some(none) = field.attr_value("none") {
+            let Ty::Option(item) = &field.ty else { panic!("{}: @none on {}, which is not an option", IDL, value) };
+            python_encode(out, item, &format!("{} if {} is None else {}", none, value, value), None, indent, depth);
+        } else {
+            python_encode(out, &field.ty, &value, count, indent, depth);
+        }
+    }
+
+    let decode = if tag.is_some() { "decode_fields" } else { "decode" };
+    writeln!(out, "\n    @classmethod\n    def {}(cls, reader: Reader) -> {}:", decode, name).unwrap();
+    let mut names = Vec::new();
+    for (i, field) in fields.iter().enumerate() {
+        let count = field.attr_value("count");
+        let mut value = if field.attr("rest").is_some() {
+            let Ty::List(item) = &field.ty else { unreachable!() };
+            let to_end = if is_u8(item) { "reader.rest()".to_string() } else { format!("reader.until_end(lambda: {})", python_decode(item, None)) };
+            format!("{} if reader.self_delimiting() else {}", python_decode(&field.ty, count), to_end)
+        } else if let Some(flag) = field.attr_value("present_if") {
+            let Ty::Option(item) = &field.ty else { unreachable!() };
+            format!("{} if {} else None", python_decode(item, None), flag)
+        } else if let Some(none) = field.attr_value("none") {
+            let Ty::Option(item) = &field.ty else { unreachable!() };
+            format!("none_if({}, {})", python_decode(item, None), none)
+        } else {
+            python_decode(&field.ty, count)
+        };
+        if let Some(since) = field.attr_value("since") {
+            if value.contains(" if ") {
+                value = format!("({})", value);
+            }
+            value = format!("{} if reader.version >= {} else {}", value, since, python_default(idl, &field.ty));
+        }
+        let name = python_field_name(field, i);
+        writeln!(out, "        {} = {}", name, value).unwrap();
+        names.push(name);
+    }
+    writeln!(out, "        return cls({})", names.join(", ")).unwrap();
+}
+
+fn python(idl: &Idl) -> String {
+    let mut out = String::from("# Generated by build.rs from src/messages.idl, do not edit.\n");
+    out += "\"\"\"The messages of the protocol, see src/messages.idl and codec.py.\"\"\"\n";
+    out += "from __future__ import annotations\n\n";
+    out += "from dataclasses import dataclass\nfrom enum import Enum\nfrom typing import List, Optional, Tuple\n\n";
+    out += "from codec import Reader, Writer, none_if\n\n";
+    python_comments(&mut out, &idl.version_docs, "");
+    writeln!(out, "PROTOCOL_VERSION = {}", idl.version).unwrap();
+    out += r#"
+
+class Message:
+    """Whole messages in the layout of a protocol version, this build's by default."""
+
+    def to_bytes(self, version: int = PROTOCOL_VERSION) -> bytes:
+        writer = Writer(version)
+        self.encode(writer)
+        return writer.into_bytes()
+
+    @classmethod
+    def from_bytes(cls, data: bytes, version: int = PROTOCOL_VERSION):
+        return cls.decode(Reader(data, version))
+"#;
+    for item in &idl.items {
+        match &item.kind {
+            Kind::Struct(fields) => python_class(&mut out, idl, &item.name, "Message", &item.docs, fields, None),
+            Kind::Enum(variants) if item.is_unit_enum() => {
+                writeln!(out, "\n\nclass {}(Message, Enum):", item.name).unwrap();
+                python_docstring(&mut out, &item.docs, "    ");
+                for (i, variant) in variants.iter().enumerate() {
+                    python_comments(&mut out, &variant.docs, "    ");
+                    writeln!(out, "    {} = {}", upper_snake(&variant.name), i).unwrap();
+                }
+                writeln!(out, "\n    def encode(self, writer: Writer) -> None:\n        writer.u8(self.value)").unwrap();
+                writeln!(out, "\n    @classmethod\n    def decode(cls, reader: Reader) -> {}:\n        return cls(reader.u8())", item.name).unwrap();
+            }
+            Kind::Enum(variants) => {
+                writeln!(out, "\n\nclass {}(Message):", item.name).unwrap();
+                let mut docs = item.docs.clone();
+                let classes: Vec<String> = variants.iter().map(|variant| format!("{}.{}", item.name, variant.name)).collect();
+                docs.push(format!(" Each variant is a subclass: {}.", classes.join(", ")));
+                python_docstring(&mut out, &docs, "    ");
+                writeln!(out, "    TAG: int\n    VARIANTS: list").unwrap();
+                writeln!(out, "\n    def encode(self, writer: Writer) -> None:\n        writer.u8(self.TAG)\n        self.encode_fields(writer)").unwrap();
+                writeln!(out, "\n    @classmethod\n    def decode(cls, reader: Reader) -> {}:", item.name).unwrap();
+                writeln!(out, "        tag = reader.u8()\n        if tag >= len(cls.VARIANTS):").unwrap();
+                writeln!(out, "            raise ValueError(f\"Invalid {} tag: {{tag}}\")", item.name).unwrap();
+                writeln!(out, "        return cls.VARIANTS[tag].decode_fields(reader)").unwrap();
+                for (tag, variant) in variants.iter().enumerate() {
+                    let fields: &[Field] = match &variant.fields {
+                        Fields::Unit => &[],
+                        Fields::Named(fields) | Fields::Tuple(fields) => fields,
+                    };
+                    let class = format!("{}{}", item.name, variant.name);
+                    python_class(&mut out, idl, &class, &item.name, &variant.docs, fields, Some(tag));
+                }
+                out.push_str("\n\n");
+                for variant in variants {
+                    writeln!(out, "{}.{} = {}{}", item.name, variant.name, item.name, variant.name).unwrap();
+                }
+                let classes: Vec<String> = variants.iter().map(|variant| format!("{}{}", item.name, variant.name)).collect();
+                writeln!(out, "{}.VARIANTS = [{}]", item.name, classes.join(", ")).unwrap();
+            }
+        }
+    }
+    out
+}
@@ -0,0 +1,301 @@
+//! Derives `Encode` and `Decode` of the `codec` module of the crate using
+//! them, which is expected at `crate::codec`. Fields are written in the order
+//! they are declared. Enums lead with a u8 tag, the index of the variant, then
+//! the fields of that variant; a tag naming no variant fails to decode.
+//!
+//! Field attributes cover the layouts the field type alone doesn't give:
+//!
+//! - `#[codec(rest)]` on a list: before protocol version 2 there is no count,
+//!   the items run to the end of the message
+//! - `#[codec(count = u32)]` on a list: the count is of that type in every version
+//! - `#[codec(present_if = field)]` on an `Option`: only on the wire when the
+//!   earlier bool `field` is set
+//! - `#[codec(none = 0)]` on an `Option`: `None` is written as that value of
+//!   the inner type
+//! - `#[codec(since = 3)]`: only on the wire from that protocol version, older
+//!   versions decode the field as its `Default`
+//!
+//! Any other `Option` is a presence byte followed by the value.
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Expr, Field, Fields, GenericArgument, Ident, PathArguments, Type};
+
+#[proc_macro_derive(Encode, attributes(codec))]
+pub fn derive_encode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    encode(&input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+#[proc_macro_derive(Decode, attributes(codec))]
+pub fn derive_decode(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    decode(&input).unwrap_or_else(syn::Error::into_compile_error).into()
+}
+
+/// How one field is laid out on the wire.
+enum Layout {
+    Plain,
+    Rest,
+    Count(Type),
+    PresentIf(Ident),
+    NoneAs(Expr),
+}
+
+/// The layout of a field and the protocol version it was added in.
+fn layout(field: &Field) -> syn::Result<(Layout, Option<Expr>)> {
+    let mut layout = Layout::Plain;
+    let mut since: Option<Expr> = None;
+    for attr in field.attrs.iter().filter(|attr| attr.path().is_ident("codec")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("since") {
+                since = Some(meta.value()?.parse()?);
+                return Ok(());
+            }
+            layout = if meta.path.is_ident("rest") {
+                Layout::Rest
+            } else if meta.path.is_ident("count") {
+                Layout::Count(meta.value()?.parse()?)
+            } else if meta.path.is_ident("present_if") {
+                Layout::PresentIf(meta.value()?.parse()?)
+            } else if meta.path.is_ident("none") {
+                Layout::NoneAs(meta.value()?.parse()?)
+            } else {
+                return Err(meta.error("expected `rest`, `count`, `present_if`, `none` or `since`"));
+            };
+            Ok(())
+        })?;
+    }
+    Ok((layout, since))
+}
+
+/// `T` of an `Option<T>` field.
+fn option_inner(field: &Field) -> syn::Result<&Type> {
+    if let Type::Path(path) = &field.ty
+        && let Some(segment) = path.path.segments.last()
+        && segment.ident == "Option"
+        && let PathArguments::AngleBracketed(arguments) = &segment.arguments
+        && let Some(GenericArgument::Type(inner)) = arguments.args.first()
+    {
+        return Ok(inner);
+    }
+    Err(syn::Error::new_spanned(&field.ty, "expected an Option"))
+}
+
+/// Names the fields are bound to: their own for named fields, `field0`,
+/// `field1`... for tuple fields.
+fn bindings(fields: &Fields) -> Vec<Ident> {
+    fields.iter().enumerate()
+        .map(|(i, field)| field.ident.clone().unwrap_or_else(|| format_ident!("field{}", i)))
+        .collect()
+}
+
+/// Writes each field, `bindings` being references to their values.
+fn encode_fields(fields: &Fields, bindings: &[Ident]) -> syn::Result<TokenStream2> {
+    let mut body = TokenStream2::new();
+    for (field, value) in fields.iter().zip(bindings) {
+        let (layout, since) = layout(field)?;
+        let write = match layout {
+            Layout::Plain => quote! { writer.put(#value); },
+            Layout::Rest => quote! {
+                if writer.self_delimiting() {
+                    writer.put(#value);
+                } else {
+                    for item in #value {
+                        writer.put(item);
+                    }
+                }
+            },
+            Layout::Count(count) => quote! {
+                writer.put(&<#count>::try_from(#value.len()).unwrap_or_else(|_| panic!("a list of {} items does not fit a {} count", #value.len(), stringify!(#count))));
+                for item in #value {
+                    writer.put(item);
+                }
+            },
+            Layout::PresentIf(_) => {
+                option_inner(field)?;
+                quote! {
+                    if let Some(value) = #value {
+                        writer.put(value);
+                    }
+                }
+            }
+            Layout::NoneAs(none) => {
+                let inner = option_inner(field)?;
+                quote! {
+                    match #value {
+                        Some(value) => writer.put(value),
+                        None => writer.put::<#inner>(&(#none)),
+                    };
+                }
+            }
+        };
+        body.extend(match since {
+            Some(since) => quote! {
+                if writer.version() >= #since {
+                    #write
+                }
+            },
+            None => write,
+        });
+    }
+    Ok(body)
+}
+
+/// Reads each field into a local named after its binding, returning early
+/// with the error of the first one that does not decode.
+fn decode_fields(fields: &Fields, bindings: &[Ident]) -> syn::Result<TokenStream2> {
+    let mut body = TokenStream2::new();
+    for (field, name) in fields.iter().zip(bindings) {
+        let ty = &field.ty;
+        let (layout, since) = layout(field)?;
+        let value = match layout {
+            Layout::Plain => quote! { reader.get()? },
+            Layout::Rest => quote! {
+                if reader.self_delimiting() {
+                    reader.get()?
+                } else {
+                    let mut items = Vec::new();
+                    while reader.remaining() > 0 {
+                        items.push(reader.get()?);
+                    }
+                    items
+                }
+            },
+            Layout::Count(count) => quote! {{
+                let count: #count = reader.get()?;
+                (0..count).map(|_| reader.get()).collect::<Result<_, _>>()?
+            }},
+            Layout::PresentIf(flag) => {
+                option_inner(field)?;
+                quote! { if #flag { Some(reader.get()?) } else { None } }
+            }
+            Layout::NoneAs(none) => {
+                let inner = option_inner(field)?;
+                quote! {{
+                    let value: #inner = reader.get()?;
+                    if value == (#none) { None } else { Some(value) }
+                }}
+            }
+        };
+        let value = match since {
+            Some(since) => quote! {
+                if reader.version() >= #since { #value } else { Default::default() }
+            },
+            None => value,
+        };
+        body.extend(quote! { let #name: #ty = #value; });
+    }
+    Ok(body)
+}
+
+/// `Name { a, b }`, `Name(field0, field1)` or `Name`, for matching and building.
+fn constructor(path: TokenStream2, fields: &Fields, bindings: &[Ident]) -> TokenStream2 {
+    match fields {
+        Fields::Named(_) => quote! { #path { #(#bindings),* } },
+        Fields::Unnamed(_) => quote! { #path(#(#bindings),*) },
+        Fields::Unit => path,
+    }
+}
+
+fn tags(input: &DeriveInput, count: usize) -> syn::Result<Vec<u8>> {
+    if count > 256 {
+        return Err(syn::Error::new_spanned(&input.ident, "more variants than a u8 tag can tell apart"));
+    }
+    Ok((0..count).map(|tag| tag as u8).collect())
+}
+
+fn encode(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let bindings = bindings(&data.fields);
+            let pattern = constructor(quote! { Self }, &data.fields, &bindings);
+            let fields = encode_fields(&data.fields, &bindings)?;
+            quote! {
+                let #pattern = self;
+                #fields
+            }
+        }
+        Data::Enum(data) => {
+            let tags = tags(input, data.variants.len())?;
+            let mut arms = TokenStream2::new();
+            for (variant, tag) in data.variants.iter().zip(tags) {
+                let ident = &variant.ident;
+                let bindings = bindings(&variant.fields);
+                let pattern = constructor(quote! { Self::#ident }, &variant.fields, &bindings);
+                let fields = encode_fields(&variant.fields, &bindings)?;
+                arms.extend(quote! {
+                    #pattern => {
+                        writer.put(&#tag);
+                        #fields
+                    }
+                });
+            }
+            quote! {
+                match self {
+                    #arms
+                }
+            }
+        }
+        Data::Union(_) => return Err(syn::Error::new_spanned(&input.ident, "unions have no wire layout")),
+    };
+    let name = &input.ident;
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics crate::codec::Encode for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn encode(&self, writer: &mut crate::codec::Writer) {
+                #body
+            }
+        }
+    })
+}
+
+fn decode(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let body = match &input.data {
+        Data::Struct(data) => {
+            let bindings = bindings(&data.fields);
+            let fields = decode_fields(&data.fields, &bindings)?;
+            let value = constructor(quote! { Self }, &data.fields, &bindings);
+            quote! {
+                #fields
+                Ok(#value)
+            }
+        }
+        Data::Enum(data) => {
+            let tags = tags(input, data.variants.len())?;
+            let mut arms = TokenStream2::new();
+            for (variant, tag) in data.variants.iter().zip(tags) {
+                let ident = &variant.ident;
+                let bindings = bindings(&variant.fields);
+                let fields = decode_fields(&variant.fields, &bindings)?;
+                let value = constructor(quote! { Self::#ident }, &variant.fields, &bindings);
+                arms.extend(quote! {
+                    #tag => {
+                        #fields
+                        Ok(#value)
+                    }
+                });
+            }
+            let type_name = name.to_string();
+            quote! {
+                let tag: u8 = reader.get()?;
+                match tag {
+                    #arms
+                    _ => Err(crate::codec::DecodeError::InvalidTag { type_name: #type_name, tag }),
+                }
+            }
+        }
+        Data::Union(_) => return Err(syn::Error::new_spanned(name, "unions have no wire layout")),
+    };
+    let (impl_generics, ty_generics, where_clause) = input.generics.split_for_impl();
+    Ok(quote! {
+        impl #impl_generics crate::codec::Decode for #name #ty_generics #where_clause {
+            #[allow(unused_variables)]
+            fn decode(reader: &mut crate::codec::Reader) -> Result<Self, crate::codec::DecodeError> {
+                #body
+            }
+        }
+    })
+}
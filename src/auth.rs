@@ -0,0 +1,355 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use crate::message::Token;
+
+/// How long a token issued by LOGIN is accepted.
+pub const TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// PBKDF2 rounds turning a password into the key kept in the users file.
+const ROUNDS: u32 = 100_000;
+/// How often a server lets one address LOGIN, as every attempt costs ROUNDS.
+pub const LOGIN_INTERVAL: Duration = Duration::from_secs(1);
+/// Length of the stamp and MAC ending every request servers send each other.
+pub const PEER_MAC_LEN: usize = 8 + 32;
+/// How far the stamp of a request between servers may be from the clock of
+/// the server receiving it, which also bounds how long it remembers stamps.
+pub const PEER_REQUEST_WINDOW: Duration = Duration::from_secs(30);
+
+/// A user's salt, the key derived from their password with it, and whether
+/// they may change the bookings of others.
+struct User {
+    salt: [u8; 16],
+    key: [u8; 32],
+    admin: bool,
+}
+
+/// The credentials of a deployment, read from a file every server is given:
+/// a `secret <hex>` line holding the key tokens are signed with, and a
+/// `user <id> <salt hex> <key hex>` line per user, followed by `admin` for
+/// admins. Tokens carry their own signature, so any server of the deployment
+/// accepts those of another.
+pub struct Users {
+    secret: [u8; 32],
+    users: HashMap<u8, User>,
+}
+
+/// Signs the requests servers send each other, PREPARE, REPLICATE, RAFT and
+/// the like, so only holders of the users file can make them. Derived from the
+/// secret rather than being it, so no token MAC passes for a request's.
+#[derive(Clone)]
+pub struct PeerKey([u8; 32]);
+
+/// The stamps of the requests between servers accepted within the last
+/// PEER_REQUEST_WINDOW, so that a captured request is refused when replayed:
+/// as stale once it is older than that, as seen before until then.
+#[derive(Default)]
+pub struct SeenRequests {
+    stamps: BTreeSet<(u64, [u8; 32])>,
+}
+
+/// Microseconds since the epoch, and never the same twice in this process, so
+/// no two requests it signs carry the same stamp.
+fn next_stamp() -> u64 {
+    static LAST: AtomicU64 = AtomicU64::new(0);
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64;
+    let last = LAST.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |last| Some(now.max(last + 1))).unwrap();
+    now.max(last + 1)
+}
+
+impl PeerKey {
+    fn mac(&self, request: &[u8]) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.0).unwrap();
+        mac.update(request);
+        mac
+    }
+
+    /// Appends the current stamp and the MAC of `request` and stamp, which
+    /// covers the request from the protocol version on.
+    pub fn sign(&self, request: &mut Vec<u8>) {
+        self.sign_at(request, next_stamp());
+    }
+
+    fn sign_at(&self, request: &mut Vec<u8>, stamp: u64) {
+        request.extend_from_slice(&stamp.to_le_bytes());
+        let mac = self.mac(request).finalize().into_bytes();
+        request.extend_from_slice(&mac);
+    }
+
+    /// Checks that `request` ends with a genuine stamp and MAC of what comes
+    /// before, and that the stamp is fresh, recording it in `seen` if so.
+    pub fn verify(&self, request: &[u8], seen: &mut SeenRequests) -> Result<(), &'static str> {
+        let Some(split) = request.len().checked_sub(PEER_MAC_LEN) else {
+            return Err("it is not signed with the secret");
+        };
+        let (signed, mac) = request.split_at(split + 8);
+        self.mac(signed).verify_slice(mac).map_err(|_| "it is not signed with the secret")?;
+        let stamp = u64::from_le_bytes(signed[split..].try_into().unwrap());
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_micros() as u64;
+        let window = PEER_REQUEST_WINDOW.as_micros() as u64;
+        if stamp.abs_diff(now) > window {
+            return Err("its stamp is too far from our clock");
+        }
+        // Stamps older than the window are refused above, so they need not be remembered
+        seen.stamps = seen.stamps.split_off(&(now.saturating_sub(window), [0; 32]));
+        if !seen.stamps.insert((stamp, mac.try_into().unwrap())) {
+            return Err("it was seen before");
+        }
+        Ok(())
+    }
+}
+
+/// `request` signed with `key`, or as it is for deployments without credentials.
+pub fn sign_request(key: Option<&PeerKey>, mut request: Vec<u8>) -> Vec<u8> {
+    if let Some(key) = key {
+        key.sign(&mut request);
+    }
+    request
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len()).step_by(2).map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok()).collect()
+}
+
+fn derive_key(password: &str, salt: &[u8]) -> [u8; 32] {
+    pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(password.as_bytes(), salt, ROUNDS)
+}
+
+fn now() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32
+}
+
+impl Users {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Cannot read users {}: {}", path, e))?;
+        Self::parse(&text, path)
+    }
+
+    /// Reads the lines of a users file, see `Users`. Errors name `origin`.
+    pub fn parse(text: &str, origin: &str) -> Result<Self, String> {
+        let mut secret: Option<[u8; 32]> = None;
+        let mut users: HashMap<u8, User> = HashMap::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            let invalid = || format!("{}:{}: cannot parse '{}'", origin, number + 1, line);
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [] => {}
+                ["secret", key] => secret = Some(from_hex(key).and_then(|key| key.try_into().ok()).ok_or_else(invalid)?),
+                ["user", id, salt, key, role @ ..] if matches!(role, [] | ["admin"]) => {
+                    let id: u8 = id.parse().map_err(|_| invalid())?;
+                    let salt = from_hex(salt).and_then(|salt| salt.try_into().ok()).ok_or_else(invalid)?;
+                    let key = from_hex(key).and_then(|key| key.try_into().ok()).ok_or_else(invalid)?;
+                    users.insert(id, User { salt, key, admin: !role.is_empty() });
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        let secret = secret.ok_or_else(|| format!("{}: no secret line", origin))?;
+        Ok(Self { secret, users })
+    }
+
+    /// Adds `user_id` with `password` to the users file at `path`, creating it
+    /// with a fresh secret if it does not exist. A user added twice keeps the
+    /// last password and role.
+    pub fn add(path: &str, user_id: u8, password: &str, admin: bool) -> Result<(), String> {
+        let mut file = OpenOptions::new().create(true).append(true).open(path).map_err(|e| format!("Cannot open users {}: {}", path, e))?;
+        let mut text = String::new();
+        if file.metadata().map_err(|e| e.to_string())?.len() == 0 {
+            let mut secret = [0u8; 32];
+            getrandom::getrandom(&mut secret).map_err(|e| e.to_string())?;
+            text += &format!("secret {}\n", to_hex(&secret));
+        }
+        text += &Self::user_line(user_id, password, admin)?;
+        file.write_all(text.as_bytes()).map_err(|e| format!("Cannot write users {}: {}", path, e))
+    }
+
+    /// The line of a users file for `user_id`, with a fresh salt.
+    fn user_line(user_id: u8, password: &str, admin: bool) -> Result<String, String> {
+        let mut salt = [0u8; 16];
+        getrandom::getrandom(&mut salt).map_err(|e| e.to_string())?;
+        Ok(format!("user {} {} {}{}\n", user_id, to_hex(&salt), to_hex(&derive_key(password, &salt)), if admin { " admin" } else { "" }))
+    }
+
+    /// A token of `user_id` if `password` is theirs.
+    pub fn login(&self, user_id: u8, password: &str) -> Option<Token> {
+        let user = self.users.get(&user_id)?;
+        // Compared in constant time, so timing tells nothing of the key
+        let key = derive_key(password, &user.salt);
+        if key.iter().zip(user.key).fold(0, |diff, (a, b)| diff | (a ^ b)) != 0 {
+            return None;
+        }
+        let expires = now().saturating_add(TOKEN_LIFETIME.as_secs() as u32);
+        Some(Token { user_id, expires, mac: self.sign(user_id, expires) })
+    }
+
+    pub fn peer_key(&self) -> PeerKey {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
+        mac.update(b"peer requests");
+        PeerKey(mac.finalize().into_bytes().into())
+    }
+
+    pub fn is_admin(&self, user_id: u8) -> bool {
+        self.users.get(&user_id).is_some_and(|user| user.admin)
+    }
+
+    /// The user a token was issued to, if it is genuine and has not expired.
+    pub fn verify(&self, token: &Token) -> Result<u8, &'static str> {
+        self.mac(token.user_id, token.expires).verify_slice(&token.mac).map_err(|_| "the token is not genuine")?;
+        if token.expires <= now() {
+            return Err("the token has expired, log in again");
+        }
+        Ok(token.user_id)
+    }
+
+    fn mac(&self, user_id: u8, expires: u32) -> Hmac<Sha256> {
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret).unwrap();
+        mac.update(&[user_id]);
+        mac.update(&expires.to_le_bytes());
+        mac
+    }
+
+    fn sign(&self, user_id: u8, expires: u32) -> [u8; 32] {
+        self.mac(user_id, expires).finalize().into_bytes().into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Credentials signing with `secret`, with no users.
+    fn users(secret: u8) -> Users {
+        Users { secret: [secret; 32], users: HashMap::new() }
+    }
+
+    fn parse(text: &str) -> Result<Users, String> {
+        Users::parse(text, "users.conf")
+    }
+
+    /// Why a users file holding `text` is refused.
+    fn parse_error(text: &str) -> String {
+        match parse(text) {
+            Ok(_) => panic!("{:?} was accepted", text),
+            Err(error) => error,
+        }
+    }
+
+    #[test]
+    fn parse_reads_users_and_admins() {
+        let salt = to_hex(&[1; 16]);
+        let key = to_hex(&[2; 32]);
+        let users = parse(&format!("secret {}\n# comment\nuser 1 {salt} {key}\nuser 2 {salt} {key} admin\n", to_hex(&[3; 32]))).unwrap();
+        assert_eq!(users.secret, [3; 32]);
+        assert!(!users.is_admin(1));
+        assert!(users.is_admin(2));
+        assert!(!users.is_admin(3));
+    }
+
+    #[test]
+    fn parse_refuses_bad_hex() {
+        let secret = format!("secret {}\n", to_hex(&[3; 32]));
+        let key = to_hex(&[2; 32]);
+        assert!(parse_error(&format!("secret {}zz\n", to_hex(&[3; 31]))).contains(":1: cannot parse"));
+        assert!(parse_error(&format!("{secret}user 1 0g{} {key}\n", to_hex(&[1; 15]))).contains(":2: cannot parse"));
+        assert!(parse_error(&format!("{secret}user 1 {} {}\n", to_hex(&[1; 16]), to_hex(&[2; 31]))).contains(":2: cannot parse"));
+        assert!(parse_error(&format!("{secret}user 1 {} {key}0\n", to_hex(&[1; 16]))).contains(":2: cannot parse"));
+        assert!(parse_error(&format!("{secret}user 1 {} {key} root\n", to_hex(&[1; 16]))).contains(":2: cannot parse"));
+    }
+
+    #[test]
+    fn parse_needs_a_secret() {
+        let error = parse_error(&format!("user 1 {} {}\n", to_hex(&[1; 16]), to_hex(&[2; 32])));
+        assert!(error.ends_with(": no secret line"), "{}", error);
+    }
+
+    #[test]
+    fn login_checks_the_password() {
+        let users = parse(&format!("secret {}\n{}", to_hex(&[3; 32]), Users::user_line(1, "right", false).unwrap())).unwrap();
+        assert!(users.login(1, "wrong").is_none());
+        assert!(users.login(2, "right").is_none());
+        let token = users.login(1, "right").unwrap();
+        assert_eq!(users.verify(&token), Ok(1));
+    }
+
+    #[test]
+    fn verify_refuses_tampered_tokens() {
+        let credentials = users(7);
+        let expires = now() + 60;
+        let token = Token { user_id: 1, expires, mac: credentials.sign(1, expires) };
+        assert_eq!(credentials.verify(&token), Ok(1));
+        let mut mac = token.mac;
+        mac[0] ^= 1;
+        assert_eq!(credentials.verify(&Token { mac, ..token }), Err("the token is not genuine"));
+        assert!(credentials.verify(&Token { user_id: 2, ..token }).is_err());
+        assert!(credentials.verify(&Token { expires: expires + 1, ..token }).is_err());
+        assert!(users(8).verify(&token).is_err());
+    }
+
+    #[test]
+    fn verify_refuses_expired_tokens() {
+        let credentials = users(7);
+        let expires = now() - 1;
+        let token = Token { user_id: 1, expires, mac: credentials.sign(1, expires) };
+        assert_eq!(credentials.verify(&token), Err("the token has expired, log in again"));
+    }
+
+    #[test]
+    fn peer_requests_need_the_secret() {
+        let key = users(7).peer_key();
+        let request = sign_request(Some(&key), vec![5, 0, 1, 2, 3]);
+        assert_eq!(request.len(), 5 + PEER_MAC_LEN);
+        assert_eq!(users(8).peer_key().verify(&request, &mut SeenRequests::default()), Err("it is not signed with the secret"));
+        let mut tampered = request.clone();
+        tampered[2] ^= 1;
+        assert!(key.verify(&tampered, &mut SeenRequests::default()).is_err());
+        // The stamp is signed too
+        tampered = request.clone();
+        tampered[5] ^= 1;
+        assert!(key.verify(&tampered, &mut SeenRequests::default()).is_err());
+        assert!(key.verify(&request[..PEER_MAC_LEN - 1], &mut SeenRequests::default()).is_err());
+        assert_eq!(key.verify(&request, &mut SeenRequests::default()), Ok(()));
+        assert_eq!(sign_request(None, vec![5, 0, 1]), vec![5, 0, 1]);
+    }
+
+    #[test]
+    fn peer_requests_are_accepted_once() {
+        let key = users(7).peer_key();
+        let mut seen = SeenRequests::default();
+        let first = sign_request(Some(&key), vec![5, 0, 1]);
+        let second = sign_request(Some(&key), vec![5, 0, 1]);
+        assert_ne!(first, second);
+        assert_eq!(key.verify(&first, &mut seen), Ok(()));
+        assert_eq!(key.verify(&first, &mut seen), Err("it was seen before"));
+        assert_eq!(key.verify(&second, &mut seen), Ok(()));
+    }
+
+    #[test]
+    fn stale_peer_requests_are_refused() {
+        let key = users(7).peer_key();
+        let now = next_stamp();
+        let window = PEER_REQUEST_WINDOW.as_micros() as u64;
+        let mut seen = SeenRequests::default();
+        for stamp in [now - window - 1_000_000, now + window + 1_000_000] {
+            let mut request = vec![5, 0, 1];
+            key.sign_at(&mut request, stamp);
+            assert_eq!(key.verify(&request, &mut seen), Err("its stamp is too far from our clock"));
+        }
+        // Accepted stamps are only remembered for as long as they are fresh
+        let mut old = vec![5, 0, 1];
+        key.sign_at(&mut old, now - window + 1_000_000);
+        assert_eq!(key.verify(&old, &mut seen), Ok(()));
+        assert_eq!(key.verify(&sign_request(Some(&key), vec![5, 0, 1]), &mut seen), Ok(()));
+        assert_eq!(seen.stamps.len(), 2);
+    }
+}
@@ -1,16 +1,20 @@
 # src/client.py
 import argparse
 import socket
+import sys
 import time
 from typing import Dict
 
+from codec import Reader
 from message import (
-    RequestType, Day,
+    RequestType, ErrorCode,
     QueryRequest, QueryResponse,
-    Booking, BookingResponse,
+    Booking, BookRequest, BookingResponse,
     Update, UpdateResponse,
-    Monitor, FacilityRecord,
-    read_string, read_u8
+    Login, LoginResponse, Token,
+    Monitor, FacilityRecord, ReplyStatus,
+    PROTOCOL_VERSION, MAX_DATAGRAM, fragments, parse_fragment,
+    day_from_string, request
 )
 
 def print_slots(day_slots: bytes) -> None:
@@ -20,33 +24,73 @@ def print_slots(day_slots: bytes) -> None:
         status = "Available" if v == 0 else f"Booked by {v}"
         print(f"{hour:02d}:{minute} - {status}")
 
-_next_req_id = -1
-def next_req_id() -> int:
-    global _next_req_id
-    _next_req_id = (_next_req_id + 1) & 0xFF
-    return _next_req_id
+# Message ids tie replies to requests; 0 is left for monitor pushes
+_next_msg_id = time.time_ns() & 0xFFFFFFFF
+def next_msg_id() -> int:
+    global _next_msg_id
+    _next_msg_id = (_next_msg_id + 1) & 0xFFFFFFFF or 1
+    return _next_msg_id
+
+def recv_message(sock: socket.socket, msg_id: int) -> bytes:
+    """Collects the fragments of the reply carrying msg_id, ignoring anything else."""
+    parts = {}
+    while True:
+        data, _ = sock.recvfrom(MAX_DATAGRAM)
+        fragment = parse_fragment(data)
+        if fragment is None or fragment[0] != msg_id:
+            continue
+        _, index, count, payload = fragment
+        parts[index] = payload
+        if len(parts) == count:
+            return b"".join(parts[i] for i in range(count))
+
+def load_token(path: str):
+    """The token saved by the last login, like Connection::token in client.rs."""
+    try:
+        with open(path) as f:
+            return Token.from_bytes(bytes.fromhex(f.read().strip()), PROTOCOL_VERSION)
+    except (OSError, ValueError):
+        return None
 
 def send_and_recv(sock: socket.socket,
                   server_addr,
                   out_bytes: bytes,
                   timeout_s: float = 3.0,
                   retries: int = 2,
-                  semantics: str = "alo") -> bytes:
+                  semantics: str = "alo") -> Reader:
+    """Returns a reader at the payload of the reply, following redirects."""
 
     sock.settimeout(timeout_s)
     attempts = 1 if semantics == "amo" else (retries + 1)
-    last_err = None
-    for _ in range(attempts):
-        try:
-            sock.sendto(out_bytes, server_addr)
-            data, _ = sock.recvfrom(4096)
-            return data
-        except Exception as e:
-            last_err = e
-            continue
-    if last_err:
-        raise last_err
-    raise RuntimeError("send_and_recv: unexpected empty last_err")
+    for _ in range(4):
+        msg_id = next_msg_id()
+        last_err = None
+        for _ in range(attempts):
+            try:
+                for datagram in fragments(msg_id, out_bytes):
+                    sock.sendto(datagram, server_addr)
+                data = recv_message(sock, msg_id)
+                break
+            except Exception as e:
+                last_err = e
+                continue
+        else:
+            if last_err:
+                raise last_err
+            raise RuntimeError("send_and_recv: unexpected empty last_err")
+
+        # Led by the protocol version, the one the request was sent in
+        reader = Reader.versioned(data)
+        status = ReplyStatus.decode(reader)
+        if isinstance(status, ReplyStatus.Ok):
+            return reader
+        if isinstance(status, ReplyStatus.UnsupportedVersion):
+            raise RuntimeError(f"unsupported version {PROTOCOL_VERSION}, the server speaks "
+                               f"versions {status.min} to {status.max}")
+        address = status.owner if isinstance(status, ReplyStatus.WrongShard) else status.leader
+        host, port_s = address.rsplit(":", 1)
+        server_addr = (host, int(port_s))
+    raise RuntimeError("send_and_recv: too many redirects")
 
 def main():
     parser = argparse.ArgumentParser(description="UDP Client for Facility Reservation")
@@ -59,6 +103,8 @@ def main():
 
     parser.add_argument("-s", "--server", required=True,
                         help="server in form ip:port, e.g. 127.0.0.1:5000")
+    parser.add_argument("--token-file", default=".facility_client_token",
+                        help="token of the last login, sent with bookings and updates")
     subparsers = parser.add_subparsers(dest="command", required=True)
 
     # query
@@ -80,6 +126,11 @@ def main():
     p_update.add_argument("-c", "--confirmation-id", required=True, type=int, help="confirmation id")
     p_update.add_argument("-o", "--offset", required=True, type=int, help="offset slots (can be negative)")
 
+    # login
+    p_login = subparsers.add_parser("login", help="log in, saving the token to --token-file")
+    p_login.add_argument("-u", "--user-id", required=True, type=int, help="user id (0..255)")
+    p_login.add_argument("-p", "--password", required=True, help="password")
+
     # monitor
     p_monitor = subparsers.add_parser("monitor", help="monitor facility updates for duration seconds")
     p_monitor.add_argument("-d", "--duration", required=True, type=int, help="duration seconds")
@@ -99,74 +150,68 @@ def main():
     sock = socket.socket(socket.AF_INET, socket.SOCK_DGRAM)
 
     if args.command == "query":
-        days = [Day.from_string(d) for d in args.days]
+        days = [day_from_string(d) for d in args.days]
         req = QueryRequest(name=args.name, days=days)
 
-        rid = next_req_id()
-        output = bytearray([RequestType.QUERY.value, rid])
-        output.extend(req.serialize())
+        reader = send_and_recv(sock, server_addr, request(RequestType.QUERY, req),
+                               timeout_s=args.timeout, retries=args.retries, semantics=args.semantics)
 
-        data = send_and_recv(sock, server_addr, bytes(output),
-                             timeout_s=args.timeout, retries=args.retries, semantics=args.semantics)
-
-        pos = 2
-        resp = QueryResponse.deserialize(data, pos)
+        resp = QueryResponse.decode(reader)
+        if resp.error != ErrorCode.NONE:
+            print(f"Facility {args.name} not found")
+            sys.exit(resp.error.value)
 
         for i in range(len(days)):
-            day_slots = resp.available[i * 16:(i + 1) * 16]
-            print(f"\n{days[i].name}:")
+            day_slots = resp.availaible[i * 16:(i + 1) * 16]
+            print(f"\n{days[i].name.capitalize()}:")
             print_slots(day_slots)
 
     elif args.command == "book":
         booking = Booking(
             facility_name=args.name,
-            day=Day.from_string(args.day),
+            day=day_from_string(args.day),
             start_slot=args.start_slot,
             num_slots=args.num_slots,
             user_id=args.user_id
         )
 
-        rid = next_req_id()
-        output = bytearray([RequestType.BOOK.value, rid])
-        output.extend(booking.serialize())
-
-        data = send_and_recv(sock, server_addr, bytes(output),
-                             timeout_s=args.timeout, retries=args.retries, semantics=args.semantics)
+        book = BookRequest(auth=load_token(args.token_file), booking=booking)
+        reader = send_and_recv(sock, server_addr, request(RequestType.BOOK, book),
+                               timeout_s=args.timeout, retries=args.retries, semantics=args.semantics)
 
-        pos = 2
-        resp = BookingResponse.deserialize(data, pos)
-        print(f"Booking Response: confirmation_id={resp.confirmation_id}, message={resp.message}")
+        resp = BookingResponse.decode(reader)
+        print(f"Booking Response: success={resp.success}, confirmation_id={resp.confirmation_id}, error={resp.error.name}, message={resp.message}")
+        sys.exit(resp.error.value)
 
     
     elif args.command == "update":
-         upd = Update(confirmation_id=args.confirmation_id, offset=args.offset)
-         rid = next_req_id()
-         output = bytearray([RequestType.UPDATE.value, rid])
-         output.extend(upd.serialize())
-
-         data = send_and_recv(sock, server_addr, bytes(output),
-                         timeout_s=args.timeout, retries=args.retries, semantics=args.semantics)
-
-    # --- 新增：响应头校验 ---
-         resp_type = data[0]
-         resp_id   = data[1]
-         if resp_type != RequestType.UPDATE.value:
-             print(f"[WARN] unexpected resp_type={resp_type} (expect UPDATE=2), resp_id={resp_id}")
-         if resp_id != rid:
-              print(f"[WARN] mismatched resp_id={resp_id} (expect {rid})")
-   
-         pos = 2
-         resp = UpdateResponse.deserialize(data, pos)
-         ok = "success" if resp.status == 0 else "failure"   # 修正成功/失败判断
-         print(f"Update Response: {ok}, message={resp.message}")
+         upd = Update(auth=load_token(args.token_file), confirmation_id=args.confirmation_id, offset=args.offset)
+         reader = send_and_recv(sock, server_addr, request(RequestType.UPDATE, upd),
+                                timeout_s=args.timeout, retries=args.retries, semantics=args.semantics)
 
+         resp = UpdateResponse.decode(reader)
+         ok = "success" if resp.success else "failure"
+         print(f"Update Response: {ok}, error={resp.error.name}, message={resp.message}")
+         sys.exit(resp.error.value)
+
+
+    elif args.command == "login":
+        login = Login(user_id=args.user_id, password=args.password)
+        reader = send_and_recv(sock, server_addr, request(RequestType.LOGIN, login),
+                               timeout_s=args.timeout, retries=args.retries, semantics=args.semantics)
+
+        resp = LoginResponse.decode(reader)
+        if resp.token is None:
+            print(f"Login Response: success={resp.success}, error={resp.error.name}, message={resp.message}")
+            sys.exit(resp.error.value)
+        with open(args.token_file, "w") as f:
+            f.write(resp.token.to_bytes().hex() + "\n")
+        print(f"Logged in as user {args.user_id}, token saved to {args.token_file}")
 
     elif args.command == "monitor":
-        rid = next_req_id()
         mon = Monitor(duration=args.duration)
-        output = bytearray([RequestType.MONITOR.value, rid])
-        output.extend(mon.serialize())
-        sock.sendto(bytes(output), server_addr)
+        for datagram in fragments(next_msg_id(), request(RequestType.MONITOR, mon)):
+            sock.sendto(datagram, server_addr)
 
         sock.settimeout(5.0)
         start_time = time.time()
@@ -174,11 +219,11 @@ def main():
 
         while time.time() - start_time < args.duration:
             try:
-                data, _addr = sock.recvfrom(4096)
+                # Updates are pushed as message 0
+                data = recv_message(sock, 0)
 
                 try:
-                
-                    record = FacilityRecord.deserialize(data, 0)
+                    record = FacilityRecord.decode(Reader.versioned(data))
                     print(str(record))
                 except Exception as parse_err:
                     print(f"Error parsing facility update: {parse_err}")
@@ -192,11 +237,9 @@ def main():
 
         try:
     
-            rid = next_req_id()
             cancel = Monitor(duration=0)
-            output = bytearray([RequestType.MONITOR.value, rid])
-            output.extend(cancel.serialize())
-            sock.sendto(bytes(output), server_addr)
+            for datagram in fragments(next_msg_id(), request(RequestType.MONITOR, cancel)):
+                sock.sendto(datagram, server_addr)
         except Exception:
             pass
         print(f"Monitoring ended after {args.duration} seconds.")
@@ -1,16 +1,49 @@
-use std::io::ErrorKind;
+use std::collections::BTreeMap;
+use std::fs;
+use std::process;
+use std::thread;
 use std::{net::UdpSocket};
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use std::fmt;
-use std::{time::{Duration, Instant}};
+use std::cell::Cell;
+use std::{time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
 pub mod message;
-use message::{FacilityRecord, RequestType, QueryRequest, QueryResponse, Booking, BookingResponse, Update, UpdateResponse, Day};
+pub mod schedule;
+pub mod shard;
+pub mod ical;
+pub mod fragment;
+pub mod codec;
+pub mod tcp;
+pub mod auth;
+use shard::ShardMap;
+use fragment::{Datagram, Reassembler};
+use auth::{PeerKey, Users};
+use codec::{Decode, Encode, Format, Reader, Writer};
+use serde::{de::DeserializeOwned, Serialize};
+use message::{ErrorCode, BookRequest, Login, LoginResponse, Token, ReplyStatus, Monitor, FacilityRecord, RequestType, QueryRequest, QueryResponse, Booking, BookingResponse, Update, UpdateResponse, GetBooking, GetBookingResponse, ListBookings, ListBookingsResponse, Export, ExportResponse, FacilityStats, StatsResponse, Promote, MultiBook, MultiBookResponse, Day, slot_time, PROTOCOL_VERSION};
 
 #[derive(Parser, Debug)]
 #[command(name = "Facility CLI", about = "A UDP client for facility booking system")]
 struct Cli {
-    #[arg(short, long, default_value = "127.0.0.1:5000")]
-    server: String,
+    /// Servers of the deployment, tried in turn until one answers; the others are found through its shard map
+    #[arg(short, long, value_delimiter = ',', default_value = "127.0.0.1:5000")]
+    server: Vec<String>,
+
+    /// File remembering the last server that answered, which is tried first next time
+    #[arg(long, default_value = ".facility_client_state")]
+    state_file: String,
+
+    /// File keeping the token of the last login, sent with every request that changes bookings
+    #[arg(long, default_value = ".facility_client_token")]
+    token_file: String,
+
+    /// Talk to the servers over UDP datagrams or length-prefixed TCP frames
+    #[arg(long, value_enum, default_value_t = Transport::Udp)]
+    transport: Transport,
+
+    /// Encoding of requests and replies; the server answers in the format it is sent
+    #[arg(long, value_enum, default_value_t = Format::Binary)]
+    wire_format: Format,
 
     #[command(subcommand)]
     command: Commands,
@@ -40,6 +73,20 @@ enum Commands {
         user_id: u8,
     },
 
+    /// Book the same slots in several facilities, all or none of them
+    MultiBook {
+        #[arg(long, value_delimiter = ',')]
+        names: Vec<String>,
+        #[arg(short, long)]
+        day: String,
+        #[arg(short, long)]
+        start_slot: u8,
+        #[arg(long)]
+        num_slots: u8,
+        #[arg(short, long)]
+        user_id: u8,
+    },
+
     /// Update booking
     Update {
         #[arg(short, long)]
@@ -51,9 +98,65 @@ enum Commands {
     Monitor {
         #[arg(short, long)]
         duration: u32,
+    },
+
+    /// Look up a booking by confirmation id
+    GetBooking {
+        #[arg(short, long)]
+        confirmation_id: u8,
+    },
+
+    /// List every booking made by a user
+    MyBookings {
+        #[arg(short, long)]
+        user_id: u8,
+    },
+
+    /// Export a facility's schedule or a user's bookings as an iCalendar (.ics) file
+    Export {
+        #[arg(short, long, conflicts_with = "user_id", required_unless_present = "user_id")]
+        name: Option<String>,
+        #[arg(short, long)]
+        user_id: Option<u8>,
+        /// File to write the calendar to, stdout if omitted
+        #[arg(short, long)]
+        output: Option<String>,
+    },
+
+    /// Show booked/free slot ratios per facility, day and hour, and bookings per user
+    Stats {
+        #[arg(short, long, value_enum, default_value_t = StatsFormat::Text)]
+        format: StatsFormat,
+    },
+
+    /// Make the backup given by --server the primary, replicating to the given backups
+    Promote {
+        #[arg(short, long, value_delimiter = ',')]
+        backups: Vec<String>,
+        /// Users file of the deployment, whose secret signs the request for servers started with --users
+        #[arg(long)]
+        users: Option<String>,
+    },
+
+    /// Log in to servers that take changes only from their users, saving the token to --token-file
+    Login {
+        #[arg(short, long)]
+        user_id: u8,
+        #[arg(short, long)]
+        password: String,
     }
 }
 
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum StatsFormat {
+    Text, Json, Csv
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Transport {
+    Udp, Tcp
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Record(pub [u8; 16]);
 
@@ -61,73 +164,358 @@ impl fmt::Display for Record {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "Time slots (8:00 AM - 4:00 PM):")?;
         for (i, &slot) in self.0.iter().enumerate() {
-            let hour = 8 + i / 2;
-            let minute = if i % 2 == 0 { "00" } else { "30" };
             let status = if slot == 0 {
                 "Available".to_string()
             } else {
                 format!("Booked by {}", slot)
             };
-            writeln!(f, "{:02}:{:<2} - {}", hour, minute, status)?;
+            writeln!(f, "{} - {}", slot_time(i as u8), status)?;
         }
         Ok(())
     }
 }
 
-pub struct Monitor {
-    pub duration: u32
+/// Exits with the error code of a failed request as the status, for scripts to act on.
+fn exit_on_error(error: ErrorCode) {
+    if error != ErrorCode::None {
+        process::exit(error as i32);
+    }
+}
+
+fn ratio(booked: u32, total: u32) -> f64 {
+    booked as f64 / total as f64
 }
 
-impl Monitor {
-    pub fn serialize(&self, output_stream: &mut Vec<u8>) {
-        output_stream.append(self.duration.to_le_bytes().to_vec().as_mut());
+/// (label, booked, total)
+type StatsRow = (String, u32, u32);
+
+/// Rows for each day and each hour of a facility.
+fn stats_rows(facility: &FacilityStats) -> (Vec<StatsRow>, Vec<StatsRow>) {
+    let days = Day::ALL.iter()
+        .map(|&day| (format!("{:?}", day), facility.booked_by_day[day as usize] as u32, FacilityStats::SLOTS_PER_DAY as u32))
+        .collect();
+    let hours = facility.booked_by_hour.iter().enumerate()
+        .map(|(hour, &booked)| (slot_time(hour as u8 * 2), booked as u32, FacilityStats::SLOTS_PER_HOUR_PER_WEEK as u32))
+        .collect();
+    (days, hours)
+}
+
+/// Booked and free slots of a period, as printed in JSON.
+#[derive(Serialize)]
+struct PeriodStats {
+    booked: u32,
+    free: u32,
+    ratio: f64,
+}
+
+impl PeriodStats {
+    fn new(booked: u32, total: u32) -> Self {
+        // Four decimals, like the text and CSV output
+        Self { booked, free: total - booked, ratio: (ratio(booked, total) * 10000.0).round() / 10000.0 }
     }
+}
 
-    pub fn deserialize(input_stream: &[u8], pos: &mut usize) -> Self {
-        let duration = u32::from_le_bytes(input_stream[*pos..*pos+4].try_into().unwrap());
-        *pos += 4;
-        Self { duration: duration }
+/// Rows printed as a JSON object keyed by label, in row order.
+struct Periods(Vec<StatsRow>);
+
+impl Serialize for Periods {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeMap;
+        let mut map = serializer.serialize_map(Some(self.0.len()))?;
+        for (label, booked, total) in &self.0 {
+            map.serialize_entry(label, &PeriodStats::new(*booked, *total))?;
+        }
+        map.end()
     }
 }
 
+#[derive(Serialize)]
+struct FacilityReport<'a> {
+    name: &'a str,
+    #[serde(flatten)]
+    week: PeriodStats,
+    days: Periods,
+    hours: Periods,
+}
+
+#[derive(Serialize)]
+struct UserReport {
+    user_id: u8,
+    bookings: u32,
+}
+
+#[derive(Serialize)]
+struct StatsReport<'a> {
+    facilities: Vec<FacilityReport<'a>>,
+    users: Vec<UserReport>,
+}
+
+/// Prints the statistics of every shard together, with the number of bookings of each user.
+fn print_stats(facilities: &[FacilityStats], users: &[(u8, u32)], format: StatsFormat) {
+    match format {
+        StatsFormat::Text => {
+            for facility in facilities {
+                let (days, hours) = stats_rows(facility);
+                println!("{}: {}/{} slots booked ({:.1}%)", facility.name, facility.booked(), facility.total(),
+                    100.0 * ratio(facility.booked(), facility.total()));
+                for (label, booked, total) in days.iter().chain(hours.iter()) {
+                    println!("  {:<10} {:>2}/{:<2} ({:.1}%)", label, booked, total, 100.0 * ratio(*booked, *total));
+                }
+            }
+            println!("Bookings per user:");
+            for (user_id, count) in users {
+                println!("  user {}: {}", user_id, count);
+            }
+        }
+        StatsFormat::Json => {
+            let facilities: Vec<FacilityReport> = facilities.iter().map(|facility| {
+                let (days, hours) = stats_rows(facility);
+                FacilityReport { name: &facility.name, week: PeriodStats::new(facility.booked(), facility.total()), days: Periods(days), hours: Periods(hours) }
+            }).collect();
+            let users: Vec<UserReport> = users.iter().map(|&(user_id, bookings)| UserReport { user_id, bookings }).collect();
+            println!("{}", serde_json::to_string(&StatsReport { facilities, users }).unwrap());
+        }
+        StatsFormat::Csv => {
+            // User rows reuse the booked column for the number of bookings
+            println!("kind,name,period,booked,free,ratio");
+            for facility in facilities {
+                let (days, hours) = stats_rows(facility);
+                println!("facility,{},week,{},{},{:.4}", facility.name, facility.booked(), facility.total() - facility.booked(),
+                    ratio(facility.booked(), facility.total()));
+                for (label, booked, total) in &days {
+                    println!("day,{},{},{},{},{:.4}", facility.name, label, booked, total - booked, ratio(*booked, *total));
+                }
+                for (label, booked, total) in &hours {
+                    println!("hour,{},{},{},{},{:.4}", facility.name, label, booked, total - booked, ratio(*booked, *total));
+                }
+            }
+            for (user_id, count) in users {
+                println!("user,{},,{},,", user_id, count);
+            }
+        }
+    }
+}
+
+/// How many times a request is sent to one server before moving on to the next.
+const ATTEMPTS_PER_SERVER: u32 = 3;
+/// How long to wait for a reply before resending the request.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The client's socket and the servers from the command line, which are
+/// fallen back on when the server a request is meant for stops answering.
+struct Connection {
+    socket: UdpSocket,
+    transport: Transport,
+    format: Format,
+    servers: Vec<String>,
+    state_file: String,
+    token_file: String,
+    next_message_id: Cell<u32>,
+}
+
+impl Connection {
+    /// Puts the server remembered in `state_file` first when it is one of `servers`.
+    fn new(socket: UdpSocket, transport: Transport, format: Format, mut servers: Vec<String>, state_file: String, token_file: String) -> Self {
+        if let Ok(last_good) = fs::read_to_string(&state_file)
+            && let Some(i) = servers.iter().position(|server| *server == last_good.trim())
+        {
+            let server = servers.remove(i);
+            servers.insert(0, server);
+        }
+        // Started from the clock so a new run reusing a port isn't taken for the last one
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().subsec_nanos();
+        Self { socket, transport, format, servers, state_file, token_file, next_message_id: Cell::new(seed.max(1)) }
+    }
+
+    /// A request of `request_type`, led by the protocol version and encoded in the connection's format.
+    fn request<T: Encode + Serialize + ?Sized>(&self, request_type: RequestType, body: &T) -> Vec<u8> {
+        let mut writer = Writer::versioned(PROTOCOL_VERSION, self.format);
+        writer.put(&request_type).put(body);
+        writer.into_bytes()
+    }
+
+    /// Id for the next request; replies carry it back. 0 is left for monitor pushes.
+    fn message_id(&self) -> u32 {
+        let message_id = self.next_message_id.get();
+        self.next_message_id.set(message_id.checked_add(1).unwrap_or(1));
+        message_id
+    }
+
+    /// The token saved by the last login, `None` before any or if the file is unreadable.
+    fn token(&self) -> Option<Token> {
+        let text = fs::read_to_string(&self.token_file).ok()?;
+        Token::from_bytes(&auth::from_hex(text.trim())?).ok()
+    }
+
+    fn remember(&self, server_address: &str) {
+        if fs::read_to_string(&self.state_file).is_ok_and(|last_good| last_good.trim() == server_address) {
+            return;
+        }
+        if let Err(e) = fs::write(&self.state_file, format!("{}\n", server_address)) {
+            eprintln!("Cannot write {}: {}", self.state_file, e);
+        }
+    }
+}
+
+/// Sends a request and waits for the reply, resending it when the read times
+/// out and following redirects to other shards or to the leader. A server
+/// that stops answering is given up on for the next one from the command
+/// line. Returns the payload after the protocol version and reply status,
+/// decoded in the format of the reply.
+fn send_request<T: Decode + DeserializeOwned>(connection: &Connection, server_address: &str, output_stream: &[u8]) -> T {
+    let fallbacks = connection.servers.iter().filter(|server| *server != server_address);
+    for candidate in std::iter::once(server_address).chain(fallbacks.map(String::as_str)) {
+        let mut server_address: String = candidate.to_string();
+        // Bounded so that two servers with inconsistent shard maps can't bounce us forever
+        for _ in 0..4 {
+            let Some(reply) = send_and_receive(connection, &server_address, output_stream) else {
+                eprintln!("{} is not answering, trying the next server", server_address);
+                break;
+            };
+            let decoded = message::reply_status(&reply).and_then(|(status, mut reader)| match status {
+                ReplyStatus::Ok => reader.get().map(|payload| (status, Some(payload))),
+                _ => Ok((status, None)),
+            });
+            let (status, payload) = match decoded {
+                Ok(decoded) => decoded,
+                Err(e) => {
+                    eprintln!("{} sent a reply that does not decode ({}), trying the next server", server_address, e);
+                    break;
+                }
+            };
+            match status {
+                ReplyStatus::Ok => {
+                    connection.remember(&server_address);
+                    return payload.unwrap();
+                }
+                ReplyStatus::WrongShard { owner } => {
+                    eprintln!("{} does not own this request, retrying at {}", server_address, owner);
+                    server_address = owner;
+                }
+                ReplyStatus::NotLeader { leader } => {
+                    eprintln!("{} is not the leader, retrying at {}", server_address, leader);
+                    server_address = leader;
+                }
+                // Another server is unlikely to be any different
+                status @ ReplyStatus::UnsupportedVersion { .. } => panic!("{} refused the request: {}", server_address, status),
+            }
+        }
+    }
+    panic!("None of the servers answered");
+}
+
+/// Sends the request up to ATTEMPTS_PER_SERVER times, `None` if no reply came back.
+/// Over TCP it is sent once: a new connection would not be recognised as a
+/// resend, so the request could be carried out twice.
+fn send_and_receive(connection: &Connection, server_address: &str, output_stream: &[u8]) -> Option<Vec<u8>> {
+    if connection.transport == Transport::Tcp {
+        return match tcp::request(server_address, output_stream, REPLY_TIMEOUT) {
+            Ok(Some(reply)) => {
+                eprintln!("Received {} bytes from {}", reply.len(), server_address);
+                Some(reply)
+            }
+            Ok(None) => {
+                eprintln!("Timeout: No data received after 5 seconds.");
+                None
+            }
+            Err(e) => {
+                eprintln!("An I/O error occurred: {}", e.kind());
+                None
+            }
+        };
+    }
+    // Resends keep the id, so a late reply to an earlier attempt still counts
+    let message_id = connection.message_id();
+    for _ in 0..ATTEMPTS_PER_SERVER {
+        match fragment::request(&connection.socket, server_address, message_id, output_stream, REPLY_TIMEOUT) {
+            Ok(Some(reply)) => {
+                // Success: data received
+                eprintln!("Received {} bytes from {}", reply.len(), server_address);
+                return Some(reply);
+            }
+            Ok(None) => {
+                // Timeout Occurred
+                eprintln!("Timeout: No data received after 5 seconds.");
+            }
+            Err(e) => {
+                // Other I/O error, e.g. the port is closed
+                eprintln!("An I/O error occurred: {}", e.kind());
+                return None;
+            }
+        }
+    }
+    None
+}
+
+/// Subscribes over a connection to every shard and prints the records they
+/// push until `duration` has passed.
+fn monitor_over_tcp(shards: &[String], output_stream: &[u8], duration: Duration) {
+    let start = Instant::now();
+    println!("Monitoring for {} seconds...", duration.as_secs());
+    thread::scope(|scope| {
+        for server_address in shards {
+            let mut stream = match tcp::connect(server_address, REPLY_TIMEOUT) {
+                Ok(stream) => stream,
+                Err(e) => {
+                    eprintln!("Cannot monitor {}: {}", server_address, e);
+                    continue;
+                }
+            };
+            if let Err(e) = tcp::write_frame(&mut stream, output_stream) {
+                eprintln!("Cannot monitor {}: {}", server_address, e);
+                continue;
+            }
+            // Short reads so the thread notices the end of the duration
+            stream.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+            scope.spawn(move || {
+                while start.elapsed() < duration {
+                    match tcp::read_frame(&mut stream) {
+                        Ok(update) => match Reader::versioned(&update).and_then(|mut reader| reader.get::<FacilityRecord>()) {
+                            Ok(facility) => println!("{}", facility),
+                            Err(e) => eprintln!("Dropping an update from {} that does not decode: {}", server_address, e),
+                        },
+                        Err(e) if matches!(e.kind(), std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut) => continue,
+                        Err(e) => {
+                            eprintln!("{} stopped sending updates: {}", server_address, e);
+                            break;
+                        }
+                    }
+                }
+            });
+        }
+    });
+    println!("Monitoring ended after {} seconds.", duration.as_secs());
+}
+
+fn fetch_shard_map(connection: &Connection) -> ShardMap {
+    let output_stream = connection.request(RequestType::SHARD_MAP, &());
+    send_request(connection, &connection.servers[0], &output_stream)
+}
+
 fn main() {
     let cli = Cli::parse();
     let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
-    let server_address = cli.server;
     
-    socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+    socket.set_read_timeout(Some(fragment::RESEND_AFTER)).unwrap();
+    let connection = Connection::new(socket, cli.transport, cli.wire_format, cli.server.clone(), cli.state_file.clone(), cli.token_file.clone());
+    let shard_map = fetch_shard_map(&connection);
+    let socket = &connection.socket;
     match cli.command {
         Commands::Query { name, days } => {
             let days: Vec<Day> = days.iter().map(|d| Day::from(d.as_str())).collect();
             let no_of_days = days.len();
+            let server_address = &shard_map.shards[shard_map.owner(&name)];
             let req = QueryRequest { name, days };
-            let num_bytes: usize;
-            let mut output_stream = vec![RequestType::QUERY as u8];
-            req.serialize(&mut output_stream);
-            socket.send_to(&output_stream, &server_address).unwrap();
-            let mut buf = [0; 1024];
-            loop {
-                match socket.recv_from(&mut buf) {
-                    Ok((bytes, src_addr)) => {
-                        // Success: data received
-                        num_bytes = bytes;
-                        println!("Received {} bytes from {}", num_bytes, src_addr);
-                        break;
-                    }
-                    Err(ref e) if e.kind() == ErrorKind::TimedOut => {
-                        // Timeout Occurred
-                        println!("Timeout: No data received after 5 seconds.");
-                        socket.send_to(&output_stream, &server_address).unwrap();
-                        continue;
-                    }
-                    Err(e) => {
-                        // Other I/O error
-                        eprintln!("An I/O error occurred: {}", e.kind());
-                    }
-                }
+            let output_stream = connection.request(RequestType::QUERY, &req);
+            let resp: QueryResponse = send_request(&connection, server_address, &output_stream);
+            if resp.error != ErrorCode::None {
+                println!("Facility {} not found", resp.name);
+                exit_on_error(resp.error);
+            }
+            if resp.availaible.len() != no_of_days * 16 {
+                eprintln!("{} sent {} slots for {} days", server_address, resp.availaible.len(), no_of_days);
+                exit_on_error(ErrorCode::InvalidRequest);
             }
-            let mut pos = 0;
-            let resp = QueryResponse::deserialize(&buf[..num_bytes], &mut pos);
             let mut records: Vec<Record> = Vec::new();
             for i in 0..no_of_days {
                 let record: Record = Record(resp.availaible[i*16..i*16+16].try_into().unwrap());
@@ -145,6 +533,7 @@ fn main() {
             num_slots,
             user_id,
         } => {
+            let server_address = &shard_map.shards[shard_map.owner(&name)];
             let booking = Booking {
                 facility_name: name,
                 day: Day::from(day.as_str()),
@@ -152,76 +541,86 @@ fn main() {
                 num_slots,
                 user_id,
             };
-            let mut output_stream = vec![RequestType::BOOK as u8];
-            booking.serialize(&mut output_stream);
-            socket.send_to(&output_stream, &server_address).unwrap();
-            let num_bytes: usize;
+            let output_stream = connection.request(RequestType::BOOK, &BookRequest { auth: connection.token(), booking });
+            let resp: BookingResponse = send_request(&connection, server_address, &output_stream);
+            println!("Booking Response: {:?}", resp);
+            exit_on_error(resp.error);
+        }
 
-            let mut buf = [0; 1024];
-            loop {
-                match socket.recv_from(&mut buf) {
-                    Ok((bytes, src_addr)) => {
-                        // Success: data received
-                        num_bytes = bytes;
-                        println!("Received {} bytes from {}", num_bytes, src_addr);
-                        break;
-                    }
-                    Err(ref e) if e.kind() == ErrorKind::TimedOut => {
-                        // Timeout Occurred
-                        println!("Timeout: No data received after 5 seconds.");
-                        socket.send_to(&output_stream, &server_address).unwrap();
-                        continue;
-                    }
-                    Err(e) => {
-                        // Other I/O error
-                        eprintln!("An I/O error occurred: {}", e.kind());
-                    }
-                }
-            }
-            // let (num_bytes, _) = socket.recv_from(&mut buf).unwrap();
-            let mut pos = 0;
-            let resp = BookingResponse::deserialize(&buf[..num_bytes], &mut pos);
+        Commands::MultiBook {
+            names,
+            day,
+            start_slot,
+            num_slots,
+            user_id,
+        } => {
+            let Some(first) = names.first() else {
+                eprintln!("Name at least one facility");
+                return;
+            };
+            // The owner of the first facility coordinates the transaction
+            let server_address = &shard_map.shards[shard_map.owner(first)];
+            let day = Day::from(day.as_str());
+            let bookings: Vec<Booking> = names.iter()
+                .map(|name| Booking { facility_name: name.clone(), day, start_slot, num_slots, user_id })
+                .collect();
+            let output_stream = connection.request(RequestType::MULTI_BOOK, &MultiBook { auth: connection.token(), bookings });
+            let resp: MultiBookResponse = send_request(&connection, server_address, &output_stream);
             println!("Booking Response: {:?}", resp);
+            exit_on_error(resp.error);
         }
 
         Commands::Update {
             confirmation_id,
             offset,
         } => {
+            let server_address = &shard_map.shards[shard_map.confirmation_owner(confirmation_id)];
             let update = Update {
+                auth: connection.token(),
                 confirmation_id,
                 offset,
             };
-            let mut output_stream = vec![RequestType::UPDATE as u8];
-            update.serialize(&mut output_stream);
-            socket.send_to(&output_stream, &server_address).unwrap();
-            let mut buf = [0; 1024];
-            let (num_bytes, _) = socket.recv_from(&mut buf).unwrap();
-            let mut pos = 0;
-            let resp = UpdateResponse::deserialize(&buf[..num_bytes], &mut pos);
+            let output_stream = connection.request(RequestType::UPDATE, &update);
+            let resp: UpdateResponse = send_request(&connection, server_address, &output_stream);
             println!("Update Response: {:?}", resp);
+            exit_on_error(resp.error);
         }
 
         Commands::Monitor { duration } => {
             let start = Instant::now();
             let timeout = Duration::from_secs(10); // 1 second per recv attempt
             socket.set_read_timeout(Some(timeout)).unwrap();
-            let mut output_stream = vec![RequestType::MONITOR as u8];
-            let monitor = Monitor { duration };
-            monitor.serialize(&mut output_stream);
-            socket.send_to(&output_stream, &server_address).unwrap();
+            let output_stream = connection.request(RequestType::MONITOR, &Monitor { duration });
+            if connection.transport == Transport::Tcp {
+                monitor_over_tcp(&shard_map.shards, &output_stream, Duration::from_secs(duration as u64));
+                return;
+            }
+            // Every shard pushes updates for the facilities it owns
+            for server_address in &shard_map.shards {
+                for datagram in fragment::fragments(connection.message_id(), &output_stream) {
+                    socket.send_to(&datagram, server_address).unwrap();
+                }
+            }
 
-            let mut buf = [0u8; 1024];
+            let mut buf = [0u8; fragment::MAX_DATAGRAM];
+            let mut reassembler = Reassembler::default();
 
             println!("Monitoring for {} seconds...", duration);
 
             while start.elapsed() < Duration::from_secs(duration as u64) {
                 match socket.recv_from(&mut buf) {
-                    Ok((num_bytes, src_addr)) => {
-                        let mut pos = 0;
-                        let facility: FacilityRecord = FacilityRecord::deserialize(&buf[..num_bytes], &mut pos);
-                        println!("{}", facility);
-                    }
+                    Ok((num_bytes, src_addr)) => match fragment::parse(&buf[..num_bytes]) {
+                        Ok(Datagram::Fragment { message_id, index, count, payload }) => {
+                            if let Some(update) = reassembler.add(src_addr, message_id, index, count, payload) {
+                                match Reader::versioned(&update).and_then(|mut reader| reader.get::<FacilityRecord>()) {
+                                    Ok(facility) => println!("{}", facility),
+                                    Err(e) => eprintln!("Dropping an update from {} that does not decode: {}", src_addr, e),
+                                }
+                            }
+                        }
+                        Ok(Datagram::Resend { .. }) => {}
+                        Err(reason) => eprintln!("Dropping {} datagram from {} ({} rejected so far)", reason, src_addr, fragment::rejected()),
+                    },
                     Err(ref e) if e.kind() == std::io::ErrorKind::WouldBlock => {
                         // No data received within timeout, continue
                         continue;
@@ -234,5 +633,103 @@ fn main() {
 
             println!("Monitoring ended after {} seconds.", duration);
         }
+
+        Commands::GetBooking { confirmation_id } => {
+            let server_address = &shard_map.shards[shard_map.confirmation_owner(confirmation_id)];
+            let get_booking = GetBooking { confirmation_id };
+            let output_stream = connection.request(RequestType::GET_BOOKING, &get_booking);
+            let resp: GetBookingResponse = send_request(&connection, server_address, &output_stream);
+            println!("Get Booking Response: {:?}", resp);
+            exit_on_error(resp.error);
+        }
+
+        Commands::MyBookings { user_id } => {
+            let list_bookings = ListBookings { user_id };
+            let output_stream = connection.request(RequestType::LIST_BOOKINGS, &list_bookings);
+            // Bookings are spread over every shard
+            let mut bookings: Vec<(u8, Booking)> = Vec::new();
+            for server_address in &shard_map.shards {
+                let mut resp: ListBookingsResponse = send_request(&connection, server_address, &output_stream);
+                bookings.append(&mut resp.bookings);
+            }
+            bookings.sort_by_key(|(confirmation_id, _)| *confirmation_id);
+            if bookings.is_empty() {
+                println!("No bookings found for user {}", user_id);
+            }
+            for (confirmation_id, booking) in bookings {
+                println!("#{} {}", confirmation_id, booking);
+            }
+        }
+
+        Commands::Export { name, user_id, output } => {
+            let (export, server_addresses) = match (name, user_id) {
+                (Some(name), _) => {
+                    let owner = shard_map.shards[shard_map.owner(&name)].clone();
+                    (Export::Facility(name), vec![owner])
+                }
+                (None, Some(user_id)) => (Export::User(user_id), shard_map.shards.clone()),
+                (None, None) => unreachable!("clap requires a facility name or user id"),
+            };
+            let output_stream = connection.request(RequestType::EXPORT, &export);
+            let mut calendars: Vec<String> = Vec::new();
+            for server_address in &server_addresses {
+                let resp: ExportResponse = send_request(&connection, server_address, &output_stream);
+                if !resp.success {
+                    println!("Export Response: {:?}", resp);
+                    exit_on_error(resp.error);
+                    return;
+                }
+                calendars.push(resp.calendar);
+            }
+            let calendar = ical::merge_calendars(&calendars);
+            match output {
+                Some(path) => {
+                    fs::write(&path, calendar).unwrap();
+                    println!("Calendar written to {}", path);
+                }
+                None => print!("{}", calendar),
+            }
+        }
+
+        Commands::Stats { format } => {
+            let output_stream = connection.request(RequestType::STATS, &());
+            let mut facilities: Vec<FacilityStats> = Vec::new();
+            // Summed over the shards, so the counts may outgrow the u8 of one shard
+            let mut per_user: BTreeMap<u8, u32> = BTreeMap::new();
+            for server_address in &shard_map.shards {
+                let mut resp: StatsResponse = send_request(&connection, server_address, &output_stream);
+                facilities.append(&mut resp.facilities);
+                for (user_id, count) in resp.users {
+                    *per_user.entry(user_id).or_insert(0) += count as u32;
+                }
+            }
+            facilities.sort_by(|a, b| a.name.cmp(&b.name));
+            let users: Vec<(u8, u32)> = per_user.into_iter().collect();
+            print_stats(&facilities, &users, format);
+        }
+
+        Commands::Promote { backups, users } => {
+            let key: Option<PeerKey> = users.as_deref().map(|path| Users::load(path).unwrap_or_else(|e| panic!("{}", e)).peer_key());
+            // Sent straight to the first --server, a backup would otherwise redirect us to its old primary
+            let output_stream = auth::sign_request(key.as_ref(), connection.request(RequestType::PROMOTE, &Promote { backups: backups.clone() }));
+            // No failover: promoting whichever server answers instead would be wrong
+            if send_and_receive(&connection, &cli.server[0], &output_stream).is_none() {
+                eprintln!("{} did not answer", cli.server[0]);
+                return;
+            }
+            println!("{} is now the primary, replicating to {:?}", cli.server[0], backups);
+        }
+
+        Commands::Login { user_id, password } => {
+            let output_stream = connection.request(RequestType::LOGIN, &Login { user_id, password });
+            let resp: LoginResponse = send_request(&connection, &connection.servers[0], &output_stream);
+            let Some(token) = resp.token else {
+                println!("Login Response: {:?}", resp);
+                exit_on_error(resp.error);
+                return;
+            };
+            fs::write(&cli.token_file, format!("{}\n", auth::to_hex(&token.to_bytes()))).unwrap();
+            println!("Logged in as user {}, token saved to {}", user_id, cli.token_file);
+        }
     }
-}
\ No newline at end of file
+}
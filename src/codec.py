@@ -0,0 +1,151 @@
+"""The binary encoding of src/codec.rs, which the classes generated into
+message_gen.py are written and read with. Integers are little endian; from
+protocol version 2 strings are preceded by a u32 byte length and lists by a u16
+count, version 1 terminated strings with a NUL and counted lists with a u8.
+From version 4 a format byte follows the version; this module only speaks
+the binary format, the JSON and CBOR ones are left to the Rust side.
+"""
+import struct
+
+# First protocol version with length-prefixed strings and counted lists
+SELF_DELIMITING = 2
+# First protocol version whose messages name their format after the version
+FORMAT_BYTE = 4
+# The format byte of the binary encoding, codec::Format::Binary
+BINARY = 0
+
+_WIDTHS = {"u8": struct.Struct("<B"), "u16": struct.Struct("<H"), "u32": struct.Struct("<I")}
+
+
+def none_if(value, none):
+    """`value`, or None if it is the value standing for None on the wire."""
+    return None if value == none else value
+
+
+class Writer:
+    """Collects the encoding of one message in the layout of `version`."""
+
+    def __init__(self, version: int):
+        self.version = version
+        self.buf = bytearray()
+
+    @classmethod
+    def versioned(cls, version: int) -> "Writer":
+        """Starts a message in `version`, which leads it, in the binary format."""
+        writer = cls(version)
+        writer.u8(version)
+        if version >= FORMAT_BYTE:
+            writer.u8(BINARY)
+        return writer
+
+    def self_delimiting(self) -> bool:
+        return self.version >= SELF_DELIMITING
+
+    def u8(self, v: int) -> None:
+        self.buf.append(v)
+
+    def i8(self, v: int) -> None:
+        self.buf.append(v & 0xFF)
+
+    def bool(self, v: bool) -> None:
+        self.buf.append(1 if v else 0)
+
+    def u16(self, v: int) -> None:
+        self.buf += _WIDTHS["u16"].pack(v)
+
+    def u32(self, v: int) -> None:
+        self.buf += _WIDTHS["u32"].pack(v)
+
+    def raw(self, data: bytes) -> None:
+        """Appends `data` as it is, without a length."""
+        self.buf += data
+
+    def string(self, s: str) -> None:
+        data = s.encode("utf-8")
+        if self.self_delimiting():
+            self.u32(len(data))
+            self.raw(data)
+        else:
+            if b"\0" in data:
+                raise ValueError("Strings cannot hold NUL before version 2")
+            self.raw(data)
+            self.u8(0)
+
+    def count(self, n: int, width: str = None) -> None:
+        """The count of a list, of the version's width unless `width` is given."""
+        width = width or ("u16" if self.self_delimiting() else "u8")
+        self.buf += _WIDTHS[width].pack(n)
+
+    def into_bytes(self) -> bytes:
+        return bytes(self.buf)
+
+
+class Reader:
+    """Walks through the encoding of one message in the layout of `version`."""
+
+    def __init__(self, data: bytes, version: int, pos: int = 0):
+        self.data = data
+        self.version = version
+        self.pos = pos
+
+    @classmethod
+    def versioned(cls, data: bytes) -> "Reader":
+        """Decodes a message led by its protocol version, in that version."""
+        reader = cls(data, data[0], 1)
+        if reader.version >= FORMAT_BYTE and reader.u8() != BINARY:
+            raise ValueError("Only the binary format is supported")
+        return reader
+
+    def self_delimiting(self) -> bool:
+        return self.version >= SELF_DELIMITING
+
+    def take(self, n: int) -> bytes:
+        if self.pos + n > len(self.data):
+            raise ValueError("Message ends early")
+        data = self.data[self.pos:self.pos + n]
+        self.pos += n
+        return data
+
+    def rest(self) -> bytes:
+        """Everything not read yet."""
+        return self.take(self.remaining())
+
+    def remaining(self) -> int:
+        return len(self.data) - self.pos
+
+    def until_end(self, decode) -> list:
+        """Items decoded one after the other up to the end of the message."""
+        items = []
+        while self.remaining() > 0:
+            items.append(decode())
+        return items
+
+    def u8(self) -> int:
+        return self.take(1)[0]
+
+    def i8(self) -> int:
+        v = self.u8()
+        return v if v < 128 else v - 256
+
+    def bool(self) -> bool:
+        return self.u8() != 0
+
+    def u16(self) -> int:
+        return _WIDTHS["u16"].unpack(self.take(2))[0]
+
+    def u32(self) -> int:
+        return _WIDTHS["u32"].unpack(self.take(4))[0]
+
+    def string(self) -> str:
+        if self.self_delimiting():
+            return self.take(self.u32()).decode("utf-8")
+        end = self.data.find(b"\0", self.pos)
+        if end < 0:
+            raise ValueError("Unterminated string")
+        s = self.take(end - self.pos).decode("utf-8")
+        self.pos += 1
+        return s
+
+    def count(self, width: str = None) -> int:
+        width = width or ("u16" if self.self_delimiting() else "u8")
+        return _WIDTHS[width].unpack(self.take(_WIDTHS[width].size))[0]
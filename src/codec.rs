@@ -0,0 +1,454 @@
+//! The binary encoding every message is written in. Integers are little
+//! endian, strings are preceded by a u32 byte length and lists by a u16 count.
+//! Version 1 of the protocol terminated strings with a NUL and counted lists
+//! with a u8; writers and readers keep the version they encode so the server
+//! can still answer version 1 clients in their layout. Input that does not
+//! follow it fails to decode with a DecodeError; a checksum only catches
+//! damage on the way, not peers that send nonsense.
+//!
+//! Messages derive `Encode` and `Decode` with the macros re-exported here, see
+//! the codec_derive crate for the layouts they generate.
+//!
+//! From version 4 a format byte follows the protocol version and the body may
+//! instead be JSON or CBOR through serde, for debugging and for peers without
+//! this codec. Each value put at the top of the message is then one JSON
+//! document followed by a newline, or one CBOR item.
+
+use std::{fmt, io};
+pub use codec_derive::{Decode, Encode};
+use serde::{de::DeserializeOwned, Serialize};
+use crate::message::PROTOCOL_VERSION;
+
+/// First protocol version with length-prefixed strings and counted lists.
+pub const SELF_DELIMITING: u8 = 2;
+/// First protocol version whose messages name their Format after the version.
+pub const FORMAT_BYTE: u8 = 4;
+
+/// How the body of a message is written, the byte after the protocol version.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, clap::ValueEnum)]
+pub enum Format {
+    /// The layout of this module
+    #[default]
+    Binary,
+    /// serde_json, one value per line
+    Json,
+    /// ciborium, values back to back
+    Cbor,
+}
+
+impl Format {
+    pub fn from_byte(byte: u8) -> Option<Self> {
+        match byte {
+            0 => Some(Format::Binary),
+            1 => Some(Format::Json),
+            2 => Some(Format::Cbor),
+            _ => None,
+        }
+    }
+}
+
+/// Why a message could not be decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The input ends in the middle of a value
+    Truncated,
+    /// A tag that names no variant of the type
+    InvalidTag { type_name: &'static str, tag: u8 },
+    /// A format byte that names no Format
+    InvalidFormat(u8),
+    /// A string that is not UTF-8
+    InvalidUtf8,
+    /// JSON or CBOR that does not deserialize to the expected type
+    Malformed(String),
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::Truncated => write!(f, "truncated message"),
+            DecodeError::InvalidTag { type_name, tag } => write!(f, "invalid {} tag: {}", type_name, tag),
+            DecodeError::InvalidFormat(byte) => write!(f, "invalid format: {}", byte),
+            DecodeError::InvalidUtf8 => write!(f, "string is not UTF-8"),
+            DecodeError::Malformed(reason) => write!(f, "malformed message: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// Messages read from a stream that do not decode are bad data on it.
+impl From<DecodeError> for io::Error {
+    fn from(error: DecodeError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidData, error)
+    }
+}
+
+/// Collects the encoding of one message.
+#[derive(Debug)]
+pub struct Writer {
+    bytes: Vec<u8>,
+    version: u8,
+    format: Format,
+}
+
+impl Default for Writer {
+    fn default() -> Self {
+        Self::with_version(PROTOCOL_VERSION)
+    }
+}
+
+impl Writer {
+    /// Encodes in the layout of this build's protocol version.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Encodes in the layout of `version`, without writing it.
+    pub fn with_version(version: u8) -> Self {
+        Self::with_format(version, Format::Binary)
+    }
+
+    /// Encodes in `format`, and in the layout of `version` if that is binary.
+    pub fn with_format(version: u8, format: Format) -> Self {
+        Self { bytes: Vec::new(), version, format }
+    }
+
+    /// Starts a message in `version` and `format`, which lead it. Versions
+    /// before FORMAT_BYTE can only be binary.
+    pub fn versioned(version: u8, format: Format) -> Self {
+        let mut writer = Self::with_format(version, format);
+        writer.put_bytes(&[version]);
+        if version >= FORMAT_BYTE {
+            writer.put_bytes(&[format as u8]);
+        }
+        writer
+    }
+
+    /// Protocol version whose layout is written.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Whether strings and lists carry their length, see SELF_DELIMITING.
+    pub fn self_delimiting(&self) -> bool {
+        self.version >= SELF_DELIMITING
+    }
+
+    /// Appends the encoding of `value`.
+    pub fn put<T: Encode + Serialize + ?Sized>(&mut self, value: &T) -> &mut Self {
+        match self.format {
+            Format::Binary => value.encode(self),
+            Format::Json => {
+                serde_json::to_writer(&mut self.bytes, value).expect("messages serialize to JSON");
+                self.bytes.push(b'\n');
+            }
+            Format::Cbor => ciborium::into_writer(value, &mut self.bytes).expect("messages serialize to CBOR"),
+        }
+        self
+    }
+
+    /// Appends `bytes` as they are, without a length.
+    pub fn put_bytes(&mut self, bytes: &[u8]) -> &mut Self {
+        self.bytes.extend_from_slice(bytes);
+        self
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+/// Walks through the encoding of one message.
+#[derive(Debug)]
+pub struct Reader<'a> {
+    input: &'a [u8],
+    pos: usize,
+    version: u8,
+    format: Format,
+}
+
+impl<'a> Reader<'a> {
+    /// Decodes the layout of this build's protocol version.
+    pub fn new(input: &'a [u8]) -> Self {
+        Self::with_version(input, PROTOCOL_VERSION)
+    }
+
+    /// Decodes the layout of `version`.
+    pub fn with_version(input: &'a [u8], version: u8) -> Self {
+        Self::with_format(input, version, Format::Binary)
+    }
+
+    /// Decodes `format`, and the layout of `version` if that is binary.
+    pub fn with_format(input: &'a [u8], version: u8, format: Format) -> Self {
+        Self { input, pos: 0, version, format }
+    }
+
+    /// Decodes a message led by its protocol version and format, in those.
+    pub fn versioned(input: &'a [u8]) -> Result<Self, DecodeError> {
+        let mut reader = Self::new(input);
+        reader.version = reader.take(1)?[0];
+        if reader.version >= FORMAT_BYTE {
+            let byte = reader.take(1)?[0];
+            reader.format = Format::from_byte(byte).ok_or(DecodeError::InvalidFormat(byte))?;
+        }
+        Ok(reader)
+    }
+
+    /// Protocol version whose layout is read.
+    pub fn version(&self) -> u8 {
+        self.version
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    /// Whether strings and lists carry their length, see SELF_DELIMITING.
+    pub fn self_delimiting(&self) -> bool {
+        self.version >= SELF_DELIMITING
+    }
+
+    /// Decodes the next value.
+    pub fn get<T: Decode + DeserializeOwned>(&mut self) -> Result<T, DecodeError> {
+        match self.format {
+            Format::Binary => T::decode(self),
+            Format::Json => {
+                let mut values = serde_json::Deserializer::from_slice(&self.input[self.pos..]).into_iter();
+                let value = match values.next() {
+                    Some(value) => value.map_err(|e| DecodeError::Malformed(e.to_string()))?,
+                    None => return Err(DecodeError::Truncated),
+                };
+                self.pos += values.byte_offset();
+                while self.input.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+                    self.pos += 1;
+                }
+                Ok(value)
+            }
+            Format::Cbor => {
+                let mut input = &self.input[self.pos..];
+                let value = ciborium::from_reader(&mut input).map_err(|e| DecodeError::Malformed(e.to_string()))?;
+                self.pos = self.input.len() - input.len();
+                Ok(value)
+            }
+        }
+    }
+
+    /// The next `len` bytes as they are.
+    pub fn take(&mut self, len: usize) -> Result<&'a [u8], DecodeError> {
+        let bytes = self.input.get(self.pos..self.pos.saturating_add(len)).ok_or(DecodeError::Truncated)?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    /// Everything not read yet.
+    pub fn rest(&mut self) -> &'a [u8] {
+        let bytes = &self.input[self.pos..];
+        self.pos = self.input.len();
+        bytes
+    }
+
+    pub fn remaining(&self) -> usize {
+        self.input.len() - self.pos
+    }
+}
+
+pub trait Encode {
+    fn encode(&self, writer: &mut Writer);
+
+    fn to_bytes(&self) -> Vec<u8> {
+        self.to_bytes_in(PROTOCOL_VERSION)
+    }
+
+    /// The encoding in the layout of protocol `version`.
+    fn to_bytes_in(&self, version: u8) -> Vec<u8> {
+        let mut writer = Writer::with_version(version);
+        self.encode(&mut writer);
+        writer.into_bytes()
+    }
+
+    /// The encoding in `format`, in the layout of `version` if that is binary.
+    fn to_bytes_as(&self, version: u8, format: Format) -> Vec<u8> where Self: Serialize {
+        let mut writer = Writer::with_format(version, format);
+        writer.put(self);
+        writer.into_bytes()
+    }
+}
+
+pub trait Decode: Sized {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError>;
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, DecodeError> {
+        Self::decode(&mut Reader::new(bytes))
+    }
+}
+
+/// Requests without a body.
+impl Encode for () {
+    fn encode(&self, _writer: &mut Writer) {}
+}
+
+impl Encode for u8 {
+    fn encode(&self, writer: &mut Writer) {
+        writer.bytes.push(*self);
+    }
+}
+
+impl Decode for u8 {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(reader.take(1)?[0])
+    }
+}
+
+impl Encode for i8 {
+    fn encode(&self, writer: &mut Writer) {
+        writer.put(&(*self as u8));
+    }
+}
+
+impl Decode for i8 {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(reader.get::<u8>()? as i8)
+    }
+}
+
+impl Encode for bool {
+    fn encode(&self, writer: &mut Writer) {
+        writer.put(&(*self as u8));
+    }
+}
+
+impl Decode for bool {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(reader.get::<u8>()? != 0)
+    }
+}
+
+impl Encode for u16 {
+    fn encode(&self, writer: &mut Writer) {
+        writer.put_bytes(&self.to_le_bytes());
+    }
+}
+
+impl Decode for u16 {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(u16::from_le_bytes(reader.take(2)?.try_into().unwrap()))
+    }
+}
+
+impl Encode for u32 {
+    fn encode(&self, writer: &mut Writer) {
+        writer.put_bytes(&self.to_le_bytes());
+    }
+}
+
+impl Decode for u32 {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(u32::from_le_bytes(reader.take(4)?.try_into().unwrap()))
+    }
+}
+
+impl Encode for str {
+    fn encode(&self, writer: &mut Writer) {
+        if writer.self_delimiting() {
+            writer.put(&(self.len() as u32)).put_bytes(self.as_bytes());
+        } else {
+            writer.put_bytes(self.as_bytes()).put(&0u8);
+        }
+    }
+}
+
+impl Encode for String {
+    fn encode(&self, writer: &mut Writer) {
+        writer.put(self.as_str());
+    }
+}
+
+impl Decode for String {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let bytes = if reader.self_delimiting() {
+            let len: u32 = reader.get()?;
+            reader.take(len as usize)?
+        } else {
+            let len = reader.input[reader.pos..].iter().position(|&byte| byte == 0).ok_or(DecodeError::Truncated)?;
+            let bytes = reader.take(len)?;
+            reader.pos += 1;
+            bytes
+        };
+        String::from_utf8(bytes.to_vec()).map_err(|_| DecodeError::InvalidUtf8)
+    }
+}
+
+/// Fixed size, so written without a count.
+impl<const N: usize> Encode for [u8; N] {
+    fn encode(&self, writer: &mut Writer) {
+        writer.put_bytes(self);
+    }
+}
+
+impl<const N: usize> Decode for [u8; N] {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(reader.take(N)?.try_into().unwrap())
+    }
+}
+
+/// Panics on more items than the count holds, u8 in version 1 and u16 after,
+/// rather than send a count that disagrees with the body. Lists built from
+/// requests are bounded before they are put.
+impl<T: Encode + Serialize> Encode for [T] {
+    fn encode(&self, writer: &mut Writer) {
+        let too_long = |count: &str| -> ! { panic!("a list of {} items does not fit a {} count", self.len(), count) };
+        if writer.self_delimiting() {
+            writer.put(&u16::try_from(self.len()).unwrap_or_else(|_| too_long("u16")));
+        } else {
+            writer.put(&u8::try_from(self.len()).unwrap_or_else(|_| too_long("u8")));
+        }
+        for item in self {
+            writer.put(item);
+        }
+    }
+}
+
+impl<T: Encode + Serialize> Encode for Vec<T> {
+    fn encode(&self, writer: &mut Writer) {
+        writer.put(self.as_slice());
+    }
+}
+
+impl<T: Decode + DeserializeOwned> Decode for Vec<T> {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let count: u16 = if reader.self_delimiting() { reader.get()? } else { reader.get::<u8>()? as u16 };
+        (0..count).map(|_| reader.get()).collect()
+    }
+}
+
+/// A presence byte, followed by the value if there is one.
+impl<T: Encode + Serialize> Encode for Option<T> {
+    fn encode(&self, writer: &mut Writer) {
+        writer.put(&self.is_some());
+        if let Some(value) = self {
+            writer.put(value);
+        }
+    }
+}
+
+impl<T: Decode + DeserializeOwned> Decode for Option<T> {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(if reader.get()? { Some(reader.get()?) } else { None })
+    }
+}
+
+impl<A: Encode + Serialize, B: Encode + Serialize> Encode for (A, B) {
+    fn encode(&self, writer: &mut Writer) {
+        writer.put(&self.0).put(&self.1);
+    }
+}
+
+impl<A: Decode + DeserializeOwned, B: Decode + DeserializeOwned> Decode for (A, B) {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok((reader.get()?, reader.get()?))
+    }
+}
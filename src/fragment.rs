@@ -0,0 +1,280 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::{self, ErrorKind};
+use std::net::{SocketAddr, ToSocketAddrs, UdpSocket};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+/// Largest datagram either side sends or expects to receive.
+pub const MAX_DATAGRAM: usize = 1024;
+/// kind (u8), message id (u32), fragment index (u16), fragment count (u16)
+pub const HEADER_LEN: usize = 9;
+/// CRC32 of the header and payload, little endian, closing every datagram
+pub const CHECKSUM_LEN: usize = 4;
+pub const MAX_PAYLOAD: usize = MAX_DATAGRAM - HEADER_LEN - CHECKSUM_LEN;
+/// Largest message a receiver puts back together, fragments of longer ones are dropped.
+pub const MAX_MESSAGE: usize = 256 * 1024;
+/// Most messages a receiver puts back together for one peer at a time.
+pub const MAX_PARTIAL_PER_PEER: usize = 16;
+/// A partly received message is dropped after this long without a new fragment.
+pub const REASSEMBLY_TIMEOUT: Duration = Duration::from_secs(2);
+/// How long a receiver waits for the next fragment before asking for the
+/// missing ones; sockets passed to `request` should time out this often.
+pub const RESEND_AFTER: Duration = Duration::from_millis(200);
+/// How long a sender keeps the fragments of a message for resend requests.
+const SENT_CACHE_TIME: Duration = Duration::from_secs(5);
+
+const KIND_FRAGMENT: u8 = 0;
+const KIND_RESEND: u8 = 1;
+
+/// Datagrams `parse` has turned down since the process started.
+static REJECTED: AtomicU64 = AtomicU64::new(0);
+
+/// One parsed datagram.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Datagram<'a> {
+    Fragment { message_id: u32, index: u16, count: u16, payload: &'a [u8] },
+    /// Asks the sender of `message_id` for the listed fragments again
+    Resend { message_id: u32, missing: Vec<u16> },
+}
+
+/// Why `parse` turned a datagram down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rejected {
+    /// The checksum does not match, the datagram was damaged on the way
+    Corrupted,
+    /// Too short or inconsistent to be ours
+    Malformed,
+}
+
+impl fmt::Display for Rejected {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rejected::Corrupted => write!(f, "corrupted"),
+            Rejected::Malformed => write!(f, "malformed"),
+        }
+    }
+}
+
+/// Number of datagrams rejected so far.
+pub fn rejected() -> u64 {
+    REJECTED.load(Ordering::Relaxed)
+}
+
+fn seal(mut datagram: Vec<u8>) -> Vec<u8> {
+    let checksum = crc32fast::hash(&datagram);
+    datagram.extend_from_slice(&checksum.to_le_bytes());
+    datagram
+}
+
+fn header(kind: u8, message_id: u32, index: u16, count: u16) -> Vec<u8> {
+    let mut datagram: Vec<u8> = vec![kind];
+    datagram.extend_from_slice(&message_id.to_le_bytes());
+    datagram.extend_from_slice(&index.to_le_bytes());
+    datagram.extend_from_slice(&count.to_le_bytes());
+    datagram
+}
+
+/// Splits `message` into datagrams of at most MAX_DATAGRAM bytes. An empty
+/// message still takes one datagram.
+pub fn fragments(message_id: u32, message: &[u8]) -> Vec<Vec<u8>> {
+    let chunks: Vec<&[u8]> = if message.is_empty() { vec![message] } else { message.chunks(MAX_PAYLOAD).collect() };
+    let count = chunks.len() as u16;
+    chunks.into_iter().enumerate().map(|(index, chunk)| {
+        let mut datagram = header(KIND_FRAGMENT, message_id, index as u16, count);
+        datagram.extend_from_slice(chunk);
+        seal(datagram)
+    }).collect()
+}
+
+fn resend_request(message_id: u32, missing: &[u16]) -> Vec<u8> {
+    let mut datagram = header(KIND_RESEND, message_id, 0, 0);
+    for index in missing.iter().take(MAX_PAYLOAD / 2) {
+        datagram.extend_from_slice(&index.to_le_bytes());
+    }
+    seal(datagram)
+}
+
+/// Checks the datagram's checksum and splits it up. Rejected datagrams are counted.
+pub fn parse(datagram: &[u8]) -> Result<Datagram<'_>, Rejected> {
+    let parsed = unseal(datagram).and_then(parse_unsealed);
+    if parsed.is_err() {
+        REJECTED.fetch_add(1, Ordering::Relaxed);
+    }
+    parsed
+}
+
+fn unseal(datagram: &[u8]) -> Result<&[u8], Rejected> {
+    if datagram.len() < HEADER_LEN + CHECKSUM_LEN {
+        return Err(Rejected::Malformed);
+    }
+    let (datagram, checksum) = datagram.split_at(datagram.len() - CHECKSUM_LEN);
+    if crc32fast::hash(datagram) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+        return Err(Rejected::Corrupted);
+    }
+    Ok(datagram)
+}
+
+fn parse_unsealed(datagram: &[u8]) -> Result<Datagram<'_>, Rejected> {
+    let message_id = u32::from_le_bytes(datagram[1..5].try_into().unwrap());
+    let index = u16::from_le_bytes(datagram[5..7].try_into().unwrap());
+    let count = u16::from_le_bytes(datagram[7..9].try_into().unwrap());
+    let payload = &datagram[HEADER_LEN..];
+    match datagram[0] {
+        KIND_FRAGMENT if index < count => Ok(Datagram::Fragment { message_id, index, count, payload }),
+        KIND_RESEND if payload.len().is_multiple_of(2) => Ok(Datagram::Resend {
+            message_id,
+            missing: payload.chunks(2).map(|index| u16::from_le_bytes([index[0], index[1]])).collect(),
+        }),
+        _ => Err(Rejected::Malformed),
+    }
+}
+
+struct Partial {
+    fragments: Vec<Option<Vec<u8>>>,
+    received: usize,
+    last_seen: Instant,
+    asked: Instant,
+}
+
+/// Puts messages from any number of peers back together.
+#[derive(Default)]
+pub struct Reassembler {
+    partial: HashMap<(SocketAddr, u32), Partial>,
+    /// Messages completed recently, so late duplicates of their fragments
+    /// don't start a new message that would be asked for again
+    completed: HashMap<(SocketAddr, u32), Instant>,
+}
+
+impl Reassembler {
+    /// Adds a fragment and returns the message once all of its fragments are in.
+    /// Messages over MAX_MESSAGE, and new ones from a peer already sending
+    /// MAX_PARTIAL_PER_PEER, are dropped before anything is allocated for them.
+    pub fn add(&mut self, from: SocketAddr, message_id: u32, index: u16, count: u16, payload: &[u8]) -> Option<Vec<u8>> {
+        if count == 1 {
+            return Some(payload.to_vec());
+        }
+        if count as usize > MAX_MESSAGE.div_ceil(MAX_PAYLOAD) {
+            return None;
+        }
+        let key = (from, message_id);
+        if self.completed.contains_key(&key) {
+            return None;
+        }
+        if !self.partial.contains_key(&key)
+            && self.partial.keys().filter(|(peer, _)| *peer == from).count() >= MAX_PARTIAL_PER_PEER
+        {
+            return None;
+        }
+        let now = Instant::now();
+        let partial = self.partial.entry(key).or_insert_with(|| Partial {
+            fragments: vec![None; count as usize],
+            received: 0,
+            last_seen: now,
+            asked: now,
+        });
+        if partial.fragments.len() != count as usize {
+            return None;
+        }
+        partial.last_seen = now;
+        if partial.fragments[index as usize].is_none() {
+            partial.fragments[index as usize] = Some(payload.to_vec());
+            partial.received += 1;
+        }
+        if partial.received < count as usize {
+            return None;
+        }
+        let partial = self.partial.remove(&key).unwrap();
+        self.completed.insert(key, now);
+        Some(partial.fragments.into_iter().flatten().flatten().collect())
+    }
+
+    /// Drops messages that stopped making progress and returns the resend
+    /// requests due for the others, with the peer to send each one to.
+    pub fn poll(&mut self) -> Vec<(SocketAddr, Vec<u8>)> {
+        let now = Instant::now();
+        self.completed.retain(|_, at| now - *at < REASSEMBLY_TIMEOUT);
+        self.partial.retain(|(from, message_id), partial| {
+            let alive = now - partial.last_seen < REASSEMBLY_TIMEOUT;
+            if !alive {
+                eprintln!("Dropping message {} from {}: {} of {} fragments arrived", message_id, from, partial.received, partial.fragments.len());
+            }
+            alive
+        });
+        let mut requests: Vec<(SocketAddr, Vec<u8>)> = Vec::new();
+        for ((from, message_id), partial) in self.partial.iter_mut() {
+            if now - partial.last_seen >= RESEND_AFTER && now - partial.asked >= RESEND_AFTER {
+                partial.asked = now;
+                let missing: Vec<u16> = (0..partial.fragments.len() as u16).filter(|index| partial.fragments[*index as usize].is_none()).collect();
+                requests.push((*from, resend_request(*message_id, &missing)));
+            }
+        }
+        requests
+    }
+}
+
+/// The datagrams of one sent message and when they went out.
+type Sent = (Instant, Vec<Vec<u8>>);
+
+/// Fragments of recently sent multi-fragment messages, kept to answer resend requests.
+#[derive(Default)]
+pub struct SentCache {
+    sent: HashMap<(SocketAddr, u32), Sent>,
+}
+
+impl SentCache {
+    pub fn insert(&mut self, to: SocketAddr, message_id: u32, datagrams: &[Vec<u8>]) {
+        let now = Instant::now();
+        self.sent.retain(|_, (at, _)| now - *at < SENT_CACHE_TIME);
+        self.sent.insert((to, message_id), (now, datagrams.to_vec()));
+    }
+
+    pub fn resend(&self, to: SocketAddr, message_id: u32, missing: &[u16]) -> Vec<Vec<u8>> {
+        match self.sent.get(&(to, message_id)) {
+            Some((_, datagrams)) => missing.iter().filter_map(|index| datagrams.get(*index as usize).cloned()).collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Sends `message` to `to` and waits up to `timeout` for the reply carrying
+/// the same message id. Meanwhile it answers resend requests for the message
+/// and asks for missing reply fragments. `Ok(None)` if no complete reply came.
+pub fn request(socket: &UdpSocket, to: &str, message_id: u32, message: &[u8], timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+    let to: SocketAddr = to.to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::new(ErrorKind::InvalidInput, "address does not resolve"))?;
+    let datagrams = fragments(message_id, message);
+    for datagram in &datagrams {
+        socket.send_to(datagram, to)?;
+    }
+    let mut reassembler = Reassembler::default();
+    let mut buf = [0u8; MAX_DATAGRAM];
+    let deadline = Instant::now() + timeout;
+    while Instant::now() < deadline {
+        match socket.recv_from(&mut buf) {
+            Ok((num_bytes, from)) => match parse(&buf[..num_bytes]) {
+                Ok(Datagram::Fragment { message_id: id, index, count, payload }) if id == message_id => {
+                    if let Some(reply) = reassembler.add(from, id, index, count, payload) {
+                        return Ok(Some(reply));
+                    }
+                }
+                Ok(Datagram::Resend { message_id: id, missing }) if id == message_id => {
+                    for index in missing {
+                        if let Some(datagram) = datagrams.get(index as usize) {
+                            socket.send_to(datagram, to)?;
+                        }
+                    }
+                }
+                Err(reason) => eprintln!("Dropping {} datagram from {} ({} rejected so far)", reason, from, rejected()),
+                // A late reply to an earlier request
+                _ => {}
+            },
+            Err(ref e) if e.kind() == ErrorKind::TimedOut || e.kind() == ErrorKind::WouldBlock => {}
+            Err(e) => return Err(e),
+        }
+        for (from, datagram) in reassembler.poll() {
+            socket.send_to(&datagram, from)?;
+        }
+    }
+    Ok(None)
+}
@@ -0,0 +1,17 @@
+//! Writes the Python message module generated by build.rs from
+//! src/messages.idl over the checked in src/message_gen.py. Run after a change
+//! of the IDL with `cargo run --bin gen_python`.
+use std::fs;
+use std::path::Path;
+
+const GENERATED: &str = include_str!(concat!(env!("OUT_DIR"), "/message_gen.py"));
+
+fn main() {
+    let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("src/message_gen.py");
+    if fs::read_to_string(&path).ok().as_deref() == Some(GENERATED) {
+        println!("{} is up to date", path.display());
+        return;
+    }
+    fs::write(&path, GENERATED).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    println!("Wrote {}", path.display());
+}
@@ -0,0 +1,225 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::message::{Booking, Day, FacilityRecord, slot_time};
+
+// The schedule only knows weekdays, so events are placed in the current week.
+
+struct Event {
+    uid: String,
+    summary: String,
+    day: Day,
+    start_slot: u8,
+    num_slots: u8,
+}
+
+/// Renders a facility's schedule as an RFC 5545 calendar, one event per booked interval.
+pub fn facility_calendar(name: &str, record: &FacilityRecord) -> String {
+    let mut events: Vec<Event> = Vec::new();
+    for day in Day::ALL {
+        let schedule = record.schedule(day);
+        for interval in schedule.bookings_in(0, schedule.num_slots()) {
+            events.push(Event {
+                uid: format!("{}-{:?}-{}@facility-booking", name, day, interval.start),
+                summary: format!("{} booked by {}", name, interval.owner),
+                day,
+                start_slot: interval.start as u8,
+                num_slots: (interval.end - interval.start) as u8,
+            });
+        }
+    }
+    render(&events)
+}
+
+/// Renders a list of bookings, keyed by confirmation id, as an RFC 5545 calendar.
+pub fn bookings_calendar(bookings: &[(u8, Booking)]) -> String {
+    let events: Vec<Event> = bookings.iter().map(|(confirmation_id, booking)| Event {
+        uid: format!("booking-{}@facility-booking", confirmation_id),
+        summary: format!("{} (confirmation #{})", booking.facility_name, confirmation_id),
+        day: booking.day,
+        start_slot: booking.start_slot,
+        num_slots: booking.num_slots,
+    }).collect();
+    render(&events)
+}
+
+/// Combines calendars rendered by several servers into a single calendar.
+pub fn merge_calendars(calendars: &[String]) -> String {
+    let Some(first) = calendars.first() else {
+        return render(&[]);
+    };
+    let body_start = |calendar: &str| calendar.find("BEGIN:VEVENT").or_else(|| calendar.find("END:VCALENDAR")).unwrap_or(calendar.len());
+    let mut output = first[..body_start(first)].to_string();
+    for calendar in calendars {
+        let end = calendar.find("END:VCALENDAR").unwrap_or(calendar.len());
+        output.push_str(&calendar[body_start(calendar).min(end)..end]);
+    }
+    output.push_str("END:VCALENDAR\r\n");
+    output
+}
+
+fn render(events: &[Event]) -> String {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let today = now / 86400;
+    let monday = monday_of(today);
+    let stamp = format!("{}T{}Z", date(today), clock(now % 86400));
+
+    let mut lines: Vec<String> = vec![
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        "PRODID:-//distributed-reservation//facility booking//EN".to_string(),
+        "CALSCALE:GREGORIAN".to_string(),
+    ];
+    for event in events {
+        let day = date(monday + event.day as u64);
+        lines.push("BEGIN:VEVENT".to_string());
+        lines.push(format!("UID:{}", escape(&event.uid)));
+        lines.push(format!("DTSTAMP:{}", stamp));
+        lines.push(format!("DTSTART:{}T{}00", day, slot_time(event.start_slot).replace(':', "")));
+        lines.push(format!("DTEND:{}T{}00", day, slot_time(event.start_slot.saturating_add(event.num_slots)).replace(':', "")));
+        lines.push(format!("SUMMARY:{}", escape(&event.summary)));
+        lines.push("END:VEVENT".to_string());
+    }
+    lines.push("END:VCALENDAR".to_string());
+
+    let mut output = String::new();
+    for line in lines {
+        output.push_str(&fold(&line));
+        output.push_str("\r\n");
+    }
+    output
+}
+
+/// The Monday of the week holding `today`, both in days since the Unix epoch.
+fn monday_of(today: u64) -> u64 {
+    // 1970-01-01 was a Thursday, three days after a Monday.
+    today - (today + 3) % 7
+}
+
+/// Formats days since the Unix epoch as YYYYMMDD (civil-from-days, proleptic Gregorian).
+fn date(days: u64) -> String {
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let d = doy - (153 * mp + 2) / 5 + 1;
+    let m = if mp < 10 { mp + 3 } else { mp - 9 };
+    let y = yoe + era * 400 + if m <= 2 { 1 } else { 0 };
+    format!("{:04}{:02}{:02}", y, m, d)
+}
+
+fn clock(seconds: u64) -> String {
+    format!("{:02}{:02}{:02}", seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace(';', "\\;").replace(',', "\\,").replace('\n', "\\n")
+}
+
+/// Folds a content line so that no physical line exceeds 75 octets.
+fn fold(line: &str) -> String {
+    let mut output = String::new();
+    let mut width = 0;
+    for c in line.chars() {
+        if width + c.len_utf8() > 75 {
+            output.push_str("\r\n ");
+            width = 1;
+        }
+        output.push(c);
+        width += c.len_utf8();
+    }
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn booking(facility_name: &str, user_id: u8) -> Booking {
+        Booking { facility_name: facility_name.to_string(), day: Day::Tuesday, start_slot: 2, num_slots: 3, user_id }
+    }
+
+    /// The calendar without its DTSTAMP lines, which change with the clock.
+    fn unstamped(calendar: &str) -> String {
+        calendar.split("\r\n").filter(|line| !line.starts_with("DTSTAMP:")).collect::<Vec<&str>>().join("\r\n")
+    }
+
+    #[test]
+    fn date_handles_leap_years() {
+        assert_eq!(date(0), "19700101");
+        assert_eq!(date(19723), "20240101");
+        assert_eq!(date(19723 + 59), "20240229");
+        assert_eq!(date(19723 + 60), "20240301");
+        assert_eq!(date(10957 + 59), "20000229");
+        assert_eq!(date(10957 + 365), "20001231");
+    }
+
+    #[test]
+    fn weeks_start_on_monday() {
+        // 2024-01-01 was a Monday
+        for today in 19723..19730 {
+            assert_eq!(monday_of(today), 19723, "{}", date(today));
+        }
+        assert_eq!(monday_of(19730), 19730);
+        assert_eq!(clock(13 * 3600 + 5 * 60 + 9), "130509");
+    }
+
+    #[test]
+    fn escape_quotes_text_separators() {
+        assert_eq!(escape("a,b;c\\d\ne"), "a\\,b\\;c\\\\d\\ne");
+        assert_eq!(escape("plain text: ok"), "plain text: ok");
+    }
+
+    #[test]
+    fn fold_keeps_lines_within_75_octets() {
+        assert_eq!(fold(&"a".repeat(75)), "a".repeat(75));
+        let folded = fold(&"a".repeat(160));
+        let lines: Vec<&str> = folded.split("\r\n").collect();
+        assert_eq!(lines, ["a".repeat(75), format!(" {}", "a".repeat(74)), format!(" {}", "a".repeat(11))]);
+        // Multi-octet characters are never split across lines
+        let folded = fold(&"é".repeat(40));
+        for line in folded.split("\r\n") {
+            assert!(line.len() <= 75, "{:?}", line);
+        }
+        assert_eq!(folded.replace("\r\n ", ""), "é".repeat(40));
+    }
+
+    #[test]
+    fn calendars_hold_one_event_per_booking() {
+        let calendar = bookings_calendar(&[(4, booking("gym", 1))]);
+        assert!(calendar.starts_with("BEGIN:VCALENDAR\r\nVERSION:2.0\r\n"));
+        assert!(calendar.ends_with("END:VEVENT\r\nEND:VCALENDAR\r\n"));
+        assert!(calendar.contains("UID:booking-4@facility-booking\r\n"));
+        assert!(calendar.contains("SUMMARY:gym (confirmation #4)\r\n"));
+        assert!(calendar.contains("T090000\r\nDTEND:"));
+        assert!(calendar.contains("T103000\r\nSUMMARY:"));
+    }
+
+    #[test]
+    fn events_may_end_at_the_last_slot() {
+        let mut last = booking("gym", 1);
+        last.start_slot = 14;
+        last.num_slots = 2;
+        assert!(bookings_calendar(&[(1, last.clone())]).contains("T150000\r\nDTEND:"));
+        assert!(bookings_calendar(&[(1, last.clone())]).contains("T160000\r\nSUMMARY:"));
+        // A malformed booking running past the end of any day
+        last.start_slot = 250;
+        last.num_slots = 10;
+        assert!(bookings_calendar(&[(1, last)]).contains("DTEND:"));
+    }
+
+    #[test]
+    fn merge_calendars_keeps_every_event_once() {
+        let first = bookings_calendar(&[(1, booking("gym", 1))]);
+        let second = bookings_calendar(&[(2, booking("pool", 1)), (5, booking("court", 1))]);
+        let empty = bookings_calendar(&[]);
+        let merged = merge_calendars(&[first.clone(), empty.clone(), second]);
+        let all = bookings_calendar(&[(1, booking("gym", 1)), (2, booking("pool", 1)), (5, booking("court", 1))]);
+        assert_eq!(unstamped(&merged), unstamped(&all));
+        assert_eq!(merged.matches("BEGIN:VCALENDAR").count(), 1);
+        assert_eq!(merged.matches("END:VCALENDAR").count(), 1);
+        assert_eq!(merge_calendars(std::slice::from_ref(&first)), first);
+        assert_eq!(unstamped(&merge_calendars(&[empty.clone(), first.clone()])), unstamped(&first));
+        assert_eq!(unstamped(&merge_calendars(&[])), unstamped(&empty));
+    }
+}
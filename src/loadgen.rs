@@ -0,0 +1,303 @@
+use std::collections::BTreeMap;
+use std::net::UdpSocket;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use clap::{error::ErrorKind, CommandFactory, Parser};
+pub mod message;
+pub mod schedule;
+pub mod shard;
+pub mod fragment;
+pub mod codec;
+use shard::ShardMap;
+use codec::Decode;
+use message::{request, reply_status, versioned, ErrorCode, ReplyStatus, RequestType, QueryRequest, QueryResponse, Booking, BookRequest, BookingResponse, Update, UpdateResponse, Day};
+
+#[derive(Parser, Debug)]
+#[command(name = "Facility load generator", about = "Drives QUERY/BOOK/UPDATE traffic at a facility booking server")]
+struct Cli {
+    /// Any server of the deployment, requests are routed with its shard map
+    #[arg(short, long, default_value = "127.0.0.1:5000")]
+    server: String,
+
+    /// Number of simulated clients, each on its own thread and socket
+    #[arg(short, long, default_value_t = 8)]
+    clients: usize,
+
+    /// How long to generate traffic for, in seconds
+    #[arg(short, long, default_value_t = 10)]
+    duration: u64,
+
+    /// Relative weight of QUERY requests in the mix
+    #[arg(long, default_value_t = 60)]
+    query_weight: u32,
+
+    /// Relative weight of BOOK requests in the mix
+    #[arg(long, default_value_t = 30)]
+    book_weight: u32,
+
+    /// Relative weight of UPDATE requests in the mix
+    #[arg(long, default_value_t = 10)]
+    update_weight: u32,
+
+    /// Facilities to spread requests over
+    #[arg(short, long, value_delimiter = ',', default_value = "facility1,facility2,facility3")]
+    facilities: Vec<String>,
+
+    /// Time to wait for a reply before resending, in milliseconds
+    #[arg(long, default_value_t = 500)]
+    timeout_ms: u64,
+
+    /// Resends before a request is counted as lost
+    #[arg(long, default_value_t = 3)]
+    max_retries: u32,
+
+    /// Seed for the request mix, taken from the clock if omitted
+    #[arg(long)]
+    seed: Option<u64>,
+}
+
+impl Cli {
+    /// Sum of the weights, in u64 so large weights can't overflow it.
+    fn total_weight(&self) -> u64 {
+        self.query_weight as u64 + self.book_weight as u64 + self.update_weight as u64
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Query, Book, Update
+}
+
+/// Outcome of one request as seen by a simulated client.
+struct Sample {
+    kind: Kind,
+    latency: Duration,
+    retries: u32,
+    lost: bool,
+    /// Why the server refused a BOOK or UPDATE
+    refused: Option<ErrorCode>,
+}
+
+/// Servers a request is sent to, following redirects, before it is counted as
+/// lost; as in the client, so servers that disagree on the owner or the leader
+/// can't bounce it forever.
+const MAX_HOPS: u32 = 4;
+
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self, bound: u64) -> u64 {
+        self.0 = self.0.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        (self.0 >> 33) % bound.max(1)
+    }
+}
+
+/// Sends `output_stream` and waits for a reply, resending on timeout and
+/// following up to MAX_HOPS shard and primary redirects. Returns the payload
+/// of the reply.
+/// Each call takes a new message id, so late replies to an earlier request are
+/// never taken for this one.
+fn exchange(socket: &UdpSocket, cli: &Cli, next_message_id: &mut u32, server_address: &str, output_stream: &[u8]) -> (Option<Vec<u8>>, u32) {
+    let message_id = *next_message_id;
+    *next_message_id = next_message_id.wrapping_add(1).max(1);
+    let output_stream: Vec<u8> = versioned(output_stream);
+    let mut server_address: String = server_address.to_string();
+    let mut retries = 0;
+    let mut hops = 1;
+    let result = loop {
+        match fragment::request(socket, &server_address, message_id, &output_stream, Duration::from_millis(cli.timeout_ms)) {
+            Ok(Some(reply)) => match reply_status(&reply) {
+                Ok((ReplyStatus::Ok, mut reader)) => break Some(reader.rest().to_vec()),
+                Ok((ReplyStatus::WrongShard { owner: next } | ReplyStatus::NotLeader { leader: next }, _)) => {
+                    if hops == MAX_HOPS {
+                        eprintln!("Giving up after {} redirects, the last from {} to {}", hops, server_address, next);
+                        break None;
+                    }
+                    hops += 1;
+                    server_address = next;
+                }
+                Ok((status @ ReplyStatus::UnsupportedVersion { .. }, _)) => panic!("{} refused the request: {}", server_address, status),
+                Err(e) => {
+                    eprintln!("{} sent a reply that does not decode: {}", server_address, e);
+                    break None;
+                }
+            },
+            Ok(None) => {
+                if retries == cli.max_retries {
+                    break None;
+                }
+                retries += 1;
+            }
+            Err(e) => {
+                eprintln!("An I/O error occurred: {}", e.kind());
+                break None;
+            }
+        }
+    };
+    (result, retries)
+}
+
+fn bind_socket(cli: &Cli) -> UdpSocket {
+    let socket = UdpSocket::bind("0.0.0.0:0").unwrap();
+    socket.set_read_timeout(Some(fragment::RESEND_AFTER.min(Duration::from_millis(cli.timeout_ms.max(1))))).unwrap();
+    socket
+}
+
+fn run_client(cli: &Cli, shard_map: &ShardMap, client_index: usize, seed: u64, deadline: Instant) -> Vec<Sample> {
+    let mut rng = Rng(seed ^ (client_index as u64).wrapping_mul(0x9E3779B97F4A7C15));
+    let socket = bind_socket(cli);
+    let mut next_message_id: u32 = 1;
+    let user_id = (client_index % 255 + 1) as u8;
+    let total_weight = cli.total_weight();
+    let mut confirmation_ids: Vec<u8> = Vec::new();
+    let mut samples: Vec<Sample> = Vec::new();
+
+    while Instant::now() < deadline {
+        let pick = rng.next(total_weight);
+        let mut kind = if pick < cli.query_weight as u64 {
+            Kind::Query
+        } else if pick < cli.query_weight as u64 + cli.book_weight as u64 {
+            Kind::Book
+        } else {
+            Kind::Update
+        };
+        // Nothing to move yet, book something first
+        if kind == Kind::Update && confirmation_ids.is_empty() {
+            kind = Kind::Book;
+        }
+        let facility_name = cli.facilities[rng.next(cli.facilities.len() as u64) as usize].clone();
+        let day = Day::ALL[rng.next(Day::ALL.len() as u64) as usize];
+
+        let output_stream: Vec<u8>;
+        let shard: usize;
+        match kind {
+            Kind::Query => {
+                shard = shard_map.owner(&facility_name);
+                output_stream = request(RequestType::QUERY, &QueryRequest { name: facility_name, days: vec![day] });
+            }
+            Kind::Book => {
+                let num_slots = 1 + rng.next(3) as u8;
+                let start_slot = rng.next(16 - num_slots as u64 + 1) as u8;
+                shard = shard_map.owner(&facility_name);
+                // Without a token, so only servers without --users take bookings and updates
+                output_stream = request(RequestType::BOOK, &BookRequest { auth: None, booking: Booking { facility_name, day, start_slot, num_slots, user_id } });
+            }
+            Kind::Update => {
+                let confirmation_id = confirmation_ids[rng.next(confirmation_ids.len() as u64) as usize];
+                let offset = rng.next(5) as i8 - 2;
+                shard = shard_map.confirmation_owner(confirmation_id);
+                output_stream = request(RequestType::UPDATE, &Update { auth: None, confirmation_id, offset });
+            }
+        }
+
+        let start = Instant::now();
+        let (reply, retries) = exchange(&socket, cli, &mut next_message_id, &shard_map.shards[shard], &output_stream);
+        let latency = start.elapsed();
+        let mut refused = None;
+        let mut lost = reply.is_none();
+        if let Some(reply) = &reply {
+            let decoded = match kind {
+                Kind::Query => QueryResponse::from_bytes(reply).map(|_| ()),
+                Kind::Book => BookingResponse::from_bytes(reply).map(|resp| {
+                    if resp.success {
+                        confirmation_ids.push(resp.confirmation_id);
+                    }
+                    refused = (!resp.success).then_some(resp.error);
+                }),
+                Kind::Update => UpdateResponse::from_bytes(reply).map(|resp| refused = (!resp.success).then_some(resp.error)),
+            };
+            // Counted as lost, the request's outcome is unknown
+            if let Err(e) = decoded {
+                eprintln!("Dropping a {:?} reply that does not decode: {}", kind, e);
+                lost = true;
+            }
+        }
+        samples.push(Sample { kind, latency, retries, lost, refused });
+    }
+    samples
+}
+
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = ((p / 100.0) * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+fn report(label: &str, samples: &[&Sample], elapsed: Duration) {
+    let mut latencies: Vec<Duration> = samples.iter().filter(|s| !s.lost).map(|s| s.latency).collect();
+    latencies.sort();
+    let retries: u32 = samples.iter().map(|s| s.retries).sum();
+    let lost = samples.iter().filter(|s| s.lost).count();
+    println!("{:<7} {:>8} req {:>9.1} req/s  p50 {:>8.2?}  p90 {:>8.2?}  p99 {:>8.2?}  max {:>8.2?}  retries {:>5}  lost {:>4}",
+        label, samples.len(), samples.len() as f64 / elapsed.as_secs_f64(),
+        percentile(&latencies, 50.0), percentile(&latencies, 90.0), percentile(&latencies, 99.0),
+        latencies.last().copied().unwrap_or_default(), retries, lost);
+}
+
+/// Prints how many answered requests lost a race for their slots, and apart
+/// from those how many were refused for any other reason, by error code.
+fn report_refusals(label: &str, answered: &[&Sample]) {
+    let percent = |count: usize| if answered.is_empty() { 0.0 } else { 100.0 * count as f64 / answered.len() as f64 };
+    let mut refusals: BTreeMap<ErrorCode, usize> = BTreeMap::new();
+    for error in answered.iter().filter_map(|s| s.refused) {
+        *refusals.entry(error).or_default() += 1;
+    }
+    let conflicts = refusals.remove(&ErrorCode::SlotTaken).unwrap_or(0);
+    let refused: usize = refusals.values().sum();
+    let reasons: Vec<String> = refusals.iter().map(|(error, count)| format!("{:?} {}", error, count)).collect();
+    println!("{:<7} conflicts: {}/{} ({:.1}%)", label, conflicts, answered.len(), percent(conflicts));
+    println!("{:<7} refusals:  {}/{} ({:.1}%) {}", label, refused, answered.len(), percent(refused), reasons.join(", "));
+}
+
+fn main() {
+    let cli = Arc::new(Cli::parse());
+    if cli.facilities.is_empty() {
+        Cli::command().error(ErrorKind::ValueValidation, "need at least one facility").exit();
+    }
+    if cli.total_weight() == 0 {
+        Cli::command().error(ErrorKind::ValueValidation, "the request mix has only zero weights").exit();
+    }
+    let seed = cli.seed.unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64);
+    println!("{} clients for {}s against {} (mix query/book/update = {}/{}/{}, seed {})",
+        cli.clients, cli.duration, cli.server, cli.query_weight, cli.book_weight, cli.update_weight, seed);
+
+    let shard_map = {
+        let socket = bind_socket(&cli);
+        match exchange(&socket, &cli, &mut 1, &cli.server, &request(RequestType::SHARD_MAP, &())) {
+            (Some(reply), _) => match ShardMap::from_bytes(&reply) {
+                Ok(shard_map) => Arc::new(shard_map),
+                Err(e) => {
+                    eprintln!("{} sent a shard map that does not decode: {}", cli.server, e);
+                    return;
+                }
+            },
+            (None, _) => {
+                eprintln!("No reply from {}", cli.server);
+                return;
+            }
+        }
+    };
+
+    let start = Instant::now();
+    let deadline = start + Duration::from_secs(cli.duration);
+    let handles: Vec<thread::JoinHandle<Vec<Sample>>> = (0..cli.clients).map(|client_index| {
+        let cli = Arc::clone(&cli);
+        let shard_map = Arc::clone(&shard_map);
+        thread::spawn(move || run_client(&cli, &shard_map, client_index, seed, deadline))
+    }).collect();
+    let samples: Vec<Sample> = handles.into_iter().flat_map(|handle| handle.join().unwrap()).collect();
+    let elapsed = start.elapsed();
+
+    report("all", &samples.iter().collect::<Vec<&Sample>>(), elapsed);
+    for (label, kind) in [("query", Kind::Query), ("book", Kind::Book), ("update", Kind::Update)] {
+        report(label, &samples.iter().filter(|s| s.kind == kind).collect::<Vec<&Sample>>(), elapsed);
+    }
+
+    for (label, kind) in [("booking", Kind::Book), ("update", Kind::Update)] {
+        report_refusals(label, &samples.iter().filter(|s| s.kind == kind && !s.lost).collect::<Vec<&Sample>>());
+    }
+    println!("rejected datagrams: {}", fragment::rejected());
+}
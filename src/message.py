@@ -1,207 +1,92 @@
 
-from enum import Enum
 from dataclasses import dataclass
 from typing import List
 import struct
+import sys
+import zlib
+
+from codec import Reader, Writer
+# The message types and PROTOCOL_VERSION, generated from messages.idl
+from message_gen import *  # noqa: F401,F403
+
+# Datagram header: kind (u8), message id (u32), fragment index (u16), fragment count (u16),
+# then the payload and a CRC32 of both (u32)
+MAX_DATAGRAM = 1024
+FRAGMENT_HEADER = struct.Struct("<BIHH")
+CHECKSUM = struct.Struct("<I")
+MAX_PAYLOAD = MAX_DATAGRAM - FRAGMENT_HEADER.size - CHECKSUM.size
+KIND_FRAGMENT = 0
+
+# Datagrams dropped for a bad checksum or layout
+rejected = 0
+
+
+def fragments(message_id: int, message: bytes) -> List[bytes]:
+    chunks = [message[i:i + MAX_PAYLOAD] for i in range(0, len(message), MAX_PAYLOAD)] or [b""]
+    datagrams = []
+    for i, chunk in enumerate(chunks):
+        datagram = FRAGMENT_HEADER.pack(KIND_FRAGMENT, message_id, i, len(chunks)) + chunk
+        datagrams.append(datagram + CHECKSUM.pack(zlib.crc32(datagram)))
+    return datagrams
+
+def reject(reason: str, datagram: bytes) -> None:
+    global rejected
+    rejected += 1
+    print(f"Dropping {reason} datagram of {len(datagram)} bytes ({rejected} rejected so far)", file=sys.stderr)
+
+def parse_fragment(datagram: bytes):
+    """(message_id, index, count, payload), or None for anything but an intact fragment."""
+    if len(datagram) < FRAGMENT_HEADER.size + CHECKSUM.size:
+        reject("malformed", datagram)
+        return None
+    body, (checksum,) = datagram[:-CHECKSUM.size], CHECKSUM.unpack_from(datagram, len(datagram) - CHECKSUM.size)
+    if zlib.crc32(body) != checksum:
+        reject("corrupted", datagram)
+        return None
+    kind, message_id, index, count = FRAGMENT_HEADER.unpack_from(body, 0)
+    if kind != KIND_FRAGMENT or index >= count:
+        return None
+    return message_id, index, count, body[FRAGMENT_HEADER.size:]
+
+
+def day_from_string(s: str) -> Day:
+    """Day named by `s`, in any case, like From<&str> for Day in message.rs."""
+    try:
+        return Day[s.strip().upper()]
+    except KeyError:
+        raise ValueError(f"Invalid day: {s}") from None
+
+def request(request_type: RequestType, body: Message) -> bytes:
+    """A request as sent: the protocol version, its type, then its body."""
+    writer = Writer.versioned(PROTOCOL_VERSION)
+    request_type.encode(writer)
+    body.encode(writer)
+    return writer.into_bytes()
 
 
-
-def write_u8(buf: bytearray, v: int) -> None:
-    buf.append(v & 0xFF)
-
-def write_i8(buf: bytearray, v: int) -> None:
-    buf.append((v + 256) % 256)
-
-def write_u32_le(buf: bytearray, v: int) -> None:
-    buf += struct.pack("<I", v)
-
-def write_string(buf: bytearray, s: str) -> None:
-    buf += s.encode("utf-8") + b"\x00"
-
-
-def read_u8(b: bytes, pos: int) -> (int, int):
-    return b[pos], pos + 1
-
-def read_i8(b: bytes, pos: int) -> (int, int):
-    v, pos = read_u8(b, pos)
-    return (v if v < 128 else v - 256), pos
-
-def read_u32_le(b: bytes, pos: int) -> (int, int):
-    return struct.unpack_from("<I", b, pos)[0], pos + 4
-
-def read_string(b: bytes, pos: int) -> (str, int):
-    start = pos
-
-    while pos < len(b) and b[pos] != 0:
-        pos += 1
-    if pos >= len(b):
-        raise ValueError("Missing null terminator while reading string")
-    s = b[start:pos].decode("utf-8")
-    pos += 1  
-    return s, pos
-
-
-class RequestType(Enum):
-    QUERY = 0
-    BOOK = 1
-    UPDATE = 2
-    MONITOR = 3
-
-class Day(Enum):
-    Monday = 0
-    Tuesday = 1
-    Wednesday = 2
-    Thursday = 3
-    Friday = 4
-    Saturday = 5
-    Sunday = 6
-
-    @classmethod
-    def from_string(cls, s: str) -> "Day":
-        m = {
-            "monday": cls.Monday, "tuesday": cls.Tuesday, "wednesday": cls.Wednesday,
-            "thursday": cls.Thursday, "friday": cls.Friday, "saturday": cls.Saturday,
-            "sunday": cls.Sunday
-        }
-        k = s.strip().lower()
-        if k not in m:
-            raise ValueError(f"Invalid day: {s}")
-        return m[k]
-
-    def __str__(self) -> str:
-        return self.name
-
-
-@dataclass
-class QueryRequest:
-    name: str
-    days: List[Day]
-
-    def serialize(self) -> bytes:
-        buf = bytearray()
-        write_string(buf, self.name)
-        # write_u8(buf, len(self.days))
-        for d in self.days:
-            write_u8(buf, d.value)
-        return bytes(buf)
-
 @dataclass
-class QueryResponse:
-  
-    available: bytes
-
-
-    @classmethod
-    def deserialize(cls, b: bytes, pos: int = 0) -> "QueryResponse":
-        _name, pos = read_string(b, pos)   
-        return cls(available=b[pos:])
-
-
-# ----- 预约 -----
-@dataclass
-class Booking:
-    facility_name: str
-    day: Day
-    start_slot: int
-    num_slots: int
-    user_id: int
-
-    def serialize(self) -> bytes:
-        buf = bytearray()
-        write_string(buf, self.facility_name)
-        write_u8(buf, self.day.value)
-        write_u8(buf, self.start_slot)
-        write_u8(buf, self.num_slots)
-        write_u8(buf, self.user_id)
-        return bytes(buf)
-
-@dataclass
-class BookingResponse:
-    confirmation_id: int
-    message: str
-
-    @classmethod
-    def deserialize(cls, b: bytes, pos: int = 0) -> "BookingResponse":
-        cid, pos = read_u32_le(b, pos)
-        msg, pos = read_string(b, pos)
-        return cls(confirmation_id=cid, message=msg)
-
-
-@dataclass
-class Update:
-    confirmation_id: int
-    offset: int  
-    def serialize(self) -> bytes:
-        buf = bytearray()
-        write_u32_le(buf, self.confirmation_id)
-        write_i8(buf, self.offset)
-        return bytes(buf)
-
-# @dataclass
-# class UpdateResponse:
-#     status: int  
-
-#     @classmethod
-#     def deserialize(cls, b: bytes, pos: int = 0) -> "UpdateResponse":
-#         status, pos = read_u8(b, pos)
-#         msg, pos = read_string(b, pos)
-#         return cls(status=status, message=msg)
-@dataclass
-class UpdateResponse:
-    status: int   # 0 success, 1 failure
-    message: str
-
-    @classmethod
-    def deserialize(cls, b: bytes, pos: int = 0) -> "UpdateResponse":
-        status, pos = read_u8(b, pos)
-        msg, pos = read_string(b, pos)
-        return cls(status=status, message=msg)
-
-@dataclass
-class Monitor:
-    duration: int  
-
-    def serialize(self) -> bytes:
-        buf = bytearray()
-        write_u32_le(buf, self.duration)
-        return bytes(buf)
-
-
-@dataclass
-class Record:
-    slots: bytes 
-
-@dataclass
-class FacilityRecord:
+class FacilityRecord(Message):
     """
     Represents the full weekly schedule for a facility.
     The server sends the complete record (all days) upon any change.
     """
-    name: str
     schedule: dict[Day, bytes]  # Maps Day enum to 16-byte slot data
 
+    SLOTS_PER_DAY = 16
+
+    def encode(self, writer: Writer) -> None:
+        for day in Day:
+            writer.raw(self.schedule[day])
+
     @classmethod
-    def deserialize(cls, b: bytes, pos: int = 0) -> "FacilityRecord":
-        # The server sends: facility_name (string) + full record (5 days * 16 bytes)
-        name, pos = read_string(b, pos)
-
-        schedule = {}
-        # The Rust server sends Monday through Friday
-        days_in_order = [Day.Monday, Day.Tuesday, Day.Wednesday, Day.Thursday, Day.Friday]
-        
-        for day in days_in_order:
-            num_slots = 16
-            if pos + num_slots > len(b):
-                raise ValueError(f"Buffer overflow while reading schedule for {day.name}")
-            schedule[day] = b[pos:pos+num_slots]
-            pos += num_slots
-            
-        return cls(name=name, schedule=schedule)
+    def decode(cls, reader: Reader) -> "FacilityRecord":
+        # The server sends the full record (5 days * 16 bytes), without the facility name
+        return cls(schedule={day: reader.take(cls.SLOTS_PER_DAY) for day in Day})
 
     def __str__(self) -> str:
-        lines = [f"--- Facility Update: {self.name} ---"]
+        lines = ["--- Facility Update ---"]
         for day, slots in self.schedule.items():
-            lines.append(f"\n{day.name}:")
+            lines.append(f"\n{day.name.capitalize()}:")
             for i, v in enumerate(slots):
                 hour = 8 + i // 2
                 minute = "00" if i % 2 == 0 else "30"
@@ -1,26 +1,33 @@
 use std::fmt;
-use std::ops::{Index, IndexMut};
+use std::ops::RangeInclusive;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use crate::codec::{Decode, DecodeError, Encode, Format, Reader, Writer};
+use crate::schedule::Schedule;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
-pub enum Day {
-    Monday,
-    Tuesday,
-    Wednesday,
-    Thursday,
-    Friday,
+// PROTOCOL_VERSION and the message types, generated by build.rs from messages.idl
+include!(concat!(env!("OUT_DIR"), "/messages.rs"));
+
+/// Versions the server accepts, answering each request in the version and
+/// layout it came in.
+pub const SUPPORTED_VERSIONS: RangeInclusive<u8> = 1..=PROTOCOL_VERSION;
+
+/// Prefixes the binary `message` with the protocol version and format, as
+/// every message is sent.
+pub fn versioned(message: &[u8]) -> Vec<u8> {
+    let mut writer = Writer::versioned(PROTOCOL_VERSION, Format::Binary);
+    writer.put_bytes(message);
+    writer.into_bytes()
 }
 
-impl From<u8> for Day {
-    fn from(item: u8) -> Self {
-        match item {
-            0 => Day::Monday,
-            1 => Day::Tuesday,
-            2 => Day::Wednesday,
-            3 => Day::Thursday,
-            4 => Day::Friday,
-            _ => panic!("Invalid day of the week value: {}", item),
-        }
-    }
+/// The status leading a reply, and a reader over the payload after it in the
+/// version and format the reply came in.
+pub fn reply_status(reply: &[u8]) -> Result<(ReplyStatus, Reader<'_>), DecodeError> {
+    let mut reader = Reader::versioned(reply)?;
+    Ok((reader.get()?, reader))
+}
+
+impl Day {
+    pub const ALL: [Day; 5] = [Day::Monday, Day::Tuesday, Day::Wednesday, Day::Thursday, Day::Friday];
 }
 
 impl From<&str> for Day {
@@ -36,355 +43,167 @@ impl From<&str> for Day {
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Booking {
-    pub facility_name: String,
-    pub day: Day,
-    pub start_slot: u8,
-    pub num_slots: u8,
-    pub user_id: u8,
-}
-
-impl Booking {
-    pub fn serialize(&self, output_stream: &mut Vec<u8>) {
-        let mut facility_name: Vec<u8> = self.facility_name.as_bytes().to_vec();
-        facility_name.push(0);
-        output_stream.append(&mut facility_name);
-        output_stream.push(self.day as u8);
-        output_stream.push(self.start_slot);
-        output_stream.push(self.num_slots);
-        output_stream.push(self.user_id);
-    }
-    pub fn deserialize(input_stream: &[u8], pos: &mut usize) -> Self {
-        let facility_name = read_string(input_stream, pos);
-        let day: Day = Day::from(input_stream[*pos]);
-        *pos = *pos + 1;
-        let start_slot: u8 = input_stream[*pos];
-        *pos = *pos + 1;
-        let num_slots: u8 = input_stream[*pos];
-        *pos = *pos + 1;
-        let user_id: u8 = input_stream[*pos];
-        *pos = *pos + 1;
-        
-        Self { facility_name: facility_name, day: day, start_slot: start_slot, num_slots: num_slots, user_id: user_id }
-    }
-}
-
-
-#[derive(Debug)]
-pub struct BookingResponse {
-    pub success: bool,
-    pub message: String,
-    pub confirmation_id: u8
-}
-impl BookingResponse {
-    pub fn serialize(&self, output_stream: &mut Vec<u8>) {
-        output_stream.push(self.success as u8);
-        let mut message_bytes: Vec<u8> = self.message.as_bytes().to_vec();
-        message_bytes.push(0);
-        output_stream.append(&mut message_bytes);
-        output_stream.push(self.confirmation_id);
-    }
-    pub fn deserialize(input_stream: &[u8], pos: &mut usize) -> Self {
-        let success: bool = input_stream[*pos] != 0;
-        *pos += 1;
-        let message: String = read_string(input_stream, pos);
-        let confirmation_id: u8 = input_stream[*pos];
-        *pos += 1;
-        Self { success: success, message: message, confirmation_id: confirmation_id }
+impl fmt::Display for Booking {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} on {:?} {}-{} (booked by {})", self.facility_name, self.day,
+            slot_time(self.start_slot), slot_time(self.start_slot.saturating_add(self.num_slots)), self.user_id)
     }
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Update {
-    pub confirmation_id: u8,
-    pub offset: i8
-}
-
-impl Update {
-    pub fn serialize(&self, output_stream: &mut Vec<u8>) {
-        output_stream.push(self.confirmation_id);
-        output_stream.push(self.offset as u8);
-    }
-
-    pub fn deserialize(input_stream: &[u8], pos: &mut usize) -> Self {
-        let confirmation_id: u8 = input_stream[*pos];
-        *pos += 1;
-        let offset: i8 = input_stream[*pos] as i8;
-        *pos += 1;
-        Self { confirmation_id: confirmation_id, offset: offset }
-    }
+/// Wall-clock time at which a half-hour slot starts, slot 0 being 08:00.
+pub fn slot_time(slot: u8) -> String {
+    let hour = 8 + slot / 2;
+    let minute = if slot.is_multiple_of(2) { "00" } else { "30" };
+    format!("{:02}:{}", hour, minute)
 }
 
-#[derive(Debug)]
-pub struct UpdateResponse {
-    pub success: bool,
-    pub message: String
-}
+impl FacilityStats {
+    pub const SLOTS_PER_DAY: u8 = FacilityRecord::SLOTS_PER_DAY;
+    /// Slots in one hour of the day across the whole week: two a day.
+    pub const SLOTS_PER_HOUR_PER_WEEK: u8 = 2 * Day::ALL.len() as u8;
 
-impl UpdateResponse {
-    pub fn serialize(&self, output_stream: &mut Vec<u8>) {
-        output_stream.push(self.success as u8);
-        let mut message_bytes: Vec<u8> = self.message.as_bytes().to_vec();
-        message_bytes.push(0);
-        output_stream.append(&mut message_bytes);
-    }
-    pub fn deserialize(input_stream: &[u8], pos: &mut usize) -> Self {
-        let success: bool = input_stream[*pos] != 0;
-        *pos += 1;
-        let message: String = read_string(input_stream, pos);
-        Self { success: success, message: message }
+    pub fn from_record(name: &str, record: &FacilityRecord) -> Self {
+        let mut booked_by_day: [u8; 5] = [0; 5];
+        let mut booked_by_hour: [u8; 8] = [0; 8];
+        for day in Day::ALL {
+            for (i, &slot) in record.day_slots(day).iter().enumerate() {
+                if slot != 0 {
+                    booked_by_day[day as usize] += 1;
+                    booked_by_hour[i / 2] += 1;
+                }
+            }
+        }
+        Self { name: name.to_string(), booked_by_day, booked_by_hour }
     }
-}
 
-pub struct Monitor {
-    pub duration: u32
-}
-
-impl Monitor {
-    pub fn serialize(&self, output_stream: &mut Vec<u8>) {
-        output_stream.append(self.duration.to_le_bytes().to_vec().as_mut());
+    pub fn booked(&self) -> u32 {
+        self.booked_by_day.iter().map(|&n| n as u32).sum()
     }
 
-    pub fn deserialize(input_stream: &[u8], pos: &mut usize) -> Self {
-        let duration = u32::from_le_bytes(input_stream[*pos..*pos+4].try_into().unwrap());
-        *pos += 4;
-        Self { duration: duration }
+    pub fn total(&self) -> u32 {
+        Self::SLOTS_PER_DAY as u32 * self.booked_by_day.len() as u32
     }
 }
 
-#[derive(Debug, Copy, Clone)]
-pub enum RequestType {
-    QUERY, BOOK, UPDATE, MONITOR
-}
-
-impl From<u8> for RequestType {
-    fn from(item: u8) -> Self {
-        match item {
-            0 => RequestType::QUERY,
-            1 => RequestType::BOOK,
-            2 => RequestType::UPDATE,
-            3 => RequestType::MONITOR,
-            _ => panic!("Invalid day of the week value: {}", item),
-        }
-    }
+/// A request as sent: its type followed by its body.
+pub fn request<T: Encode + Serialize + ?Sized>(request_type: RequestType, body: &T) -> Vec<u8> {
+    let mut writer = Writer::new();
+    writer.put(&request_type).put(body);
+    writer.into_bytes()
 }
 
-#[derive(Debug, Clone)]
-pub struct QueryRequest {
-    pub name: String,
-    pub days: Vec<Day>
-}
-pub fn read_string(input_stream: &[u8], pos: &mut usize) -> String {
-    let mut str_vec: Vec<u8> = Vec::new();
-    while input_stream[*pos] != 0 {
-        str_vec.push(input_stream[*pos]);
-        *pos = *pos + 1;
-    }
-    *pos = *pos + 1;
-    let str_name: String = String::from_utf8(str_vec).unwrap();
-    str_name
-}
-impl QueryRequest {
-    pub fn serialize(&self, output_stream: &mut Vec<u8>) {
-        let mut name_bytes: Vec<u8> = self.name.as_bytes().to_vec();
-        name_bytes.push(0);
-        output_stream.append(&mut name_bytes);
-        for day in &self.days {
-            let u8_day = *day as u8;
-            output_stream.push(u8_day);
-        }
-    }
-
-    pub fn deserialize(input_stream: &[u8], pos: &mut usize) -> Self {
-        let name = read_string(input_stream, pos);
-        let mut days: Vec<Day> = Vec::new();
-        let len = input_stream.len();
-        for i in *pos..len {
-            let day: Day = Day::from(input_stream[i]);
-            days.push(day);
+impl fmt::Display for ReplyStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplyStatus::Ok => write!(f, "ok"),
+            ReplyStatus::WrongShard { owner } => write!(f, "wrong shard, owned by {}", owner),
+            ReplyStatus::NotLeader { leader } => write!(f, "not the leader, {} is", leader),
+            ReplyStatus::UnsupportedVersion { min, max } =>
+                write!(f, "unsupported version {}, the server speaks versions {} to {}", PROTOCOL_VERSION, min, max),
         }
-        *pos = *pos + len;
-        Self { name: name, days: days }
-    }
-}
-
-#[derive(Debug)]
-pub struct QueryResponse {
-    pub name: String,
-    pub availaible: Vec<u8>
-}
-impl QueryResponse {
-    pub fn serialize(&mut self, output_stream: &mut Vec<u8>) {
-        let mut name_bytes: Vec<u8> = self.name.as_bytes().to_vec();
-        name_bytes.push(0);
-        output_stream.append(&mut name_bytes);
-        output_stream.append(&mut self.availaible);
-    }
-    pub fn deserialize(input_stream: &[u8], pos: &mut usize) -> Self {
-        let name = read_string(input_stream, pos);
-        let availaible: Vec<u8> = input_stream[*pos..].to_vec();
-        Self { name: name, availaible: availaible }
     }
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct FacilityRecord {
-    monday: [u8;16],
-    tuesday: [u8;16],
-    wednesday: [u8;16],
-    thursday: [u8;16],
-    friday: [u8;16]
+    days: [Schedule; 5]
 }
 impl FacilityRecord {
-    pub fn new() -> Self {
-        Self { monday: [0;16], tuesday: [0;16], wednesday: [0;16], thursday: [0;16], friday: [0;16] }
-    }
+    pub const SLOTS_PER_DAY: u8 = 16;
 
-    pub fn serialize(&self, output_stream: &mut Vec<u8>) {
-        output_stream.append(self.monday.to_vec().as_mut());
-        output_stream.append(self.tuesday.to_vec().as_mut());
-        output_stream.append(self.wednesday.to_vec().as_mut());
-        output_stream.append(self.thursday.to_vec().as_mut());
-        output_stream.append(self.friday.to_vec().as_mut());
+    pub fn new() -> Self {
+        Self { days: std::array::from_fn(|_| Schedule::new(Self::SLOTS_PER_DAY as u16)) }
     }
 
-    pub fn deserialize(input_stream: &[u8], pos: &mut usize) -> Self {
-        let monday: [u8; 16] = input_stream[*pos..*pos+16].try_into().unwrap();
-        *pos += 16;
-        let tuesday: [u8; 16] = input_stream[*pos..*pos+16].try_into().unwrap();
-        *pos += 16;
-        let wednesday: [u8; 16] = input_stream[*pos..*pos+16].try_into().unwrap();
-        *pos += 16;
-        let thursday: [u8; 16] = input_stream[*pos..*pos+16].try_into().unwrap();
-        *pos += 16;
-        let friday: [u8; 16] = input_stream[*pos..*pos+16].try_into().unwrap();
-        *pos += 16;
-        Self { monday: monday, tuesday: tuesday, wednesday: wednesday, thursday: thursday, friday: friday }
+    pub fn schedule(&self, day: Day) -> &Schedule {
+        &self.days[day as usize]
     }
 
     pub fn is_slot_availaible(&mut self, day: Day, start_slot: u8, num_slot: u8, user_id: u8) -> bool {
-        let record = &mut self[day];
-
-        let start = start_slot as usize;
-        let end = (start_slot + num_slot) as usize;
-
-        // Bounds check to avoid panic
-        if end > record.len() {
-            return false;
-        }
-
-        // Check if all slots in range are free (0)
-        if record[start..end].iter().all(|&x| x == 0) {
-            // Mark them as booked by user_id
-            for slot in &mut record[start..end] {
-                *slot = user_id;
-            }
-            true
-        } else {
-            false
-        }
+        let start = start_slot as u16;
+        let end = start + num_slot as u16;
+        // Mark the range as booked by user_id if it is entirely free
+        self.days[day as usize].insert(start, end, user_id)
     }
 
     pub fn update_booking(&mut self, day: Day, start_slot: u8, num_slot: u8, user_id: u8, offset: i8) -> bool {
-        let record = &mut self[day];
-        let start = start_slot as isize;
-        let end = start + num_slot as isize;
-        let new_start = start + offset as isize;
-        let new_end = end + offset as isize;
-        let len = record.len() as isize;
+        let start = start_slot as i32;
+        let new_start = start + offset as i32;
 
-        //Bounds check
-        if new_start < 0 || new_end > len {
+        //Bounds check, the end is checked by the schedule
+        if new_start < 0 {
             return false;
         }
 
-        //Check if the existing booking matches user_id
-        if record[start as usize..end as usize]
-            .iter()
-            .any(|&slot| slot != user_id)
-        {
-            // The current slots are not owned by this user
-            return false;
-        }
+        //Fails if the booking is not held by user_id or the new range is taken
+        self.days[day as usize].move_booking(start as u16, start as u16 + num_slot as u16, user_id, new_start as u16)
+    }
 
-        //Check if new range overlaps with existing non-zero slots
-        if record[new_start as usize..new_end as usize]
-            .iter()
-            .any(|&slot| slot != 0 && slot != user_id)
-        {
-            return false;
-        }
+    /// Frees a booking made with `is_slot_availaible`.
+    pub fn release(&mut self, day: Day, start_slot: u8, num_slot: u8, user_id: u8) -> bool {
+        let start = start_slot as u16;
+        self.days[day as usize].remove(start, start + num_slot as u16, user_id)
+    }
 
-        //Clear old slots
-        for slot in &mut record[start as usize..end as usize] {
-            *slot = 0;
-        }
+    pub fn day_slots(&self, day: Day) -> [u8;16] {
+        self.days[day as usize].to_slots().try_into().unwrap()
+    }
+}
 
-        //5. Fill new slots
-        for slot in &mut record[new_start as usize..new_end as usize] {
-            *slot = user_id;
-        }
-        true
+impl Default for FacilityRecord {
+    fn default() -> Self {
+        Self::new()
     }
-    
-    fn get_day_slots(&self, day: Day) -> &[u8;16] {
-        match day {
-            Day::Monday => &self.monday,
-            Day::Tuesday => &self.tuesday,
-            Day::Wednesday => &self.wednesday,
-            Day::Thursday => &self.thursday,
-            Day::Friday => &self.friday,
+}
+
+/// One owner byte per slot, day after day.
+impl Encode for FacilityRecord {
+    fn encode(&self, writer: &mut Writer) {
+        for day in Day::ALL {
+            writer.put(&self.day_slots(day));
         }
     }
 }
 
-impl Index<Day> for FacilityRecord {
-    type Output = [u8; 16];
-
-    fn index(&self, day: Day) -> &Self::Output {
-        match day {
-            Day::Monday => &self.monday,
-            Day::Tuesday => &self.tuesday,
-            Day::Wednesday => &self.wednesday,
-            Day::Thursday => &self.thursday,
-            Day::Friday => &self.friday,
-        }
+impl Decode for FacilityRecord {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        let slots_per_day = Self::SLOTS_PER_DAY as usize;
+        let mut days = reader.take(slots_per_day * Day::ALL.len())?.chunks(slots_per_day);
+        Ok(Self { days: std::array::from_fn(|_| Schedule::from_slots(days.next().unwrap())) })
     }
 }
 
-impl IndexMut<Day> for FacilityRecord {
-    fn index_mut(&mut self, day: Day) -> &mut Self::Output {
-        match day {
-            Day::Monday => &mut self.monday,
-            Day::Tuesday => &mut self.tuesday,
-            Day::Wednesday => &mut self.wednesday,
-            Day::Thursday => &mut self.thursday,
-            Day::Friday => &mut self.friday,
-        }
+/// The slots of each day, as a list of 5 lists of 16 owners.
+impl Serialize for FacilityRecord {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        Day::ALL.map(|day| self.day_slots(day)).serialize(serializer)
     }
 }
 
+impl<'de> Deserialize<'de> for FacilityRecord {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let days = <[[u8; 16]; 5]>::deserialize(deserializer)?;
+        Ok(Self { days: days.map(|slots| Schedule::from_slots(&slots)) })
+    }
+}
 
 impl fmt::Display for FacilityRecord {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         writeln!(f, "🏢 Facility Weekly Schedule (8:00 AM - 4:00 PM):")?;
 
-        for day in [Day::Monday, Day::Tuesday, Day::Wednesday, Day::Thursday, Day::Friday] {
-            let slots = self.get_day_slots(day);
+        for day in Day::ALL {
+            let slots = self.day_slots(day);
             writeln!(f, "\n📅 {:?}:", day)?;
             for (i, &slot) in slots.iter().enumerate() {
-                let hour = 8 + i / 2;
-                let minute = if i % 2 == 0 { "00" } else { "30" };
                 let status = if slot == 0 {
                     "Available".to_string()
                 } else {
                     format!("Booked by {}", slot)
                 };
-                writeln!(f, "{:02}:{:<2} - {}", hour, minute, status)?;
+                writeln!(f, "{} - {}", slot_time(i as u8), status)?;
             }
         }
 
         Ok(())
     }
-}
\ No newline at end of file
+}
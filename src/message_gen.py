@@ -0,0 +1,1026 @@
+# Generated by build.rs from src/messages.idl, do not edit.
+"""The messages of the protocol, see src/messages.idl and codec.py."""
+from __future__ import annotations
+
+from dataclasses import dataclass
+from enum import Enum
+from typing import List, Optional, Tuple
+
+from codec import Reader, Writer, none_if
+
+# Protocol version this build speaks. It is the first byte of every request
+# and reply, ahead of the request type or reply status. Version 2 made strings
+# and lists self-delimiting, see codec::SELF_DELIMITING, version 3 added the
+# error codes of responses, version 4 the format byte, see codec::Format, and
+# version 5 the tokens of mutating requests.
+PROTOCOL_VERSION = 5
+
+
+class Message:
+    """Whole messages in the layout of a protocol version, this build's by default."""
+
+    def to_bytes(self, version: int = PROTOCOL_VERSION) -> bytes:
+        writer = Writer(version)
+        self.encode(writer)
+        return writer.into_bytes()
+
+    @classmethod
+    def from_bytes(cls, data: bytes, version: int = PROTOCOL_VERSION):
+        return cls.decode(Reader(data, version))
+
+
+class Day(Message, Enum):
+    MONDAY = 0
+    TUESDAY = 1
+    WEDNESDAY = 2
+    THURSDAY = 3
+    FRIDAY = 4
+
+    def encode(self, writer: Writer) -> None:
+        writer.u8(self.value)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> Day:
+        return cls(reader.u8())
+
+
+class ErrorCode(Message, Enum):
+    """Why a request failed, for clients to act on. Responses carry it next to a
+    message explaining the failure to people, `None` when nothing failed.
+    """
+    NONE = 0
+    FACILITY_NOT_FOUND = 1
+    SLOT_TAKEN = 2
+    # The booking was made by another user and the caller is no admin
+    FORBIDDEN = 3
+    # Slots or offsets past either end of the day
+    OUT_OF_RANGE = 4
+    # A request that can never succeed as sent, e.g. booking no slots
+    INVALID_REQUEST = 5
+    BOOKING_NOT_FOUND = 6
+    # The shard has handed out every confirmation id it has
+    IDS_EXHAUSTED = 7
+    # A server the request depends on did not answer
+    UNAVAILABLE = 8
+    # A mutating request without a valid token of its user, or a failed login
+    UNAUTHENTICATED = 9
+
+    def encode(self, writer: Writer) -> None:
+        writer.u8(self.value)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> ErrorCode:
+        return cls(reader.u8())
+
+
+class RequestType(Message, Enum):
+    QUERY = 0
+    BOOK = 1
+    UPDATE = 2
+    MONITOR = 3
+    GET_BOOKING = 4
+    LIST_BOOKINGS = 5
+    EXPORT = 6
+    STATS = 7
+    SHARD_MAP = 8
+    REPLICATE = 9
+    PROMOTE = 10
+    RAFT = 11
+    MULTI_BOOK = 12
+    PREPARE = 13
+    COMMIT = 14
+    ABORT = 15
+    TXN_STATUS = 16
+    LOGIN = 17
+
+    def encode(self, writer: Writer) -> None:
+        writer.u8(self.value)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> RequestType:
+        return cls(reader.u8())
+
+
+class ReplyStatus(Message):
+    """Leads every reply, right after the protocol version. Anything other than
+    `Ok` replaces the reply payload: the redirects tell the client to resend the
+    request elsewhere, `UnsupportedVersion` gives the versions the server accepts.
+    Each variant is a subclass: ReplyStatus.Ok, ReplyStatus.WrongShard, ReplyStatus.NotLeader, ReplyStatus.UnsupportedVersion.
+    """
+    TAG: int
+    VARIANTS: list
+
+    def encode(self, writer: Writer) -> None:
+        writer.u8(self.TAG)
+        self.encode_fields(writer)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> ReplyStatus:
+        tag = reader.u8()
+        if tag >= len(cls.VARIANTS):
+            raise ValueError(f"Invalid ReplyStatus tag: {tag}")
+        return cls.VARIANTS[tag].decode_fields(reader)
+
+
+@dataclass
+class ReplyStatusOk(ReplyStatus):
+    TAG = 0
+
+    def encode_fields(self, writer: Writer) -> None:
+        pass
+
+    @classmethod
+    def decode_fields(cls, reader: Reader) -> ReplyStatusOk:
+        return cls()
+
+
+@dataclass
+class ReplyStatusWrongShard(ReplyStatus):
+    TAG = 1
+    owner: str
+
+    def encode_fields(self, writer: Writer) -> None:
+        writer.string(self.owner)
+
+    @classmethod
+    def decode_fields(cls, reader: Reader) -> ReplyStatusWrongShard:
+        owner = reader.string()
+        return cls(owner)
+
+
+@dataclass
+class ReplyStatusNotLeader(ReplyStatus):
+    TAG = 2
+    leader: str
+
+    def encode_fields(self, writer: Writer) -> None:
+        writer.string(self.leader)
+
+    @classmethod
+    def decode_fields(cls, reader: Reader) -> ReplyStatusNotLeader:
+        leader = reader.string()
+        return cls(leader)
+
+
+@dataclass
+class ReplyStatusUnsupportedVersion(ReplyStatus):
+    TAG = 3
+    min: int
+    max: int
+
+    def encode_fields(self, writer: Writer) -> None:
+        writer.u8(self.min)
+        writer.u8(self.max)
+
+    @classmethod
+    def decode_fields(cls, reader: Reader) -> ReplyStatusUnsupportedVersion:
+        min = reader.u8()
+        max = reader.u8()
+        return cls(min, max)
+
+
+ReplyStatus.Ok = ReplyStatusOk
+ReplyStatus.WrongShard = ReplyStatusWrongShard
+ReplyStatus.NotLeader = ReplyStatusNotLeader
+ReplyStatus.UnsupportedVersion = ReplyStatusUnsupportedVersion
+ReplyStatus.VARIANTS = [ReplyStatusOk, ReplyStatusWrongShard, ReplyStatusNotLeader, ReplyStatusUnsupportedVersion]
+
+
+@dataclass
+class QueryRequest(Message):
+    name: str
+    days: List[Day]
+
+    def encode(self, writer: Writer) -> None:
+        writer.string(self.name)
+        if writer.self_delimiting():
+            writer.count(len(self.days))
+            for item0 in self.days:
+                item0.encode(writer)
+        else:
+            for item0 in self.days:
+                item0.encode(writer)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> QueryRequest:
+        name = reader.string()
+        days = [Day.decode(reader) for _ in range(reader.count())] if reader.self_delimiting() else reader.until_end(lambda: Day.decode(reader))
+        return cls(name, days)
+
+
+@dataclass
+class QueryResponse(Message):
+    """`availaible` holds the slots of the requested days, in order."""
+    name: str
+    error: ErrorCode
+    availaible: bytes
+
+    def encode(self, writer: Writer) -> None:
+        writer.string(self.name)
+        if writer.version >= 3:
+            self.error.encode(writer)
+        if writer.self_delimiting():
+            writer.count(len(self.availaible))
+            writer.raw(self.availaible)
+        else:
+            writer.raw(self.availaible)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> QueryResponse:
+        name = reader.string()
+        error = ErrorCode.decode(reader) if reader.version >= 3 else ErrorCode(0)
+        availaible = reader.take(reader.count()) if reader.self_delimiting() else reader.rest()
+        return cls(name, error, availaible)
+
+
+@dataclass
+class Booking(Message):
+    facility_name: str
+    day: Day
+    start_slot: int
+    num_slots: int
+    user_id: int
+
+    def encode(self, writer: Writer) -> None:
+        writer.string(self.facility_name)
+        self.day.encode(writer)
+        writer.u8(self.start_slot)
+        writer.u8(self.num_slots)
+        writer.u8(self.user_id)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> Booking:
+        facility_name = reader.string()
+        day = Day.decode(reader)
+        start_slot = reader.u8()
+        num_slots = reader.u8()
+        user_id = reader.u8()
+        return cls(facility_name, day, start_slot, num_slots, user_id)
+
+
+@dataclass
+class Token(Message):
+    """Proof of a login, valid on every server of the deployment until `expires`
+    (seconds since the Unix epoch). `mac` signs the other fields, see auth::Users.
+    """
+    user_id: int
+    expires: int
+    mac: bytes
+
+    def encode(self, writer: Writer) -> None:
+        writer.u8(self.user_id)
+        writer.u32(self.expires)
+        writer.raw(self.mac)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> Token:
+        user_id = reader.u8()
+        expires = reader.u32()
+        mac = reader.take(32)
+        return cls(user_id, expires, mac)
+
+
+@dataclass
+class Login(Message):
+    """Asks for a token of `user_id`."""
+    user_id: int
+    password: str
+
+    def encode(self, writer: Writer) -> None:
+        writer.u8(self.user_id)
+        writer.string(self.password)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> Login:
+        user_id = reader.u8()
+        password = reader.string()
+        return cls(user_id, password)
+
+
+@dataclass
+class LoginResponse(Message):
+    success: bool
+    error: ErrorCode
+    message: str
+    token: Optional[Token]
+
+    def encode(self, writer: Writer) -> None:
+        writer.bool(self.success)
+        self.error.encode(writer)
+        writer.string(self.message)
+        if self.token is not None:
+            self.token.encode(writer)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> LoginResponse:
+        success = reader.bool()
+        error = ErrorCode.decode(reader)
+        message = reader.string()
+        token = Token.decode(reader) if success else None
+        return cls(success, error, message, token)
+
+
+@dataclass
+class BookRequest(Message):
+    """The body of a BOOK request. Servers with credentials only book for the
+    user of the token, older versions have none and are refused by them.
+    """
+    auth: Optional[Token]
+    booking: Booking
+
+    def encode(self, writer: Writer) -> None:
+        if writer.version >= 5:
+            writer.bool(self.auth is not None)
+            if self.auth is not None:
+                self.auth.encode(writer)
+        self.booking.encode(writer)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> BookRequest:
+        auth = ((Token.decode(reader) if reader.bool() else None)) if reader.version >= 5 else None
+        booking = Booking.decode(reader)
+        return cls(auth, booking)
+
+
+@dataclass
+class BookingResponse(Message):
+    success: bool
+    error: ErrorCode
+    message: str
+    confirmation_id: int
+
+    def encode(self, writer: Writer) -> None:
+        writer.bool(self.success)
+        if writer.version >= 3:
+            self.error.encode(writer)
+        writer.string(self.message)
+        writer.u8(self.confirmation_id)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> BookingResponse:
+        success = reader.bool()
+        error = ErrorCode.decode(reader) if reader.version >= 3 else ErrorCode(0)
+        message = reader.string()
+        confirmation_id = reader.u8()
+        return cls(success, error, message, confirmation_id)
+
+
+@dataclass
+class Update(Message):
+    auth: Optional[Token]
+    confirmation_id: int
+    offset: int
+
+    def encode(self, writer: Writer) -> None:
+        if writer.version >= 5:
+            writer.bool(self.auth is not None)
+            if self.auth is not None:
+                self.auth.encode(writer)
+        writer.u8(self.confirmation_id)
+        writer.i8(self.offset)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> Update:
+        auth = ((Token.decode(reader) if reader.bool() else None)) if reader.version >= 5 else None
+        confirmation_id = reader.u8()
+        offset = reader.i8()
+        return cls(auth, confirmation_id, offset)
+
+
+@dataclass
+class UpdateResponse(Message):
+    success: bool
+    error: ErrorCode
+    message: str
+
+    def encode(self, writer: Writer) -> None:
+        writer.bool(self.success)
+        if writer.version >= 3:
+            self.error.encode(writer)
+        writer.string(self.message)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> UpdateResponse:
+        success = reader.bool()
+        error = ErrorCode.decode(reader) if reader.version >= 3 else ErrorCode(0)
+        message = reader.string()
+        return cls(success, error, message)
+
+
+@dataclass
+class Monitor(Message):
+    duration: int
+
+    def encode(self, writer: Writer) -> None:
+        writer.u32(self.duration)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> Monitor:
+        duration = reader.u32()
+        return cls(duration)
+
+
+@dataclass
+class GetBooking(Message):
+    confirmation_id: int
+
+    def encode(self, writer: Writer) -> None:
+        writer.u8(self.confirmation_id)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> GetBooking:
+        confirmation_id = reader.u8()
+        return cls(confirmation_id)
+
+
+@dataclass
+class GetBookingResponse(Message):
+    success: bool
+    error: ErrorCode
+    message: str
+    booking: Optional[Booking]
+
+    def encode(self, writer: Writer) -> None:
+        writer.bool(self.success)
+        if writer.version >= 3:
+            self.error.encode(writer)
+        writer.string(self.message)
+        if self.booking is not None:
+            self.booking.encode(writer)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> GetBookingResponse:
+        success = reader.bool()
+        error = ErrorCode.decode(reader) if reader.version >= 3 else ErrorCode(0)
+        message = reader.string()
+        booking = Booking.decode(reader) if success else None
+        return cls(success, error, message, booking)
+
+
+@dataclass
+class ListBookings(Message):
+    user_id: int
+
+    def encode(self, writer: Writer) -> None:
+        writer.u8(self.user_id)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> ListBookings:
+        user_id = reader.u8()
+        return cls(user_id)
+
+
+@dataclass
+class ListBookingsResponse(Message):
+    bookings: List[Tuple[int, Booking]]
+
+    def encode(self, writer: Writer) -> None:
+        writer.count(len(self.bookings))
+        for item0 in self.bookings:
+            writer.u8(item0[0])
+            item0[1].encode(writer)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> ListBookingsResponse:
+        bookings = [(reader.u8(), Booking.decode(reader)) for _ in range(reader.count())]
+        return cls(bookings)
+
+
+class Export(Message):
+    """What an EXPORT request renders: a facility's schedule or a user's bookings.
+    Each variant is a subclass: Export.Facility, Export.User.
+    """
+    TAG: int
+    VARIANTS: list
+
+    def encode(self, writer: Writer) -> None:
+        writer.u8(self.TAG)
+        self.encode_fields(writer)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> Export:
+        tag = reader.u8()
+        if tag >= len(cls.VARIANTS):
+            raise ValueError(f"Invalid Export tag: {tag}")
+        return cls.VARIANTS[tag].decode_fields(reader)
+
+
+@dataclass
+class ExportFacility(Export):
+    TAG = 0
+    field0: str
+
+    def encode_fields(self, writer: Writer) -> None:
+        writer.string(self.field0)
+
+    @classmethod
+    def decode_fields(cls, reader: Reader) -> ExportFacility:
+        field0 = reader.string()
+        return cls(field0)
+
+
+@dataclass
+class ExportUser(Export):
+    TAG = 1
+    field0: int
+
+    def encode_fields(self, writer: Writer) -> None:
+        writer.u8(self.field0)
+
+    @classmethod
+    def decode_fields(cls, reader: Reader) -> ExportUser:
+        field0 = reader.u8()
+        return cls(field0)
+
+
+Export.Facility = ExportFacility
+Export.User = ExportUser
+Export.VARIANTS = [ExportFacility, ExportUser]
+
+
+@dataclass
+class ExportResponse(Message):
+    success: bool
+    error: ErrorCode
+    message: str
+    calendar: str
+
+    def encode(self, writer: Writer) -> None:
+        writer.bool(self.success)
+        if writer.version >= 3:
+            self.error.encode(writer)
+        writer.string(self.message)
+        writer.string(self.calendar)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> ExportResponse:
+        success = reader.bool()
+        error = ErrorCode.decode(reader) if reader.version >= 3 else ErrorCode(0)
+        message = reader.string()
+        calendar = reader.string()
+        return cls(success, error, message, calendar)
+
+
+@dataclass
+class FacilityStats(Message):
+    """Booked slot counts for one facility. Each day has 16 slots and each hour
+    covers 2 slots on each of the 5 days.
+    """
+    name: str
+    booked_by_day: bytes
+    booked_by_hour: bytes
+
+    def encode(self, writer: Writer) -> None:
+        writer.string(self.name)
+        writer.raw(self.booked_by_day)
+        writer.raw(self.booked_by_hour)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> FacilityStats:
+        name = reader.string()
+        booked_by_day = reader.take(5)
+        booked_by_hour = reader.take(8)
+        return cls(name, booked_by_day, booked_by_hour)
+
+
+@dataclass
+class StatsResponse(Message):
+    facilities: List[FacilityStats]
+    # (user_id, number of bookings on this shard), saturating at 255. A shard
+    # hands out at most 255 confirmation ids so it never does; clients adding
+    # up several shards sum the counts in a wider type.
+    users: List[Tuple[int, int]]
+
+    def encode(self, writer: Writer) -> None:
+        writer.count(len(self.facilities))
+        for item0 in self.facilities:
+            item0.encode(writer)
+        writer.count(len(self.users))
+        for item0 in self.users:
+            writer.u8(item0[0])
+            writer.u8(item0[1])
+
+    @classmethod
+    def decode(cls, reader: Reader) -> StatsResponse:
+        facilities = [FacilityStats.decode(reader) for _ in range(reader.count())]
+        users = [(reader.u8(), reader.u8()) for _ in range(reader.count())]
+        return cls(facilities, users)
+
+
+class Mutation(Message):
+    """A change already applied by a primary. Each one states its outcome rather
+    than the request that caused it, so replaying it twice is harmless.
+    Each variant is a subclass: Mutation.Snapshot, Mutation.Book, Mutation.Move, Mutation.Hold, Mutation.Finish.
+    """
+    TAG: int
+    VARIANTS: list
+
+    def encode(self, writer: Writer) -> None:
+        writer.u8(self.TAG)
+        self.encode_fields(writer)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> Mutation:
+        tag = reader.u8()
+        if tag >= len(cls.VARIANTS):
+            raise ValueError(f"Invalid Mutation tag: {tag}")
+        return cls.VARIANTS[tag].decode_fields(reader)
+
+
+@dataclass
+class MutationSnapshot(Mutation):
+    """The whole booking state, sent when a backup (re)connects"""
+    TAG = 0
+    next_id: Optional[int]
+    bookings: List[Tuple[int, Booking]]
+
+    def encode_fields(self, writer: Writer) -> None:
+        writer.u8(0 if self.next_id is None else self.next_id)
+        writer.count(len(self.bookings))
+        for item0 in self.bookings:
+            writer.u8(item0[0])
+            item0[1].encode(writer)
+
+    @classmethod
+    def decode_fields(cls, reader: Reader) -> MutationSnapshot:
+        next_id = none_if(reader.u8(), 0)
+        bookings = [(reader.u8(), Booking.decode(reader)) for _ in range(reader.count())]
+        return cls(next_id, bookings)
+
+
+@dataclass
+class MutationBook(Mutation):
+    TAG = 1
+    confirmation_id: int
+    booking: Booking
+
+    def encode_fields(self, writer: Writer) -> None:
+        writer.u8(self.confirmation_id)
+        self.booking.encode(writer)
+
+    @classmethod
+    def decode_fields(cls, reader: Reader) -> MutationBook:
+        confirmation_id = reader.u8()
+        booking = Booking.decode(reader)
+        return cls(confirmation_id, booking)
+
+
+@dataclass
+class MutationMove(Mutation):
+    TAG = 2
+    confirmation_id: int
+    start_slot: int
+
+    def encode_fields(self, writer: Writer) -> None:
+        writer.u8(self.confirmation_id)
+        writer.u8(self.start_slot)
+
+    @classmethod
+    def decode_fields(cls, reader: Reader) -> MutationMove:
+        confirmation_id = reader.u8()
+        start_slot = reader.u8()
+        return cls(confirmation_id, start_slot)
+
+
+@dataclass
+class MutationHold(Mutation):
+    """Slots held by a prepared transaction, with the confirmation ids reserved for them"""
+    TAG = 3
+    txn: TxnId
+    bookings: List[Tuple[int, Booking]]
+
+    def encode_fields(self, writer: Writer) -> None:
+        self.txn.encode(writer)
+        writer.count(len(self.bookings))
+        for item0 in self.bookings:
+            writer.u8(item0[0])
+            item0[1].encode(writer)
+
+    @classmethod
+    def decode_fields(cls, reader: Reader) -> MutationHold:
+        txn = TxnId.decode(reader)
+        bookings = [(reader.u8(), Booking.decode(reader)) for _ in range(reader.count())]
+        return cls(txn, bookings)
+
+
+@dataclass
+class MutationFinish(Mutation):
+    """The outcome of a held transaction, which turns its holds into bookings or releases them"""
+    TAG = 4
+    txn: TxnId
+    outcome: Outcome
+
+    def encode_fields(self, writer: Writer) -> None:
+        self.txn.encode(writer)
+        self.outcome.encode(writer)
+
+    @classmethod
+    def decode_fields(cls, reader: Reader) -> MutationFinish:
+        txn = TxnId.decode(reader)
+        outcome = Outcome.decode(reader)
+        return cls(txn, outcome)
+
+
+Mutation.Snapshot = MutationSnapshot
+Mutation.Book = MutationBook
+Mutation.Move = MutationMove
+Mutation.Hold = MutationHold
+Mutation.Finish = MutationFinish
+Mutation.VARIANTS = [MutationSnapshot, MutationBook, MutationMove, MutationHold, MutationFinish]
+
+
+@dataclass
+class Replicate(Message):
+    """Sent by a primary to its backups, naming itself so backups can redirect clients."""
+    primary: str
+    mutation: Mutation
+
+    def encode(self, writer: Writer) -> None:
+        writer.string(self.primary)
+        self.mutation.encode(writer)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> Replicate:
+        primary = reader.string()
+        mutation = Mutation.decode(reader)
+        return cls(primary, mutation)
+
+
+@dataclass
+class Promote(Message):
+    """Turns a backup into the primary, replicating to `backups` from then on."""
+    backups: List[str]
+
+    def encode(self, writer: Writer) -> None:
+        writer.count(len(self.backups))
+        for item0 in self.backups:
+            writer.string(item0)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> Promote:
+        backups = [reader.string() for _ in range(reader.count())]
+        return cls(backups)
+
+
+@dataclass
+class MultiBook(Message):
+    """Books several facilities, possibly on different shards, all or nothing."""
+    auth: Optional[Token]
+    bookings: List[Booking]
+
+    def encode(self, writer: Writer) -> None:
+        if writer.version >= 5:
+            writer.bool(self.auth is not None)
+            if self.auth is not None:
+                self.auth.encode(writer)
+        writer.count(len(self.bookings))
+        for item0 in self.bookings:
+            item0.encode(writer)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> MultiBook:
+        auth = ((Token.decode(reader) if reader.bool() else None)) if reader.version >= 5 else None
+        bookings = [Booking.decode(reader) for _ in range(reader.count())]
+        return cls(auth, bookings)
+
+
+@dataclass
+class MultiBookResponse(Message):
+    """`confirmation_ids` follow the order of the requested bookings."""
+    success: bool
+    error: ErrorCode
+    message: str
+    confirmation_ids: bytes
+
+    def encode(self, writer: Writer) -> None:
+        writer.bool(self.success)
+        if writer.version >= 3:
+            self.error.encode(writer)
+        writer.string(self.message)
+        writer.count(len(self.confirmation_ids))
+        writer.raw(self.confirmation_ids)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> MultiBookResponse:
+        success = reader.bool()
+        error = ErrorCode.decode(reader) if reader.version >= 3 else ErrorCode(0)
+        message = reader.string()
+        confirmation_ids = reader.take(reader.count())
+        return cls(success, error, message, confirmation_ids)
+
+
+@dataclass
+class TxnId(Message):
+    """A distributed transaction, named by its coordinator and a number the coordinator never reuses."""
+    coordinator: str
+    id: int
+
+    def encode(self, writer: Writer) -> None:
+        writer.string(self.coordinator)
+        writer.u32(self.id)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> TxnId:
+        coordinator = reader.string()
+        id = reader.u32()
+        return cls(coordinator, id)
+
+
+@dataclass
+class Prepare(Message):
+    """Asks a participant to hold the slots of its share of a transaction."""
+    txn: TxnId
+    bookings: List[Booking]
+
+    def encode(self, writer: Writer) -> None:
+        self.txn.encode(writer)
+        writer.count(len(self.bookings))
+        for item0 in self.bookings:
+            item0.encode(writer)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> Prepare:
+        txn = TxnId.decode(reader)
+        bookings = [Booking.decode(reader) for _ in range(reader.count())]
+        return cls(txn, bookings)
+
+
+@dataclass
+class PrepareResponse(Message):
+    """A participant's vote. The confirmation ids are reserved for the bookings
+    and become valid if the transaction commits. Laid out like a MultiBookResponse.
+    """
+    vote: bool
+    error: ErrorCode
+    message: str
+    confirmation_ids: bytes
+
+    def encode(self, writer: Writer) -> None:
+        writer.bool(self.vote)
+        if writer.version >= 3:
+            self.error.encode(writer)
+        writer.string(self.message)
+        writer.count(len(self.confirmation_ids))
+        writer.raw(self.confirmation_ids)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> PrepareResponse:
+        vote = reader.bool()
+        error = ErrorCode.decode(reader) if reader.version >= 3 else ErrorCode(0)
+        message = reader.string()
+        confirmation_ids = reader.take(reader.count())
+        return cls(vote, error, message, confirmation_ids)
+
+
+class Outcome(Message, Enum):
+    PENDING = 0
+    COMMITTED = 1
+    ABORTED = 2
+
+    def encode(self, writer: Writer) -> None:
+        writer.u8(self.value)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> Outcome:
+        return cls(reader.u8())
+
+
+@dataclass
+class LogEntry(Message):
+    """One entry of the Raft log. `command` is the BOOK, UPDATE, PREPARE, COMMIT
+    or ABORT request as the leader received it, an empty command is the no-op a
+    new leader appends.
+    """
+    term: int
+    # Commands outgrow a u8 count
+    command: bytes
+
+    def encode(self, writer: Writer) -> None:
+        writer.u32(self.term)
+        writer.count(len(self.command), "u32")
+        writer.raw(self.command)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> LogEntry:
+        term = reader.u32()
+        command = reader.take(reader.count("u32"))
+        return cls(term, command)
+
+
+class RaftMessage(Message):
+    """Messages exchanged between the nodes of a Raft cluster, see raft::Raft.
+    Each variant is a subclass: RaftMessage.RequestVote, RaftMessage.Vote, RaftMessage.AppendEntries, RaftMessage.Appended.
+    """
+    TAG: int
+    VARIANTS: list
+
+    def encode(self, writer: Writer) -> None:
+        writer.u8(self.TAG)
+        self.encode_fields(writer)
+
+    @classmethod
+    def decode(cls, reader: Reader) -> RaftMessage:
+        tag = reader.u8()
+        if tag >= len(cls.VARIANTS):
+            raise ValueError(f"Invalid RaftMessage tag: {tag}")
+        return cls.VARIANTS[tag].decode_fields(reader)
+
+
+@dataclass
+class RaftMessageRequestVote(RaftMessage):
+    TAG = 0
+    term: int
+    candidate: int
+    last_log_index: int
+    last_log_term: int
+
+    def encode_fields(self, writer: Writer) -> None:
+        writer.u32(self.term)
+        writer.u8(self.candidate)
+        writer.u32(self.last_log_index)
+        writer.u32(self.last_log_term)
+
+    @classmethod
+    def decode_fields(cls, reader: Reader) -> RaftMessageRequestVote:
+        term = reader.u32()
+        candidate = reader.u8()
+        last_log_index = reader.u32()
+        last_log_term = reader.u32()
+        return cls(term, candidate, last_log_index, last_log_term)
+
+
+@dataclass
+class RaftMessageVote(RaftMessage):
+    TAG = 1
+    term: int
+    granted: bool
+
+    def encode_fields(self, writer: Writer) -> None:
+        writer.u32(self.term)
+        writer.bool(self.granted)
+
+    @classmethod
+    def decode_fields(cls, reader: Reader) -> RaftMessageVote:
+        term = reader.u32()
+        granted = reader.bool()
+        return cls(term, granted)
+
+
+@dataclass
+class RaftMessageAppendEntries(RaftMessage):
+    TAG = 2
+    term: int
+    leader: int
+    prev_log_index: int
+    prev_log_term: int
+    leader_commit: int
+    # A batch can hold more than 255 entries
+    entries: List[LogEntry]
+
+    def encode_fields(self, writer: Writer) -> None:
+        writer.u32(self.term)
+        writer.u8(self.leader)
+        writer.u32(self.prev_log_index)
+        writer.u32(self.prev_log_term)
+        writer.u32(self.leader_commit)
+        writer.count(len(self.entries), "u32")
+        for item0 in self.entries:
+            item0.encode(writer)
+
+    @classmethod
+    def decode_fields(cls, reader: Reader) -> RaftMessageAppendEntries:
+        term = reader.u32()
+        leader = reader.u8()
+        prev_log_index = reader.u32()
+        prev_log_term = reader.u32()
+        leader_commit = reader.u32()
+        entries = [LogEntry.decode(reader) for _ in range(reader.count("u32"))]
+        return cls(term, leader, prev_log_index, prev_log_term, leader_commit, entries)
+
+
+@dataclass
+class RaftMessageAppended(RaftMessage):
+    """On failure `match_index` is the follower's last index, as a hint where to resume"""
+    TAG = 3
+    term: int
+    success: bool
+    match_index: int
+
+    def encode_fields(self, writer: Writer) -> None:
+        writer.u32(self.term)
+        writer.bool(self.success)
+        writer.u32(self.match_index)
+
+    @classmethod
+    def decode_fields(cls, reader: Reader) -> RaftMessageAppended:
+        term = reader.u32()
+        success = reader.bool()
+        match_index = reader.u32()
+        return cls(term, success, match_index)
+
+
+RaftMessage.RequestVote = RaftMessageRequestVote
+RaftMessage.Vote = RaftMessageVote
+RaftMessage.AppendEntries = RaftMessageAppendEntries
+RaftMessage.Appended = RaftMessageAppended
+RaftMessage.VARIANTS = [RaftMessageRequestVote, RaftMessageVote, RaftMessageAppendEntries, RaftMessageAppended]
@@ -0,0 +1,615 @@
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::net::TcpStream;
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use crate::auth::{sign_request, PeerKey};
+use crate::codec::{DecodeError, Format, Reader, Writer};
+use crate::message::{reply_status, LogEntry, RaftMessage, ReplyStatus, RequestType, PROTOCOL_VERSION};
+use crate::tcp::{self, read_frame, write_frame};
+
+/// How often a leader contacts an idle follower.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(50);
+/// A follower that hears nothing from a leader for this long, plus up to as
+/// much again at random, starts an election.
+const ELECTION_TIMEOUT: Duration = Duration::from_millis(300);
+/// How long a node waits on a peer before giving up on the call.
+const RPC_TIMEOUT: Duration = Duration::from_millis(200);
+/// How long a client request waits for its entry to be applied.
+const COMMIT_TIMEOUT: Duration = Duration::from_secs(2);
+/// Most entries sent in one AppendEntries.
+const MAX_BATCH: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Follower, Candidate, Leader
+}
+
+struct Node {
+    state: State,
+    current_term: u32,
+    voted_for: Option<u8>,
+    /// `log[i]` is the entry at index `i + 1`, index 0 stands for the empty log
+    log: Vec<LogEntry>,
+    commit_index: u32,
+    last_applied: u32,
+    leader: Option<usize>,
+    votes: usize,
+    election_deadline: Instant,
+    next_index: Vec<u32>,
+    match_index: Vec<u32>,
+    /// Replies of entries proposed on this node, filled in once they are applied
+    results: HashMap<u32, Option<Vec<u8>>>,
+    storage: Option<File>,
+    rng: u64,
+}
+
+impl Node {
+    fn last_index(&self) -> u32 {
+        self.log.len() as u32
+    }
+
+    fn term_at(&self, index: u32) -> u32 {
+        if index == 0 { 0 } else { self.log[index as usize - 1].term }
+    }
+
+    fn reset_election_deadline(&mut self) {
+        self.rng = self.rng.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+        let jitter = (self.rng >> 33) % ELECTION_TIMEOUT.as_millis() as u64;
+        self.election_deadline = Instant::now() + ELECTION_TIMEOUT + Duration::from_millis(jitter);
+    }
+
+    /// Records the term and vote, which must survive a restart before any reply relies on them.
+    fn persist_vote(&mut self) {
+        let mut record = Writer::new();
+        record.put(&0u8).put(&self.current_term).put(&self.voted_for.unwrap_or(u8::MAX));
+        self.persist(&record.into_bytes());
+    }
+
+    fn persist_entry(&mut self, index: u32) {
+        let mut record = Writer::new();
+        record.put(&1u8).put(&index).put(&self.log[index as usize - 1]);
+        self.persist(&record.into_bytes());
+    }
+
+    fn persist(&mut self, record: &[u8]) {
+        if let Some(storage) = &mut self.storage {
+            storage.write_all(record).and_then(|_| storage.sync_data()).expect("cannot write the raft state");
+        }
+    }
+
+    fn step_down(&mut self, term: u32) {
+        if term > self.current_term {
+            self.current_term = term;
+            self.voted_for = None;
+            self.leader = None;
+            self.persist_vote();
+        }
+        if self.state != State::Follower {
+            self.state = State::Follower;
+            self.reset_election_deadline();
+        }
+    }
+}
+
+/// Replays a state file made of `0 term vote` records and `1 index entry`
+/// records, where an entry at `index` replaces the log from there on.
+fn load(path: &str) -> io::Result<(u32, Option<u8>, Vec<LogEntry>)> {
+    let bytes: Vec<u8> = match fs::read(path) {
+        Ok(bytes) => bytes,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    replay(&mut Reader::new(&bytes))
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: corrupt raft state: {}", path, e)))
+}
+
+fn replay(reader: &mut Reader) -> Result<(u32, Option<u8>, Vec<LogEntry>), DecodeError> {
+    let (mut term, mut voted_for, mut log) = (0, None, Vec::new());
+    while reader.remaining() > 0 {
+        let kind: u8 = reader.get()?;
+        match kind {
+            0 => {
+                term = reader.get()?;
+                let vote: u8 = reader.get()?;
+                voted_for = if vote == u8::MAX { None } else { Some(vote) };
+            }
+            1 => {
+                let index: u32 = reader.get()?;
+                log.truncate((index as usize).saturating_sub(1));
+                log.push(reader.get()?);
+            }
+            tag => return Err(DecodeError::InvalidTag { type_name: "raft state record", tag }),
+        }
+    }
+    Ok((term, voted_for, log))
+}
+
+/// Raft consensus over the BOOK and UPDATE requests of one shard. Every node
+/// applies committed entries in log order through `next_committed`/`applied`,
+/// only the leader accepts new requests.
+pub struct Raft {
+    pub id: usize,
+    pub peers: Vec<String>,
+    /// Signs the RAFT requests sent to peers when the deployment has credentials
+    key: Option<PeerKey>,
+    node: Mutex<Node>,
+    /// Signalled whenever the log, the commit index, the applied index or the state moves
+    changed: Condvar,
+}
+
+impl Raft {
+    /// Node `id` of the cluster formed by `peers`, restoring its term, vote
+    /// and log from `state_path` when given.
+    pub fn new(id: usize, peers: Vec<String>, state_path: Option<&str>, key: Option<PeerKey>) -> io::Result<Self> {
+        let (current_term, voted_for, log, storage) = match state_path {
+            Some(path) => {
+                let (term, voted_for, log) = load(path)?;
+                (term, voted_for, log, Some(OpenOptions::new().create(true).append(true).open(path)?))
+            }
+            None => (0, None, Vec::new(), None),
+        };
+        let seed = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_nanos() as u64 ^ id as u64;
+        let mut node = Node {
+            state: State::Follower,
+            current_term,
+            voted_for,
+            log,
+            commit_index: 0,
+            last_applied: 0,
+            leader: None,
+            votes: 0,
+            election_deadline: Instant::now(),
+            next_index: vec![1; peers.len()],
+            match_index: vec![0; peers.len()],
+            results: HashMap::new(),
+            storage,
+            rng: seed,
+        };
+        node.reset_election_deadline();
+        Ok(Self { id, peers, key, node: Mutex::new(node), changed: Condvar::new() })
+    }
+
+    /// Starts the election timer and one replication thread per peer.
+    pub fn start(self: &Arc<Self>) {
+        let raft = Arc::clone(self);
+        thread::spawn(move || raft.run_timer());
+        for peer in (0..self.peers.len()).filter(|peer| *peer != self.id) {
+            let raft = Arc::clone(self);
+            thread::spawn(move || raft.run_replicator(peer));
+        }
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Node> {
+        self.node.lock().unwrap()
+    }
+
+    fn majority(&self) -> usize {
+        self.peers.len() / 2 + 1
+    }
+
+    /// The node believed to lead the current term, if any.
+    pub fn leader(&self) -> Option<usize> {
+        let node = self.lock();
+        if node.state == State::Leader { Some(self.id) } else { node.leader }
+    }
+
+    /// Appends `command` to the log and waits until it is applied, returning
+    /// the reply it produced. `None` if this node is not the leader or lost
+    /// leadership before the entry committed; the client's resend covers it.
+    pub fn propose(&self, command: Vec<u8>) -> Option<Vec<u8>> {
+        let mut node = self.lock();
+        if node.state != State::Leader {
+            return None;
+        }
+        let term = node.current_term;
+        node.log.push(LogEntry { term, command });
+        let index = node.last_index();
+        node.persist_entry(index);
+        node.match_index[self.id] = index;
+        node.results.insert(index, None);
+        self.advance_commit(&mut node);
+        self.changed.notify_all();
+
+        let deadline = Instant::now() + COMMIT_TIMEOUT;
+        while node.last_applied < index && index <= node.last_index() && node.term_at(index) == term {
+            let now = Instant::now();
+            if now >= deadline {
+                break;
+            }
+            node = self.changed.wait_timeout(node, deadline - now).unwrap().0;
+        }
+        let reply = node.results.remove(&index).flatten();
+        // A different entry at our index means another leader overwrote ours
+        if index <= node.last_index() && node.term_at(index) == term { reply } else { None }
+    }
+
+    /// Waits for the next committed entry to apply and returns its index and command.
+    pub fn next_committed(&self) -> (u32, Vec<u8>) {
+        let mut node = self.lock();
+        while node.commit_index <= node.last_applied {
+            node = self.changed.wait(node).unwrap();
+        }
+        let index = node.last_applied + 1;
+        (index, node.log[index as usize - 1].command.clone())
+    }
+
+    /// Records that the entry at `index` was applied, producing `reply`.
+    pub fn applied(&self, index: u32, reply: Vec<u8>) {
+        let mut node = self.lock();
+        node.last_applied = index;
+        if let Some(result) = node.results.get_mut(&index) {
+            *result = Some(reply);
+        }
+        self.changed.notify_all();
+    }
+
+    /// Answers a RequestVote or AppendEntries from another node. Replies are
+    /// only ever read by the node that asked, so one sent unprompted is ignored,
+    /// and so is an AppendEntries from a leader outside our peer list.
+    pub fn handle(&self, message: RaftMessage) -> Option<RaftMessage> {
+        let mut node = self.lock();
+        match message {
+            RaftMessage::RequestVote { term, candidate, last_log_index, last_log_term } => {
+                if term > node.current_term {
+                    node.step_down(term);
+                }
+                let up_to_date = (last_log_term, last_log_index) >= (node.term_at(node.last_index()), node.last_index());
+                let granted = term == node.current_term && up_to_date
+                    && node.voted_for.is_none_or(|voted_for| voted_for == candidate);
+                if granted {
+                    node.voted_for = Some(candidate);
+                    node.persist_vote();
+                    node.reset_election_deadline();
+                }
+                Some(RaftMessage::Vote { term: node.current_term, granted })
+            }
+            RaftMessage::AppendEntries { term, leader, prev_log_index, prev_log_term, entries, leader_commit } => {
+                // Redirects index the peer list with it, so a misconfigured peer must not become our leader
+                if leader as usize >= self.peers.len() {
+                    return None;
+                }
+                if term < node.current_term {
+                    return Some(RaftMessage::Appended { term: node.current_term, success: false, match_index: node.last_index() });
+                }
+                node.step_down(term);
+                node.leader = Some(leader as usize);
+                node.reset_election_deadline();
+                if prev_log_index > node.last_index() || node.term_at(prev_log_index) != prev_log_term {
+                    let hint = node.last_index().min(prev_log_index.saturating_sub(1));
+                    return Some(RaftMessage::Appended { term: node.current_term, success: false, match_index: hint });
+                }
+                let last_new = prev_log_index + entries.len() as u32;
+                for (index, entry) in (prev_log_index + 1..).zip(entries) {
+                    if index <= node.last_index() {
+                        if node.term_at(index) == entry.term {
+                            continue;
+                        }
+                        // Committed entries always match, so only uncommitted ones are dropped here
+                        node.log.truncate(index as usize - 1);
+                    }
+                    node.log.push(entry);
+                    node.persist_entry(index);
+                }
+                // A late AppendEntries may cover less of the log than was already committed
+                let commit_index = node.commit_index.max(leader_commit.min(last_new));
+                if commit_index > node.commit_index {
+                    node.commit_index = commit_index;
+                    self.changed.notify_all();
+                }
+                Some(RaftMessage::Appended { term: node.current_term, success: true, match_index: last_new })
+            }
+            RaftMessage::Vote { .. } | RaftMessage::Appended { .. } => None,
+        }
+    }
+
+    /// Commits the newest entry of the current term held by a majority, and with it everything before.
+    fn advance_commit(&self, node: &mut Node) {
+        for index in (node.commit_index + 1..=node.last_index()).rev() {
+            if node.term_at(index) != node.current_term {
+                break;
+            }
+            if node.match_index.iter().filter(|match_index| **match_index >= index).count() >= self.majority() {
+                node.commit_index = index;
+                self.changed.notify_all();
+                break;
+            }
+        }
+    }
+
+    fn become_leader(&self, node: &mut Node) {
+        println!("raft node {} is the leader for term {}", self.id, node.current_term);
+        node.state = State::Leader;
+        node.leader = Some(self.id);
+        node.next_index = vec![node.last_index() + 1; self.peers.len()];
+        node.match_index = vec![0; self.peers.len()];
+        // Entries from earlier terms only commit behind one of our own
+        let term = node.current_term;
+        node.log.push(LogEntry { term, command: Vec::new() });
+        let index = node.last_index();
+        node.persist_entry(index);
+        node.match_index[self.id] = index;
+        self.advance_commit(node);
+        self.changed.notify_all();
+    }
+
+    fn run_timer(self: Arc<Self>) {
+        loop {
+            thread::sleep(Duration::from_millis(10));
+            let mut node = self.lock();
+            if node.state == State::Leader || Instant::now() < node.election_deadline {
+                continue;
+            }
+            node.state = State::Candidate;
+            node.current_term += 1;
+            node.voted_for = Some(self.id as u8);
+            node.persist_vote();
+            node.votes = 1;
+            node.leader = None;
+            node.reset_election_deadline();
+            if node.votes >= self.majority() {
+                self.become_leader(&mut node);
+                continue;
+            }
+            let request = RaftMessage::RequestVote {
+                term: node.current_term,
+                candidate: self.id as u8,
+                last_log_index: node.last_index(),
+                last_log_term: node.term_at(node.last_index()),
+            };
+            drop(node);
+            for peer in (0..self.peers.len()).filter(|peer| *peer != self.id) {
+                let raft = Arc::clone(&self);
+                let request = request.clone();
+                thread::spawn(move || raft.request_vote(peer, request));
+            }
+        }
+    }
+
+    fn request_vote(&self, peer: usize, request: RaftMessage) {
+        let RaftMessage::RequestVote { term, .. } = request else {
+            return;
+        };
+        let mut stream = None;
+        let Ok(RaftMessage::Vote { term: reply_term, granted }) = self.call(&mut stream, peer, &request) else {
+            return;
+        };
+        let mut node = self.lock();
+        if reply_term > node.current_term {
+            node.step_down(reply_term);
+        } else if granted && node.state == State::Candidate && node.current_term == term {
+            node.votes += 1;
+            if node.votes >= self.majority() {
+                self.become_leader(&mut node);
+            }
+        }
+    }
+
+    /// Keeps one follower's log in step with the leader's, sending heartbeats when idle.
+    fn run_replicator(self: Arc<Self>, peer: usize) {
+        let mut stream: Option<TcpStream> = None;
+        let mut last_sent = Instant::now() - HEARTBEAT_INTERVAL;
+        loop {
+            let mut node = self.lock();
+            while node.state != State::Leader
+                || (node.next_index[peer] > node.last_index() && last_sent.elapsed() < HEARTBEAT_INTERVAL)
+            {
+                let wait = HEARTBEAT_INTERVAL.saturating_sub(last_sent.elapsed()).max(Duration::from_millis(1));
+                node = self.changed.wait_timeout(node, wait).unwrap().0;
+            }
+            let term = node.current_term;
+            let prev_log_index = node.next_index[peer] - 1;
+            let end = node.last_index().min(prev_log_index + MAX_BATCH as u32);
+            let request = RaftMessage::AppendEntries {
+                term,
+                leader: self.id as u8,
+                prev_log_index,
+                prev_log_term: node.term_at(prev_log_index),
+                entries: node.log[prev_log_index as usize..end as usize].to_vec(),
+                leader_commit: node.commit_index,
+            };
+            drop(node);
+
+            last_sent = Instant::now();
+            let reply = self.call(&mut stream, peer, &request);
+            let mut node = self.lock();
+            match reply {
+                Ok(RaftMessage::Appended { term: reply_term, success, match_index }) => {
+                    if reply_term > node.current_term {
+                        node.step_down(reply_term);
+                    } else if node.state == State::Leader && node.current_term == term {
+                        if success {
+                            node.match_index[peer] = node.match_index[peer].max(match_index);
+                            node.next_index[peer] = node.match_index[peer] + 1;
+                            self.advance_commit(&mut node);
+                        } else {
+                            node.next_index[peer] = (match_index + 1).min(prev_log_index).max(1);
+                        }
+                    }
+                }
+                _ => {
+                    // Unreachable peer: retry with the next heartbeat rather than spinning
+                    drop(node);
+                    thread::sleep(HEARTBEAT_INTERVAL);
+                }
+            }
+        }
+    }
+
+    /// Sends `request` to `peer` over `stream`, connecting first if needed, and returns its answer.
+    fn call(&self, stream: &mut Option<TcpStream>, peer: usize, request: &RaftMessage) -> io::Result<RaftMessage> {
+        if stream.is_none() {
+            *stream = Some(tcp::connect(&self.peers[peer], RPC_TIMEOUT)?);
+        }
+        let mut output_stream = Writer::versioned(PROTOCOL_VERSION, Format::Binary);
+        output_stream.put(&RequestType::RAFT).put(request);
+        let output_stream: Vec<u8> = sign_request(self.key.as_ref(), output_stream.into_bytes());
+        let connection: &mut TcpStream = stream.as_mut().unwrap();
+        let reply = match write_frame(connection, &output_stream).and_then(|_| read_frame(connection)) {
+            Ok(reply) => reply,
+            Err(e) => {
+                *stream = None;
+                return Err(e);
+            }
+        };
+        let (status, mut reader) = reply_status(&reply)?;
+        match status {
+            ReplyStatus::Ok => Ok(reader.get::<RaftMessage>()?),
+            status => Err(io::Error::other(format!("peer refused raft message: {:?}", status))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempFile;
+
+    fn entry(term: u32, command: u8) -> LogEntry {
+        LogEntry { term, command: vec![command] }
+    }
+
+    /// Node 0 of a three node cluster in `term` holding `log`, kept in memory only.
+    fn node(term: u32, log: Vec<LogEntry>) -> Raft {
+        let raft = Raft::new(0, vec!["a".to_string(), "b".to_string(), "c".to_string()], None, None).unwrap();
+        let mut node = raft.lock();
+        node.current_term = term;
+        node.log = log;
+        drop(node);
+        raft
+    }
+
+    fn append(term: u32, prev_log_index: u32, prev_log_term: u32, entries: Vec<LogEntry>) -> RaftMessage {
+        RaftMessage::AppendEntries { term, leader: 1, prev_log_index, prev_log_term, leader_commit: 0, entries }
+    }
+
+    fn request_vote(term: u32, candidate: u8, last_log_index: u32, last_log_term: u32) -> RaftMessage {
+        RaftMessage::RequestVote { term, candidate, last_log_index, last_log_term }
+    }
+
+    #[test]
+    fn conflicting_entries_are_truncated() {
+        let raft = node(2, vec![entry(1, 1), entry(1, 2), entry(2, 3), entry(2, 4)]);
+        let reply = raft.handle(append(3, 2, 1, vec![entry(3, 5)]));
+        assert_eq!(reply, Some(RaftMessage::Appended { term: 3, success: true, match_index: 3 }));
+        assert_eq!(raft.lock().log, vec![entry(1, 1), entry(1, 2), entry(3, 5)]);
+    }
+
+    #[test]
+    fn matching_entries_are_kept() {
+        let raft = node(2, vec![entry(1, 1), entry(2, 2), entry(2, 3)]);
+        // A delayed AppendEntries holding only a prefix of the log must not shorten it
+        let reply = raft.handle(append(2, 0, 0, vec![entry(1, 1), entry(2, 2)]));
+        assert_eq!(reply, Some(RaftMessage::Appended { term: 2, success: true, match_index: 2 }));
+        assert_eq!(raft.lock().log.len(), 3);
+    }
+
+    #[test]
+    fn late_appends_keep_the_commit_index() {
+        let raft = node(2, vec![entry(1, 1), entry(2, 2), entry(2, 3)]);
+        let commit = |leader_commit: u32, prev_log_index: u32, entries: Vec<LogEntry>| {
+            let prev_log_term = if prev_log_index == 0 { 0 } else { raft.lock().term_at(prev_log_index) };
+            raft.handle(RaftMessage::AppendEntries { term: 2, leader: 1, prev_log_index, prev_log_term, leader_commit, entries });
+            raft.lock().commit_index
+        };
+        assert_eq!(commit(2, 3, Vec::new()), 2);
+        // The leader has committed more since, but this one only reaches index 1 of the log
+        assert_eq!(commit(3, 0, vec![entry(1, 1)]), 2);
+        assert_eq!(commit(3, 1, Vec::new()), 2);
+        assert_eq!(commit(3, 3, Vec::new()), 3);
+    }
+
+    #[test]
+    fn missing_previous_entry_is_refused() {
+        let raft = node(2, vec![entry(1, 1)]);
+        let reply = raft.handle(append(2, 3, 2, vec![entry(2, 2)]));
+        assert_eq!(reply, Some(RaftMessage::Appended { term: 2, success: false, match_index: 1 }));
+        assert_eq!(raft.lock().log, vec![entry(1, 1)]);
+    }
+
+    #[test]
+    fn stale_term_is_refused() {
+        let raft = node(3, vec![entry(1, 1)]);
+        let reply = raft.handle(append(2, 1, 1, vec![entry(2, 2)]));
+        assert_eq!(reply, Some(RaftMessage::Appended { term: 3, success: false, match_index: 1 }));
+        assert_eq!(raft.lock().log, vec![entry(1, 1)]);
+        assert_eq!(raft.handle(request_vote(2, 1, 5, 2)), Some(RaftMessage::Vote { term: 3, granted: false }));
+        assert_eq!(raft.lock().voted_for, None);
+    }
+
+    #[test]
+    fn vote_needs_an_up_to_date_log() {
+        let raft = node(2, vec![entry(1, 1), entry(2, 2)]);
+        // A longer log of an older term is behind ours
+        assert_eq!(raft.handle(request_vote(3, 1, 5, 1)), Some(RaftMessage::Vote { term: 3, granted: false }));
+        // A shorter log of the same term too
+        assert_eq!(raft.handle(request_vote(3, 1, 1, 2)), Some(RaftMessage::Vote { term: 3, granted: false }));
+        assert_eq!(raft.handle(request_vote(3, 1, 2, 2)), Some(RaftMessage::Vote { term: 3, granted: true }));
+        // One vote per term
+        assert_eq!(raft.handle(request_vote(3, 2, 9, 3)), Some(RaftMessage::Vote { term: 3, granted: false }));
+        assert_eq!(raft.lock().voted_for, Some(1));
+    }
+
+    #[test]
+    fn appends_from_unknown_leaders_are_ignored() {
+        let raft = node(2, vec![entry(1, 1)]);
+        let message = RaftMessage::AppendEntries { term: 3, leader: 3, prev_log_index: 1, prev_log_term: 1, leader_commit: 2, entries: vec![entry(3, 2)] };
+        assert_eq!(raft.handle(message), None);
+        let node = raft.lock();
+        assert_eq!((node.current_term, node.leader, node.log.len()), (2, None, 1));
+    }
+
+    #[test]
+    fn replies_are_ignored() {
+        let raft = node(2, Vec::new());
+        assert_eq!(raft.handle(RaftMessage::Vote { term: 5, granted: true }), None);
+        assert_eq!(raft.handle(RaftMessage::Appended { term: 5, success: true, match_index: 3 }), None);
+        assert_eq!(raft.lock().current_term, 2);
+        // The node is still usable afterwards
+        assert!(raft.handle(request_vote(2, 1, 0, 0)).is_some());
+    }
+
+    #[test]
+    fn load_missing_file_is_empty() {
+        let (term, voted_for, log) = load(TempFile::new("raft-missing").path()).unwrap();
+        assert_eq!((term, voted_for, log), (0, None, Vec::new()));
+    }
+
+    #[test]
+    fn load_replays_votes_and_truncations() {
+        let state = TempFile::new("raft-replay");
+        let path = state.path();
+        let mut records = Writer::new();
+        records.put(&0u8).put(&1u32).put(&2u8);
+        records.put(&1u8).put(&1u32).put(&entry(1, 1));
+        records.put(&1u8).put(&2u32).put(&entry(1, 2));
+        records.put(&1u8).put(&3u32).put(&entry(1, 3));
+        // A new leader overwrote the log from index 2, and no vote was cast in its term
+        records.put(&0u8).put(&2u32).put(&u8::MAX);
+        records.put(&1u8).put(&2u32).put(&entry(2, 4));
+        fs::write(path, records.into_bytes()).unwrap();
+        let (term, voted_for, log) = load(path).unwrap();
+        assert_eq!((term, voted_for, log), (2, None, vec![entry(1, 1), entry(2, 4)]));
+    }
+
+    #[test]
+    fn load_restores_what_handle_persisted() {
+        let state = TempFile::new("raft-persisted");
+        let raft = Raft::new(0, vec!["a".to_string(), "b".to_string()], Some(state.path()), None).unwrap();
+        raft.handle(request_vote(4, 1, 0, 0));
+        raft.handle(append(4, 0, 0, vec![entry(3, 1), entry(3, 2)]));
+        raft.handle(append(4, 1, 3, vec![entry(4, 3)]));
+        drop(raft);
+        let (term, voted_for, log) = load(state.path()).unwrap();
+        assert_eq!((term, voted_for, log), (4, Some(1), vec![entry(3, 1), entry(4, 3)]));
+    }
+
+    #[test]
+    fn load_rejects_unknown_records() {
+        let state = TempFile::with("raft-corrupt", [7u8]);
+        let error = load(state.path()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,63 @@
+use std::io;
+use std::net::TcpStream;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::auth::{sign_request, PeerKey};
+use crate::codec::{Format, Writer};
+use crate::message::{reply_status, Mutation, Replicate, ReplyStatus, RequestType, PROTOCOL_VERSION};
+use crate::tcp::{self, read_frame, write_frame};
+
+/// How long a primary waits on a backup before carrying on without it.
+const BACKUP_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A backup as seen by the primary. The connection is opened lazily and
+/// dropped on any error; every new connection starts with a snapshot. Each
+/// link has its own lock, so waiting on one backup never blocks the role lock.
+pub struct BackupLink {
+    pub address: String,
+    /// Signs the REPLICATE requests when the deployment has credentials
+    key: Option<PeerKey>,
+    stream: Mutex<Option<TcpStream>>,
+}
+
+impl BackupLink {
+    pub fn new(address: &str, key: Option<PeerKey>) -> Arc<Self> {
+        Arc::new(Self { address: address.to_string(), key, stream: Mutex::new(None) })
+    }
+
+    fn exchange(&self, stream: &mut TcpStream, primary: &str, mutation: Mutation) -> io::Result<()> {
+        let mut output_stream = Writer::versioned(PROTOCOL_VERSION, Format::Binary);
+        output_stream.put(&RequestType::REPLICATE).put(&Replicate { primary: primary.to_string(), mutation });
+        write_frame(stream, &sign_request(self.key.as_ref(), output_stream.into_bytes()))?;
+        let reply = read_frame(stream)?;
+        match reply_status(&reply)?.0 {
+            ReplyStatus::Ok => Ok(()),
+            status => Err(io::Error::other(format!("backup refused replication: {:?}", status))),
+        }
+    }
+
+    /// Delivers `mutation` and waits for the acknowledgement. `snapshot` is
+    /// called to bring a newly connected backup up to date first.
+    pub fn send(&self, primary: &str, mutation: Mutation, snapshot: &dyn Fn() -> Vec<Mutation>) -> io::Result<()> {
+        let mut stream = self.stream.lock().unwrap();
+        let result = match stream.take() {
+            Some(mut stream) => self.exchange(&mut stream, primary, mutation).map(|_| stream),
+            None => tcp::connect(&self.address, BACKUP_TIMEOUT).and_then(|mut stream| {
+                for state in snapshot() {
+                    self.exchange(&mut stream, primary, state)?;
+                }
+                self.exchange(&mut stream, primary, mutation)?;
+                Ok(stream)
+            }),
+        };
+        *stream = Some(result?);
+        Ok(())
+    }
+}
+
+pub enum Role {
+    /// Serves clients and forwards every mutation to its backups before replying
+    Primary { backups: Vec<Arc<BackupLink>> },
+    /// Applies what the primary sends and redirects clients to it
+    Backup { primary: String },
+}
@@ -0,0 +1,257 @@
+use std::collections::BTreeMap;
+
+/// A booked range of slots `[start, end)` held by `owner`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interval {
+    pub start: u16,
+    pub end: u16,
+    pub owner: u8,
+}
+
+/// One day's bookings stored as non-overlapping intervals keyed by start slot,
+/// so lookups cost O(log n) in the number of bookings rather than the number of slots.
+/// Adjacent bookings of the same owner share one interval, as they share a run
+/// in the owner bytes of the wire, so a schedule survives `from_slots(to_slots())`
+/// unchanged and any booking can be found again inside its owner's run.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+    num_slots: u16,
+    intervals: BTreeMap<u16, (u16, u8)>,
+}
+
+impl Schedule {
+    pub fn new(num_slots: u16) -> Self {
+        Self { num_slots, intervals: BTreeMap::new() }
+    }
+
+    /// Rebuilds a schedule from one owner byte per slot (0 = free), one interval per run.
+    pub fn from_slots(slots: &[u8]) -> Self {
+        let mut schedule = Self::new(slots.len() as u16);
+        let mut i = 0;
+        while i < slots.len() {
+            let owner = slots[i];
+            let start = i;
+            while i < slots.len() && slots[i] == owner {
+                i += 1;
+            }
+            if owner != 0 {
+                schedule.intervals.insert(start as u16, (i as u16, owner));
+            }
+        }
+        schedule
+    }
+
+    pub fn num_slots(&self) -> u16 {
+        self.num_slots
+    }
+
+    /// One owner byte per slot (0 = free), the layout used on the wire.
+    pub fn to_slots(&self) -> Vec<u8> {
+        let mut slots: Vec<u8> = vec![0; self.num_slots as usize];
+        for (&start, &(end, owner)) in &self.intervals {
+            slots[start as usize..end as usize].fill(owner);
+        }
+        slots
+    }
+
+    /// Owner of `slot`, 0 when it is free.
+    pub fn owner_at(&self, slot: u16) -> u8 {
+        match self.intervals.range(..=slot).next_back() {
+            Some((_, &(end, owner))) if end > slot => owner,
+            _ => 0,
+        }
+    }
+
+    /// Whether every slot in `[start, end)` is inside the day and unbooked.
+    pub fn is_free(&self, start: u16, end: u16) -> bool {
+        if start > end || end > self.num_slots {
+            return false;
+        }
+        // Only the last interval starting before `end` can reach into the range
+        match self.intervals.range(..end).next_back() {
+            Some((_, &(last_end, _))) => last_end <= start,
+            None => true,
+        }
+    }
+
+    /// Start of the first window of `len` free slots at or after `from`.
+    pub fn first_free(&self, len: u16, from: u16) -> Option<u16> {
+        let mut candidate = from;
+        if let Some((_, &(end, _))) = self.intervals.range(..=from).next_back() {
+            candidate = candidate.max(end);
+        }
+        for (&start, &(end, _)) in self.intervals.range(candidate..) {
+            if start - candidate >= len {
+                break;
+            }
+            candidate = end;
+        }
+        if candidate as u32 + len as u32 <= self.num_slots as u32 {
+            Some(candidate)
+        } else {
+            None
+        }
+    }
+
+    /// Bookings overlapping `[start, end)`, in slot order.
+    pub fn bookings_in(&self, start: u16, end: u16) -> Vec<Interval> {
+        let mut bookings: Vec<Interval> = Vec::new();
+        if let Some((&s, &(e, owner))) = self.intervals.range(..start).next_back()
+            && e > start
+        {
+            bookings.push(Interval { start: s, end: e, owner });
+        }
+        for (&s, &(e, owner)) in self.intervals.range(start..end) {
+            bookings.push(Interval { start: s, end: e, owner });
+        }
+        bookings
+    }
+
+    /// Books `[start, end)` for `owner` if the whole range is free, joining
+    /// the runs of `owner` it touches.
+    pub fn insert(&mut self, start: u16, end: u16, owner: u8) -> bool {
+        if start == end {
+            return self.is_free(start, end);
+        }
+        if !self.is_free(start, end) {
+            return false;
+        }
+        let (mut run_start, mut run_end) = (start, end);
+        if let Some((&s, &(e, o))) = self.intervals.range(..start).next_back()
+            && e == start && o == owner
+        {
+            self.intervals.remove(&s);
+            run_start = s;
+        }
+        if let Some(&(e, o)) = self.intervals.get(&end)
+            && o == owner
+        {
+            self.intervals.remove(&end);
+            run_end = e;
+        }
+        self.intervals.insert(run_start, (run_end, owner));
+        true
+    }
+
+    /// Releases the booking `[start, end)` if `owner` holds all of it, leaving
+    /// the rest of the run it was part of booked.
+    pub fn remove(&mut self, start: u16, end: u16, owner: u8) -> bool {
+        if start >= end {
+            return false;
+        }
+        match self.intervals.range(..=start).next_back() {
+            Some((&s, &(e, o))) if e >= end && o == owner => {
+                self.intervals.remove(&s);
+                if s < start {
+                    self.intervals.insert(s, (start, owner));
+                }
+                if end < e {
+                    self.intervals.insert(end, (e, owner));
+                }
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Moves the booking `[start, end)` held by `owner` so that it starts at
+    /// `new_start`. The booking may overlap its own old range; nothing changes on failure.
+    pub fn move_booking(&mut self, start: u16, end: u16, owner: u8, new_start: u16) -> bool {
+        if start == end {
+            return self.is_free(new_start, new_start);
+        }
+        if !self.remove(start, end, owner) {
+            return false;
+        }
+        let new_end = new_start as u32 + (end - start) as u32;
+        if new_end <= self.num_slots as u32 && self.insert(new_start, new_end as u16, owner) {
+            true
+        } else {
+            // Just freed, so this always succeeds and rejoins the run it came from
+            self.insert(start, end, owner);
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A 16-slot day holding the given `(start, end, owner)` bookings.
+    fn schedule(bookings: &[(u16, u16, u8)]) -> Schedule {
+        let mut schedule = Schedule::new(16);
+        for &(start, end, owner) in bookings {
+            assert!(schedule.insert(start, end, owner), "[{}, {}) for {}", start, end, owner);
+        }
+        schedule
+    }
+
+    fn intervals(schedule: &Schedule) -> Vec<(u16, u16, u8)> {
+        schedule.bookings_in(0, schedule.num_slots()).iter().map(|i| (i.start, i.end, i.owner)).collect()
+    }
+
+    #[test]
+    fn insert_refuses_taken_or_out_of_day_slots() {
+        let mut day = schedule(&[(2, 4, 1)]);
+        assert!(!day.insert(3, 5, 2));
+        assert!(!day.insert(0, 3, 1));
+        assert!(!day.insert(15, 17, 2));
+        assert!(day.insert(4, 4, 2));
+        assert!(day.insert(0, 2, 2));
+        assert_eq!(intervals(&day), [(0, 2, 2), (2, 4, 1)]);
+        assert_eq!(day.owner_at(1), 2);
+        assert_eq!(day.owner_at(4), 0);
+        assert_eq!(day.first_free(3, 0), Some(4));
+    }
+
+    #[test]
+    fn adjacent_bookings_of_one_owner_share_a_run() {
+        let day = schedule(&[(2, 4, 1), (6, 8, 1), (4, 6, 1), (8, 9, 2)]);
+        assert_eq!(intervals(&day), [(2, 8, 1), (8, 9, 2)]);
+    }
+
+    #[test]
+    fn remove_releases_one_booking_of_a_run() {
+        let mut day = schedule(&[(2, 4, 1), (4, 6, 1), (6, 8, 1)]);
+        assert!(!day.remove(4, 6, 2));
+        assert!(!day.remove(5, 9, 1));
+        assert!(!day.remove(4, 4, 1));
+        assert!(day.remove(4, 6, 1));
+        assert_eq!(intervals(&day), [(2, 4, 1), (6, 8, 1)]);
+        assert!(day.remove(2, 4, 1));
+        assert!(day.remove(6, 8, 1));
+        assert_eq!(intervals(&day), []);
+        assert!(!day.remove(6, 8, 1));
+    }
+
+    #[test]
+    fn move_booking_rolls_back_on_failure() {
+        let mut day = schedule(&[(2, 4, 1), (4, 6, 1), (8, 10, 2)]);
+        let before = day.clone();
+        // Into another owner's booking, past the end of the day, or of someone else's booking
+        assert!(!day.move_booking(4, 6, 1, 7));
+        assert!(!day.move_booking(4, 6, 1, 15));
+        assert!(!day.move_booking(4, 6, 2, 5));
+        assert_eq!(day, before);
+        // Over its own old slots, out of the middle of its owner's run
+        assert!(day.move_booking(4, 6, 1, 5));
+        assert_eq!(intervals(&day), [(2, 4, 1), (5, 7, 1), (8, 10, 2)]);
+        assert!(day.move_booking(2, 4, 1, 3));
+        assert_eq!(intervals(&day), [(3, 7, 1), (8, 10, 2)]);
+    }
+
+    #[test]
+    fn slots_round_trip() {
+        let day = schedule(&[(0, 2, 3), (2, 4, 1), (4, 6, 1), (7, 8, 1), (15, 16, 2)]);
+        let slots = day.to_slots();
+        assert_eq!(slots, [3, 3, 1, 1, 1, 1, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2]);
+        let decoded = Schedule::from_slots(&slots);
+        assert_eq!(decoded, day);
+        // Bookings stay removable one by one after a round trip
+        let mut decoded = decoded;
+        assert!(decoded.remove(2, 4, 1));
+        assert!(decoded.move_booking(4, 6, 1, 2));
+        assert_eq!(decoded.to_slots(), [3, 3, 1, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2]);
+    }
+}
@@ -1,140 +1,1558 @@
-use std::{collections::HashMap, net::{SocketAddr, UdpSocket}, thread, time::Duration};
+use std::{collections::{BTreeMap, HashMap}, net::{IpAddr, SocketAddr, TcpListener, TcpStream, UdpSocket}, process, sync::{Arc, Mutex, MutexGuard}, thread, time::{Duration, Instant}};
+use clap::{Parser, ValueEnum};
+pub mod codec;
 pub mod message;
-use message::{FacilityRecord, RequestType, QueryRequest, QueryResponse, Booking, BookingResponse, Update, UpdateResponse, Monitor};
+pub mod schedule;
+pub mod ical;
+pub mod shard;
+pub mod replication;
+pub mod raft;
+pub mod txn;
+pub mod fragment;
+pub mod tcp;
+pub mod auth;
+#[cfg(test)]
+mod testing;
+use shard::ShardMap;
+use auth::{PeerKey, SeenRequests, Users, LOGIN_INTERVAL, PEER_MAC_LEN};
+use codec::{Decode, DecodeError, Encode, Format, Reader, Writer, FORMAT_BYTE};
+use fragment::{Datagram, Reassembler, SentCache};
+use raft::Raft;
+use txn::{Coordinator, InDoubt};
+use replication::{BackupLink, Role};
+use message::{ErrorCode, BookRequest, Login, LoginResponse, Token, MultiBook, MultiBookResponse, Prepare, PrepareResponse, TxnId, Outcome, Mutation, Replicate, Promote, ReplyStatus, PROTOCOL_VERSION, SUPPORTED_VERSIONS, FacilityRecord, RequestType, QueryRequest, QueryResponse, Booking, BookingResponse, Update, UpdateResponse, Monitor, GetBooking, GetBookingResponse, ListBookings, ListBookingsResponse, Export, ExportResponse, FacilityStats, StatsResponse};
 
-fn main() {
+#[derive(Parser, Debug)]
+#[command(name = "Facility Server", about = "A UDP server for facility booking system")]
+struct Cli {
+    /// Address to listen on, defaults to this shard's entry in the shard map or 127.0.0.1:5000
+    #[arg(short, long)]
+    address: Option<String>,
+
+    /// Facilities of the deployment, this server creates the ones its shard owns
+    #[arg(short, long, value_delimiter = ',', default_value = "facility1,facility2,facility3")]
+    facilities: Vec<String>,
+
+    /// File listing the shards of the deployment, see shard::ShardMap::load
+    #[arg(long)]
+    shard_map: Option<String>,
+
+    /// Index of this server in the shard map
+    #[arg(long, default_value_t = 0)]
+    shard: usize,
+
+    /// Run as a primary that forwards every booking change to these backups before replying
+    #[arg(long, value_delimiter = ',', conflicts_with = "backup_of")]
+    backups: Vec<String>,
+
+    /// Run as a backup of the primary at this address
+    #[arg(long)]
+    backup_of: Option<String>,
+
+    /// Addresses of every node of this shard's Raft cluster, including --address
+    #[arg(long, value_delimiter = ',', conflicts_with_all = ["backups", "backup_of"])]
+    raft_peers: Vec<String>,
+
+    /// File keeping this node's Raft term, vote and log across restarts
+    #[arg(long, requires = "raft_peers")]
+    raft_state: Option<String>,
+
+    /// File logging the multi-facility bookings this server coordinates, so it can finish them after a crash; without one it refuses them
+    #[arg(long)]
+    txn_log: Option<String>,
+
+    /// Credentials of the deployment, see auth::Users; mutating requests then need a token from LOGIN
+    #[arg(long)]
+    users: Option<String>,
+
+    /// Add this user to the --users file, creating it if needed, and exit
+    #[arg(long, requires_all = ["users", "password"])]
+    add_user: Option<u8>,
+
+    /// Password of the user given by --add-user
+    #[arg(long, requires = "add_user")]
+    password: Option<String>,
+
+    /// Let the user given by --add-user change the bookings of others
+    #[arg(long, requires = "add_user")]
+    admin: bool,
+
+    /// Number of worker threads receiving and handling requests
+    #[arg(short, long, default_value_t = 4)]
+    workers: usize,
+
+    /// Test option: delay the reply to a successful query by this many milliseconds
+    #[arg(long, default_value_t = 0)]
+    query_delay_ms: u64,
+
+    /// Test option: delay the reply to a successful booking by this many milliseconds
+    #[arg(long, default_value_t = 0)]
+    book_delay_ms: u64,
+
+    /// Test option: delay the reply to a successful update by this many milliseconds
+    #[arg(long, default_value_t = 0)]
+    update_delay_ms: u64,
+
+    /// Test option: exit while coordinating a multi-facility booking
+    #[arg(long, value_enum)]
+    crash_point: Option<CrashPoint>,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum CrashPoint {
+    /// Once every participant has voted, before the outcome is logged
+    AfterPrepare,
+    /// Once the outcome is logged, before participants hear of it
+    AfterDecision,
+}
+
+struct Bookings {
+    /// Next confirmation id to hand out, `None` once the ids are used up
+    next_id: Option<u8>,
+    list: HashMap<u8, Booking>,
+}
+
+/// Where a request came from, which is also where a monitoring client is
+/// pushed its updates.
+#[derive(Clone)]
+enum Peer {
+    Udp(SocketAddr),
+    /// The connection is shared with the thread serving its requests
+    Tcp(SocketAddr, Arc<Mutex<TcpStream>>),
+}
+
+impl Peer {
+    fn addr(&self) -> SocketAddr {
+        match self {
+            Peer::Udp(addr) | Peer::Tcp(addr, _) => *addr,
+        }
+    }
+}
+
+/// This server's share of a transaction it voted yes on: the slots are held
+/// and the confirmation ids reserved until the coordinator's outcome arrives.
+struct Prepared {
+    bookings: Vec<(u8, Booking)>,
+    /// When to ask the coordinator for the outcome if it has not arrived
+    deadline: Instant,
+}
+
+/// State shared by all workers. Each facility has its own lock so requests on
+/// different facilities never wait on each other. When both are needed, a
+/// facility lock is always taken before the bookings lock, then the prepared
+/// lock, and neither the role lock nor a backup link's lock is requested while
+/// holding the bookings lock. The role lock is never held across network I/O.
+struct Server {
+    socket: UdpSocket,
+    address: String,
+    role: Mutex<Role>,
+    /// Set when BOOK and UPDATE go through a Raft log instead of being applied directly
+    raft: Option<Arc<Raft>>,
+    shard_map: ShardMap,
+    shard: usize,
+    facilities: HashMap<String, Mutex<FacilityRecord>>,
+    bookings: Mutex<Bookings>,
+    prepared: Mutex<HashMap<TxnId, Prepared>>,
+    coordinator: Mutex<Coordinator>,
+    crash_point: Option<CrashPoint>,
+    /// Without credentials anyone may change any booking
+    users: Option<Users>,
+    /// Signs and checks requests between servers, present with credentials
+    peer_key: Option<PeerKey>,
+    /// Requests between servers accepted lately, to refuse replays of them
+    peer_requests: Mutex<SeenRequests>,
+    /// When each address last tried to LOGIN, kept for LOGIN_INTERVAL
+    login_attempts: Mutex<HashMap<IpAddr, Instant>>,
+    /// Monitoring clients with the protocol version and format to push updates in
+    monitoring_clients: Mutex<Vec<(Peer, u8, Format)>>,
+    /// Requests arriving in several fragments, which may land on different workers
+    reassembler: Mutex<Reassembler>,
+    /// Replies sent in several fragments, kept for clients that miss some
+    sent: Mutex<SentCache>,
+    query_delay: Duration,
+    book_delay: Duration,
+    update_delay: Duration,
+}
+
+/// Refuses bookings of no slots or running past the end of the day.
+fn check_slots(booking: &Booking) -> Result<(), (ErrorCode, &'static str)> {
+    if booking.num_slots == 0 {
+        return Err((ErrorCode::InvalidRequest, "Booking Failed, no slots requested"));
+    }
+    if booking.start_slot as u16 + booking.num_slots as u16 > FacilityRecord::SLOTS_PER_DAY as u16 {
+        return Err((ErrorCode::OutOfRange, "Booking Failed, the slots run past the end of the day"));
+    }
+    Ok(())
+}
+
+/// Sends one datagram, logging rather than failing on errors: UDP may lose it
+/// anyway, and the client resends the request if it needs the reply.
+fn send_datagram(socket: &UdpSocket, datagram: &[u8], addr: SocketAddr) {
+    if let Err(e) = socket.send_to(datagram, addr) {
+        eprintln!("Cannot send {} bytes to {}: {}", datagram.len(), addr, e);
+    }
+}
+
+/// Requests servers send each other, which change state no client may touch directly.
+fn is_peer_request(request_type: RequestType) -> bool {
+    matches!(request_type, RequestType::PREPARE | RequestType::COMMIT | RequestType::ABORT | RequestType::TXN_STATUS
+        | RequestType::REPLICATE | RequestType::PROMOTE | RequestType::RAFT)
+}
+
+/// Bookings owned by `user_id`, ordered by confirmation id.
+fn user_bookings(booking_list: &HashMap<u8, Booking>, user_id: u8) -> Vec<(u8, Booking)> {
+    let mut bookings: Vec<(u8, Booking)> = booking_list.iter()
+        .filter(|(_, booking)| booking.user_id == user_id)
+        .map(|(confirmation_id, booking)| (*confirmation_id, booking.clone()))
+        .collect();
+    bookings.sort_by_key(|(confirmation_id, _)| *confirmation_id);
+    bookings
+}
+
+impl Server {
+    /// Reply sending the client to `shard` when it is not this server.
+    fn redirect(&self, shard: usize, version: u8, format: Format) -> Option<(Vec<u8>, Duration)> {
+        if shard == self.shard {
+            return None;
+        }
+        Some((ReplyStatus::WrongShard { owner: self.shard_map.shards[shard].clone() }.to_bytes_as(version, format), Duration::ZERO))
+    }
+
+    /// Checks the token of a mutating request, which servers with credentials
+    /// require, and returns the user it was issued to; `None` when this server
+    /// has no credentials.
+    fn authenticate(&self, auth: Option<&Token>) -> Result<Option<u8>, String> {
+        let Some(users) = &self.users else {
+            return Ok(None);
+        };
+        let token = auth.ok_or("log in first")?;
+        users.verify(token).map(Some).map_err(str::to_string)
+    }
+
+    /// Like `authenticate`, also refusing a token of anyone but the users booked for.
+    fn authenticate_as(&self, auth: Option<&Token>, user_ids: impl IntoIterator<Item = u8>) -> Result<(), String> {
+        let Some(user) = self.authenticate(auth)? else {
+            return Ok(());
+        };
+        match user_ids.into_iter().find(|user_id| *user_id != user) {
+            Some(user_id) => Err(format!("logged in as user {}, not {}", user, user_id)),
+            None => Ok(()),
+        }
+    }
 
-    let mut all_facilities: HashMap<String, FacilityRecord> = HashMap::new();
-    all_facilities.insert(String::from("facility1"), FacilityRecord::new());
-    all_facilities.insert(String::from("facility2"), FacilityRecord::new());
-    all_facilities.insert(String::from("facility3"), FacilityRecord::new());
-    let socket: UdpSocket = UdpSocket::bind("127.0.0.1:5000").unwrap();
-    let mut booking_counter: u8 = 0;
-    let mut booking_list:HashMap<u8, Booking> = HashMap::new();
+    /// Whether the sender of `auth` may change a booking of `owner`: only the
+    /// owner or an admin can. The token was verified by `authenticate` when
+    /// the request came in, so it is trusted here even when a Raft log entry
+    /// is applied after it has expired.
+    fn may_change(&self, auth: Option<&Token>, owner: u8) -> bool {
+        match (&self.users, auth) {
+            (None, _) => true,
+            (Some(users), Some(token)) => token.user_id == owner || users.is_admin(token.user_id),
+            (Some(_), None) => false,
+        }
+    }
 
-    let mut monitoring_clients: Vec<SocketAddr> = Vec::new();
+    /// Books the slots if they are free and hands out the next confirmation id.
+    fn book(&self, booking: Booking) -> BookingResponse {
+        let refuse = |error: ErrorCode, message: &str| BookingResponse { success: false, error, message: message.to_string(), confirmation_id: 0 };
+        if let Err((error, message)) = check_slots(&booking) {
+            return refuse(error, message);
+        }
+        match self.facilities.get(&booking.facility_name) {
+            Some(facility) => {
+                let mut record = facility.lock().unwrap();
+                if record.is_slot_availaible(booking.day, booking.start_slot, booking.num_slots, booking.user_id) {
+                    let mut bookings = self.bookings.lock().unwrap();
+                    let Some(confirmation_id) = bookings.next_id else {
+                        record.release(booking.day, booking.start_slot, booking.num_slots, booking.user_id);
+                        return refuse(ErrorCode::IdsExhausted, "Booking Failed, no confirmation ids left");
+                    };
+                    bookings.next_id = confirmation_id.checked_add(self.shard_map.shards.len() as u8);
+                    bookings.list.insert(confirmation_id, booking.clone());
+                    drop(bookings);
+                    self.replicate(Mutation::Book { confirmation_id, booking });
+                    let snapshot: FacilityRecord = record.clone();
+                    drop(record);
+                    self.notify_monitors(&snapshot);
+                    BookingResponse { success: true, error: ErrorCode::None, message: "Booking Successful".to_string(), confirmation_id }
+                } else {
+                    println!("error in booking already booked");
+                    refuse(ErrorCode::SlotTaken, "Booking Failed, Slots not availaible")
+                }
+            },
+            None => refuse(ErrorCode::FacilityNotFound, "Booking Failed, Facility not availaible"),
+        }
+    }
 
-    println!("server running on 5000");
-    let mut buf:[u8;1024] = [0; 1024];
+    /// Moves a booking by `update.offset` slots if its owner asks and the new slots are free.
+    fn update(&self, update: Update) -> UpdateResponse {
+        // The facility of a booking never changes, so it can be read before taking the facility lock
+        let facility_name: Option<String> = self.bookings.lock().unwrap().list
+            .get(&update.confirmation_id)
+            .map(|booking| booking.facility_name.clone());
+        match facility_name {
+            Some(facility_name) => {
+                let mut facility = self.facilities[&facility_name].lock().unwrap();
+                let mut bookings = self.bookings.lock().unwrap();
+                let booking: &mut Booking = bookings.list.get_mut(&update.confirmation_id).unwrap();
+                if !self.may_change(update.auth.as_ref(), booking.user_id) {
+                    return UpdateResponse { success: false, error: ErrorCode::Forbidden, message: "Update Failed, the booking was made by another user".to_string() };
+                }
+                let new_start = booking.start_slot as i16 + update.offset as i16;
+                if new_start < 0 || new_start + booking.num_slots as i16 > FacilityRecord::SLOTS_PER_DAY as i16 {
+                    return UpdateResponse { success: false, error: ErrorCode::OutOfRange, message: "Update Failed, the booking would leave the day".to_string() };
+                }
+                if facility.update_booking(booking.day, booking.start_slot, booking.num_slots, booking.user_id, update.offset) {
+                    let new_start = booking.start_slot as i8 + update.offset;
+                    booking.start_slot = new_start as u8; // check for subtraction
+                    drop(bookings);
+                    self.replicate(Mutation::Move { confirmation_id: update.confirmation_id, start_slot: new_start as u8 });
+                    UpdateResponse { success: true, error: ErrorCode::None, message: "Booking updated".to_string() }
+                } else {
+                    UpdateResponse { success: false, error: ErrorCode::SlotTaken, message: "Update Failed, Slot not availaible".to_string() }
+                }
+            },
+            None => UpdateResponse { success: false, error: ErrorCode::BookingNotFound, message: "Update Failed, no such booking made".to_string() },
+        }
+    }
 
-    loop {
-        let bytes: usize;
-        let addr: SocketAddr;
-        match socket.recv_from(&mut buf) {
-            Ok((num_bytes, src_addr)) => {
-                // Success: data received
-                bytes = num_bytes;
-                addr = src_addr;
+    /// Holds the slots of this server's share of a transaction and reserves
+    /// confirmation ids for them, voting no if any slot is taken.
+    fn prepare(&self, prepare: Prepare) -> PrepareResponse {
+        let refuse = |error: ErrorCode, message: &str| PrepareResponse { vote: false, error, message: message.to_string(), confirmation_ids: Vec::new() };
+        if let Some((error, message)) = prepare.bookings.iter().find_map(|booking| check_slots(booking).err()) {
+            return refuse(error, message);
+        }
+        let mut names: Vec<String> = prepare.bookings.iter().map(|booking| booking.facility_name.clone()).collect();
+        names.sort();
+        names.dedup();
+        if names.iter().any(|name| !self.facilities.contains_key(name)) {
+            return refuse(ErrorCode::FacilityNotFound, "Booking Failed, Facility not availaible");
+        }
+        // Facility locks in name order, like a snapshot, so two transactions can't deadlock
+        let mut records: HashMap<String, MutexGuard<FacilityRecord>> = names.into_iter()
+            .map(|name| { let record = self.facilities[&name].lock().unwrap(); (name, record) })
+            .collect();
+        let mut bookings = self.bookings.lock().unwrap();
+        let mut prepared = self.prepared.lock().unwrap();
+        // The coordinator resent its prepare
+        if let Some(held) = prepared.get(&prepare.txn) {
+            return PrepareResponse { vote: true, error: ErrorCode::None, message: "Prepared".to_string(), confirmation_ids: held.bookings.iter().map(|(confirmation_id, _)| *confirmation_id).collect() };
+        }
+        let release = |records: &mut HashMap<String, MutexGuard<FacilityRecord>>, held: &[Booking]| {
+            for booking in held {
+                records.get_mut(&booking.facility_name).unwrap().release(booking.day, booking.start_slot, booking.num_slots, booking.user_id);
             }
-            Err(e) => {
-                // Other I/O error
-                eprintln!("An I/O error occurred: {}", e.kind());
+        };
+        for (i, booking) in prepare.bookings.iter().enumerate() {
+            if !records.get_mut(&booking.facility_name).unwrap().is_slot_availaible(booking.day, booking.start_slot, booking.num_slots, booking.user_id) {
+                release(&mut records, &prepare.bookings[..i]);
+                return refuse(ErrorCode::SlotTaken, "Booking Failed, Slots not availaible");
+            }
+        }
+        let stride = self.shard_map.shards.len() as u8;
+        let mut confirmation_ids: Vec<u8> = Vec::new();
+        let mut next_id: Option<u8> = bookings.next_id;
+        for _ in &prepare.bookings {
+            let Some(confirmation_id) = next_id else {
+                release(&mut records, &prepare.bookings);
+                return refuse(ErrorCode::IdsExhausted, "Booking Failed, no confirmation ids left");
+            };
+            confirmation_ids.push(confirmation_id);
+            next_id = confirmation_id.checked_add(stride);
+        }
+        bookings.next_id = next_id;
+        let held: Vec<(u8, Booking)> = confirmation_ids.iter().copied().zip(prepare.bookings).collect();
+        prepared.insert(prepare.txn.clone(), Prepared { bookings: held.clone(), deadline: Instant::now() + txn::PREPARE_TIMEOUT });
+        drop(prepared);
+        drop(bookings);
+        // Under the facility locks, so backups see the holds before any later change to these facilities
+        self.replicate(Mutation::Hold { txn: prepare.txn, bookings: held });
+        drop(records);
+        PrepareResponse { vote: true, error: ErrorCode::None, message: "Prepared".to_string(), confirmation_ids }
+    }
+
+    /// Turns the holds of a prepared transaction into bookings, or releases
+    /// them. Outcomes for unknown transactions were already applied.
+    fn finish(&self, txn: &TxnId, outcome: Outcome) {
+        let mut names: Vec<String> = match self.prepared.lock().unwrap().get(txn) {
+            Some(held) => held.bookings.iter().map(|(_, booking)| booking.facility_name.clone()).collect(),
+            None => return,
+        };
+        names.sort();
+        names.dedup();
+        // Facility locks in name order, like prepare, and all of them until backups have the outcome
+        let mut records: HashMap<String, MutexGuard<FacilityRecord>> = names.into_iter()
+            .map(|name| { let record = self.facilities[&name].lock().unwrap(); (name, record) })
+            .collect();
+        // Finished by another worker while we waited for the locks
+        let Some(held) = self.prepared.lock().unwrap().remove(txn) else {
+            return;
+        };
+        for (confirmation_id, booking) in held.bookings {
+            if outcome == Outcome::Committed {
+                self.bookings.lock().unwrap().list.insert(confirmation_id, booking);
+            } else {
+                records.get_mut(&booking.facility_name).unwrap().release(booking.day, booking.start_slot, booking.num_slots, booking.user_id);
+            }
+        }
+        self.replicate(Mutation::Finish { txn: txn.clone(), outcome });
+        if outcome == Outcome::Committed {
+            let snapshots: Vec<FacilityRecord> = records.values().map(|record| (**record).clone()).collect();
+            drop(records);
+            for snapshot in &snapshots {
+                self.notify_monitors(snapshot);
+            }
+        }
+    }
+
+    /// Coordinates a booking of several facilities with two-phase commit: every
+    /// owning shard holds its slots, then all of them commit or all abort.
+    /// Refused without a --txn-log, see txn::Coordinator.
+    fn multi_book(&self, request: MultiBook) -> MultiBookResponse {
+        if !self.coordinator.lock().unwrap().is_logged() {
+            return MultiBookResponse { success: false, error: ErrorCode::InvalidRequest, message: "Booking Failed, this server has no --txn-log to coordinate with".to_string(), confirmation_ids: Vec::new() };
+        }
+        if request.bookings.is_empty() {
+            return MultiBookResponse { success: false, error: ErrorCode::InvalidRequest, message: "Booking Failed, nothing to book".to_string(), confirmation_ids: Vec::new() };
+        }
+        let mut shares: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        for (i, booking) in request.bookings.iter().enumerate() {
+            shares.entry(self.shard_map.owner(&booking.facility_name)).or_default().push(i);
+        }
+        let participants: Vec<String> = shares.keys().map(|shard| self.shard_map.shards[*shard].clone()).collect();
+        let txn: TxnId = self.coordinator.lock().unwrap().begin(&participants);
+
+        let mut confirmation_ids: Vec<u8> = vec![0; request.bookings.len()];
+        let mut failure: Option<(ErrorCode, String)> = None;
+        for (participant, indexes) in participants.iter().zip(shares.values()) {
+            let output_stream = message::request(RequestType::PREPARE, &Prepare { txn: txn.clone(), bookings: indexes.iter().map(|i| request.bookings[*i].clone()).collect() });
+            match txn::call(participant, &output_stream, self.peer_key.as_ref()).and_then(|reply| Ok(PrepareResponse::from_bytes(&reply)?)) {
+                Ok(vote) => {
+                    if !vote.vote {
+                        failure = Some((vote.error, vote.message));
+                        break;
+                    }
+                    for (i, confirmation_id) in indexes.iter().zip(vote.confirmation_ids) {
+                        confirmation_ids[*i] = confirmation_id;
+                    }
+                }
+                Err(e) => {
+                    failure = Some((ErrorCode::Unavailable, format!("Booking Failed, {} did not answer: {}", participant, e)));
+                    break;
+                }
+            }
+        }
+        if self.crash_point == Some(CrashPoint::AfterPrepare) {
+            eprintln!("crashing after preparing transaction {}", txn.id);
+            process::exit(1);
+        }
+        let outcome = if failure.is_none() { Outcome::Committed } else { Outcome::Aborted };
+        self.coordinator.lock().unwrap().decide(txn.id, outcome);
+        if self.crash_point == Some(CrashPoint::AfterDecision) {
+            eprintln!("crashing after deciding transaction {}", txn.id);
+            process::exit(1);
+        }
+        self.resolve(&txn, &participants, outcome);
+        match failure {
+            None => MultiBookResponse { success: true, error: ErrorCode::None, message: "Booking Successful".to_string(), confirmation_ids },
+            Some((error, message)) => MultiBookResponse { success: false, error, message, confirmation_ids: Vec::new() },
+        }
+    }
+
+    /// Sends the outcome to every participant and returns whether all of them
+    /// acknowledged it. Participants that missed it ask again after PREPARE_TIMEOUT.
+    fn resolve(&self, txn: &TxnId, participants: &[String], outcome: Outcome) -> bool {
+        let request_type = if outcome == Outcome::Committed { RequestType::COMMIT } else { RequestType::ABORT };
+        let output_stream = message::request(request_type, txn);
+        let acknowledged = participants.iter()
+            .filter(|participant| match txn::call(participant, &output_stream, self.peer_key.as_ref()) {
+                Ok(_) => true,
+                Err(e) => {
+                    eprintln!("{} missed the outcome of transaction {}: {}", participant, txn.id, e);
+                    false
+                }
+            })
+            .count();
+        if acknowledged < participants.len() {
+            return false;
+        }
+        self.coordinator.lock().unwrap().done(txn.id);
+        true
+    }
+
+    /// Finishes the transactions a previous run of this server left in doubt.
+    /// Those without a logged outcome never committed anywhere and are aborted.
+    fn recover(&self, in_doubt: Vec<InDoubt>) {
+        for txn in in_doubt {
+            let outcome = match txn.outcome {
+                Outcome::Committed => Outcome::Committed,
+                _ => {
+                    self.coordinator.lock().unwrap().decide(txn.id, Outcome::Aborted);
+                    Outcome::Aborted
+                }
+            };
+            println!("finishing transaction {} ({:?}) with {:?}", txn.id, outcome, txn.participants);
+            let id = TxnId { coordinator: self.address.clone(), id: txn.id };
+            while !self.resolve(&id, &txn.participants, outcome) {
+                thread::sleep(txn::PREPARE_TIMEOUT);
+            }
+        }
+    }
+
+    /// Asks coordinators about prepared transactions whose outcome is overdue.
+    /// Slots stay held while the coordinator is unreachable, as 2PC requires.
+    fn run_txn_timer(&self) {
+        loop {
+            thread::sleep(Duration::from_secs(1));
+            if let Some(raft) = &self.raft
+                && raft.leader() != Some(raft.id)
+            {
+                continue;
+            }
+            // Backups learn outcomes from their primary
+            if matches!(*self.role.lock().unwrap(), Role::Backup { .. }) {
                 continue;
             }
+            let now = Instant::now();
+            let overdue: Vec<TxnId> = self.prepared.lock().unwrap().iter_mut()
+                .filter(|(_, held)| held.deadline <= now)
+                .map(|(txn, held)| {
+                    held.deadline = now + txn::PREPARE_TIMEOUT;
+                    txn.clone()
+                })
+                .collect();
+            for txn in overdue {
+                let output_stream = message::request(RequestType::TXN_STATUS, &txn);
+                let request_type = match txn::call(&txn.coordinator, &output_stream, self.peer_key.as_ref()).and_then(|reply| Ok(Outcome::from_bytes(&reply)?)) {
+                    Ok(outcome) => match outcome {
+                        Outcome::Committed => RequestType::COMMIT,
+                        Outcome::Aborted => RequestType::ABORT,
+                        Outcome::Pending => continue,
+                    },
+                    Err(e) => {
+                        eprintln!("coordinator of transaction {} at {} is unreachable, holding its slots: {}", txn.id, txn.coordinator, e);
+                        continue;
+                    }
+                };
+                let command = message::request(request_type, &txn);
+                match &self.raft {
+                    Some(raft) => {
+                        raft.propose(command);
+                    }
+                    None => {
+                        if let Err(e) = self.execute(&command) {
+                            eprintln!("cannot finish transaction {}: {}", txn.id, e);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Applies a request that changes booking state, either a committed Raft
+    /// entry or a participant step, and returns the serialized response.
+    fn execute(&self, command: &[u8]) -> Result<Vec<u8>, DecodeError> {
+        if command.is_empty() {
+            return Ok(Vec::new());
         }
+        let mut reader = Reader::new(command);
+        Ok(match reader.get::<RequestType>()? {
+            RequestType::BOOK => self.book(reader.get::<BookRequest>()?.booking).to_bytes(),
+            RequestType::UPDATE => self.update(reader.get()?).to_bytes(),
+            RequestType::PREPARE => self.prepare(reader.get()?).to_bytes(),
+            RequestType::COMMIT => {
+                self.finish(&reader.get()?, Outcome::Committed);
+                Vec::new()
+            }
+            RequestType::ABORT => {
+                self.finish(&reader.get()?, Outcome::Aborted);
+                Vec::new()
+            }
+            // Every node skips the same entry, like one that does not decode
+            other => {
+                eprintln!("skipping a {:?} request, which does not change booking state", other);
+                Vec::new()
+            }
+        })
+    }
+
+    fn run_applier(&self, raft: &Raft) {
+        loop {
+            let (index, command) = raft.next_committed();
+            // Every node skips the same entry, so their states stay the same
+            let reply = self.execute(&command).unwrap_or_else(|e| {
+                eprintln!("skipping raft entry {} that does not decode: {}", index, e);
+                Vec::new()
+            });
+            raft.applied(index, reply);
+        }
+    }
+
+    /// The whole booking state, followed by the holds of every prepared transaction.
+    fn snapshot(&self) -> Vec<Mutation> {
+        let bookings = self.bookings.lock().unwrap();
+        let mut list: Vec<(u8, Booking)> = bookings.list.iter()
+            .map(|(confirmation_id, booking)| (*confirmation_id, booking.clone()))
+            .collect();
+        list.sort_by_key(|(confirmation_id, _)| *confirmation_id);
+        let mut state = vec![Mutation::Snapshot { next_id: bookings.next_id, bookings: list }];
+        state.extend(self.prepared.lock().unwrap().iter()
+            .map(|(txn, held)| Mutation::Hold { txn: txn.clone(), bookings: held.bookings.clone() }));
+        state
+    }
+
+    /// Forwards a mutation this primary has just applied to every backup. Called
+    /// with the facility lock held so backups see changes to a facility in order.
+    /// The role lock is released before any backup is contacted.
+    fn replicate(&self, mutation: Mutation) {
+        let backups: Vec<Arc<BackupLink>> = match &*self.role.lock().unwrap() {
+            Role::Primary { backups } => backups.clone(),
+            Role::Backup { .. } => return,
+        };
+        for backup in backups {
+            if let Err(e) = backup.send(&self.address, mutation.clone(), &|| self.snapshot()) {
+                eprintln!("backup {} is unreachable and will be sent a snapshot later: {}", backup.address, e);
+            }
+        }
+    }
 
-        let mut pos = 0;
-        let request_type: RequestType = RequestType::from(buf[pos]);
-        pos = pos + 1;
+    /// Applies a mutation received from the primary.
+    fn apply(&self, mutation: Mutation) {
+        let stride = self.shard_map.shards.len() as u8;
+        match mutation {
+            Mutation::Snapshot { next_id, bookings: list } => {
+                let mut names: Vec<&String> = self.facilities.keys().collect();
+                names.sort();
+                let mut records: HashMap<&String, std::sync::MutexGuard<FacilityRecord>> = names.into_iter()
+                    .map(|name| (name, self.facilities[name].lock().unwrap()))
+                    .collect();
+                for record in records.values_mut() {
+                    **record = FacilityRecord::new();
+                }
+                let mut bookings = self.bookings.lock().unwrap();
+                bookings.list.clear();
+                // Holds follow the snapshot as mutations of their own
+                self.prepared.lock().unwrap().clear();
+                for (confirmation_id, booking) in list {
+                    if let Some(record) = records.get_mut(&booking.facility_name) {
+                        record.is_slot_availaible(booking.day, booking.start_slot, booking.num_slots, booking.user_id);
+                    }
+                    bookings.list.insert(confirmation_id, booking);
+                }
+                bookings.next_id = next_id;
+            }
+            Mutation::Book { confirmation_id, booking } => {
+                let Some(facility) = self.facilities.get(&booking.facility_name) else {
+                    return;
+                };
+                let mut record = facility.lock().unwrap();
+                let mut bookings = self.bookings.lock().unwrap();
+                if bookings.list.contains_key(&confirmation_id) {
+                    return;
+                }
+                record.is_slot_availaible(booking.day, booking.start_slot, booking.num_slots, booking.user_id);
+                bookings.list.insert(confirmation_id, booking);
+                if bookings.next_id.is_some_and(|next_id| next_id <= confirmation_id) {
+                    bookings.next_id = confirmation_id.checked_add(stride);
+                }
+                drop(bookings);
+                let snapshot: FacilityRecord = record.clone();
+                drop(record);
+                self.notify_monitors(&snapshot);
+            }
+            Mutation::Move { confirmation_id, start_slot } => {
+                let facility_name: Option<String> = self.bookings.lock().unwrap().list
+                    .get(&confirmation_id)
+                    .map(|booking| booking.facility_name.clone());
+                let Some(facility_name) = facility_name else {
+                    return;
+                };
+                let mut record = self.facilities[&facility_name].lock().unwrap();
+                let mut bookings = self.bookings.lock().unwrap();
+                let booking: &mut Booking = bookings.list.get_mut(&confirmation_id).unwrap();
+                let offset = start_slot as i16 - booking.start_slot as i16;
+                if offset != 0 && record.update_booking(booking.day, booking.start_slot, booking.num_slots, booking.user_id, offset as i8) {
+                    booking.start_slot = start_slot;
+                }
+            }
+            Mutation::Hold { txn, bookings: held } => {
+                let mut names: Vec<String> = held.iter().map(|(_, booking)| booking.facility_name.clone()).collect();
+                names.sort();
+                names.dedup();
+                if names.iter().any(|name| !self.facilities.contains_key(name)) {
+                    return;
+                }
+                let mut records: HashMap<String, MutexGuard<FacilityRecord>> = names.into_iter()
+                    .map(|name| { let record = self.facilities[&name].lock().unwrap(); (name, record) })
+                    .collect();
+                let mut bookings = self.bookings.lock().unwrap();
+                let mut prepared = self.prepared.lock().unwrap();
+                if prepared.contains_key(&txn) {
+                    return;
+                }
+                for (confirmation_id, booking) in &held {
+                    records.get_mut(&booking.facility_name).unwrap().is_slot_availaible(booking.day, booking.start_slot, booking.num_slots, booking.user_id);
+                    if bookings.next_id.is_some_and(|next_id| next_id <= *confirmation_id) {
+                        bookings.next_id = confirmation_id.checked_add(stride);
+                    }
+                }
+                prepared.insert(txn, Prepared { bookings: held, deadline: Instant::now() + txn::PREPARE_TIMEOUT });
+            }
+            Mutation::Finish { txn, outcome } => self.finish(&txn, outcome),
+        }
+    }
+
+    /// Serves length-prefixed requests over TCP, from clients as well as from
+    /// primaries reaching their backups and other servers. Replies are written
+    /// under the connection's lock since monitor pushes share it.
+    fn serve_connection(&self, mut stream: TcpStream) {
+        let (Ok(addr), Ok(writer)) = (stream.peer_addr(), stream.try_clone()) else {
+            return;
+        };
+        if let Err(e) = stream.set_nodelay(true) {
+            eprintln!("Cannot disable Nagle's algorithm for {}: {}", addr, e);
+        }
+        let writer = Arc::new(Mutex::new(writer));
+        let peer = Peer::Tcp(addr, Arc::clone(&writer));
+        while let Ok(frame) = tcp::read_frame(&mut stream) {
+            if let Some((output_stream, delay)) = self.handle(&frame, &peer) {
+                thread::sleep(delay);
+                if tcp::write_frame(&mut writer.lock().unwrap(), &output_stream).is_err() {
+                    break;
+                }
+            }
+        }
+    }
+
+    fn run_worker(&self) {
+        let mut buf:[u8;fragment::MAX_DATAGRAM] = [0; fragment::MAX_DATAGRAM];
+        loop {
+            match self.socket.recv_from(&mut buf) {
+                Ok((num_bytes, src_addr)) => match fragment::parse(&buf[..num_bytes]) {
+                    Ok(Datagram::Fragment { message_id, index, count, payload }) => {
+                        let request: Option<Vec<u8>> = self.reassembler.lock().unwrap().add(src_addr, message_id, index, count, payload);
+                        if let Some(request) = request
+                            && let Some((output_stream, delay)) = self.handle(&request, &Peer::Udp(src_addr))
+                        {
+                            self.reply(output_stream, src_addr, message_id, delay);
+                        }
+                    }
+                    Ok(Datagram::Resend { message_id, missing }) => {
+                        for datagram in self.sent.lock().unwrap().resend(src_addr, message_id, &missing) {
+                            send_datagram(&self.socket, &datagram, src_addr);
+                        }
+                    }
+                    Err(reason) => eprintln!("Dropping {} datagram from {} ({} rejected so far)", reason, src_addr, fragment::rejected()),
+                },
+                Err(e) => {
+                    // Other I/O error
+                    eprintln!("An I/O error occurred: {}", e.kind());
+                }
+            }
+        }
+    }
+
+    /// Asks clients for request fragments that went missing, until reassembly gives up.
+    fn run_reassembly_timer(&self) {
+        loop {
+            thread::sleep(fragment::RESEND_AFTER / 2);
+            let requests = self.reassembler.lock().unwrap().poll();
+            for (addr, datagram) in requests {
+                send_datagram(&self.socket, &datagram, addr);
+            }
+        }
+    }
+
+    /// Sends a reply under the id of the request it answers, from a separate
+    /// thread when it is artificially delayed so the worker can move on to the
+    /// next request.
+    fn reply(&self, output_stream: Vec<u8>, addr: SocketAddr, message_id: u32, delay: Duration) {
+        let datagrams: Vec<Vec<u8>> = fragment::fragments(message_id, &output_stream);
+        if datagrams.len() > 1 {
+            self.sent.lock().unwrap().insert(addr, message_id, &datagrams);
+        }
+        if delay.is_zero() {
+            for datagram in &datagrams {
+                send_datagram(&self.socket, datagram, addr);
+            }
+            return;
+        }
+        let socket = match self.socket.try_clone() {
+            Ok(socket) => socket,
+            Err(e) => {
+                eprintln!("Cannot delay the reply to {}: {}", addr, e);
+                return;
+            }
+        };
+        thread::spawn(move || {
+            thread::sleep(delay);
+            for datagram in &datagrams {
+                send_datagram(&socket, datagram, addr);
+            }
+        });
+    }
+
+    /// Handles one message and returns the serialized reply together with the
+    /// delay to apply before sending it, or `None` if there is nothing to send.
+    /// The reply is in the version and format of the request; versions this
+    /// server does not speak are refused, unknown formats and bodies that do
+    /// not decode are dropped.
+    fn handle(&self, message: &[u8], peer: &Peer) -> Option<(Vec<u8>, Duration)> {
+        let (&version, input_stream) = message.split_first()?;
+        if !SUPPORTED_VERSIONS.contains(&version) {
+            eprintln!("Refusing a version {} request from {}", version, peer.addr());
+            let mut output_stream = Writer::versioned(PROTOCOL_VERSION, Format::Binary);
+            output_stream.put(&ReplyStatus::UnsupportedVersion { min: *SUPPORTED_VERSIONS.start(), max: *SUPPORTED_VERSIONS.end() });
+            return Some((output_stream.into_bytes(), Duration::ZERO));
+        }
+        let (format, input_stream) = match input_stream.split_first() {
+            Some((&byte, input_stream)) if version >= FORMAT_BYTE => match Format::from_byte(byte) {
+                Some(format) => (format, input_stream),
+                None => {
+                    eprintln!("Dropping a request in unknown format {} from {}", byte, peer.addr());
+                    return None;
+                }
+            },
+            _ => (Format::Binary, input_stream),
+        };
+        // With credentials, requests only servers make must end with a MAC under the secret
+        let input_stream = match (&self.peer_key, Reader::with_format(input_stream, version, format).get::<RequestType>()) {
+            (Some(key), Ok(request_type)) if is_peer_request(request_type) => {
+                if let Err(reason) = key.verify(message, &mut self.peer_requests.lock().unwrap()) {
+                    eprintln!("Dropping a {:?} request from {}: {}", request_type, peer.addr(), reason);
+                    return None;
+                }
+                &input_stream[..input_stream.len() - PEER_MAC_LEN]
+            }
+            _ => input_stream,
+        };
+        let (reply, delay) = match self.handle_request(input_stream, peer, version, format) {
+            Ok(handled) => handled?,
+            Err(e) => {
+                eprintln!("Dropping a {:?} request from {} that does not decode: {}", format, peer.addr(), e);
+                return None;
+            }
+        };
+        let mut output_stream = Writer::versioned(version, format);
+        output_stream.put_bytes(&reply);
+        Some((output_stream.into_bytes(), delay))
+    }
+
+    fn handle_request(&self, input_stream: &[u8], peer: &Peer, version: u8, format: Format) -> Result<Option<(Vec<u8>, Duration)>, DecodeError> {
+        let mut reader = Reader::with_format(input_stream, version, format);
+        let request_type: RequestType = reader.get()?;
+        // Backups only take changes from the primary, clients are sent there
+        if let Role::Backup { primary } = &*self.role.lock().unwrap()
+            && !matches!(request_type, RequestType::REPLICATE | RequestType::PROMOTE | RequestType::SHARD_MAP | RequestType::MONITOR | RequestType::TXN_STATUS | RequestType::LOGIN)
+        {
+            return Ok(Some((ReplyStatus::NotLeader { leader: primary.clone() }.to_bytes_as(version, format), Duration::ZERO)));
+        }
+        if let Some(raft) = &self.raft
+            && !matches!(request_type, RequestType::RAFT | RequestType::SHARD_MAP | RequestType::MONITOR | RequestType::TXN_STATUS | RequestType::LOGIN)
+        {
+            match raft.leader() {
+                Some(leader) if leader == raft.id => {}
+                Some(leader) => match raft.peers.get(leader) {
+                    Some(address) => return Ok(Some((ReplyStatus::NotLeader { leader: address.clone() }.to_bytes_as(version, format), Duration::ZERO))),
+                    None => return Ok(None),
+                },
+                // Mid-election: stay silent and let the client's resend find the new leader
+                None => return Ok(None),
+            }
+        }
+        let mut output_stream = Writer::with_format(version, format);
+        output_stream.put(&ReplyStatus::Ok);
+        let mut delay = Duration::ZERO;
         match request_type {
             RequestType::QUERY => {
-                let req1: QueryRequest = QueryRequest::deserialize(&buf[..bytes], &mut pos);
+                let req1: QueryRequest = reader.get()?;
+                if let Some(redirect) = self.redirect(self.shard_map.owner(&req1.name), version, format) {
+                    return Ok(Some(redirect));
+                }
                 let mut availaiblilty: Vec<u8> = Vec::new();
-                let facility = all_facilities[&req1.name]; // handle error
-                for day in req1.days {
-                    availaiblilty.append(&mut facility[day].to_vec());
+                // An unknown facility is answered with no availability
+                let mut error = ErrorCode::FacilityNotFound;
+                // The reply counts its slots with a u16
+                if req1.days.len() * FacilityRecord::SLOTS_PER_DAY as usize > u16::MAX as usize {
+                    error = ErrorCode::InvalidRequest;
+                } else if let Some(facility) = self.facilities.get(&req1.name) {
+                    let facility = facility.lock().unwrap();
+                    for day in req1.days {
+                        availaiblilty.append(&mut facility.day_slots(day).to_vec());
+                    }
+                    error = ErrorCode::None;
+                    delay = self.query_delay;
                 }
-                let mut query_response: QueryResponse = QueryResponse { name: req1.name, availaible: availaiblilty };
-                let mut output_stream: Vec<u8> = Vec::new();
-                query_response.serialize(&mut output_stream);
-                thread::sleep(Duration::from_secs(10));
-                socket.send_to(&output_stream, addr).unwrap();
+                let query_response: QueryResponse = QueryResponse { name: req1.name, error, availaible: availaiblilty };
+                output_stream.put(&query_response);
             }
 
             RequestType::BOOK => {
-                let booking: Booking = Booking::deserialize(&buf[..bytes], &mut pos);
-                let facility = all_facilities.get_mut(&booking.facility_name);
-                match facility {
-                    Some(record) => {
-                        let booking_status = record.is_slot_availaible(booking.day, booking.start_slot, booking.num_slots, booking.user_id);
-                        match booking_status {
-                            true => {
-                                booking_counter += 1;
-                                booking_list.insert(booking_counter, booking);
-                                let booking_response: BookingResponse = BookingResponse { success: true, message: "Booking Successful".to_string(), confirmation_id: booking_counter };
-                                let mut output_stream: Vec<u8> = Vec::new();
-                                booking_response.serialize(&mut output_stream);
-                                thread::sleep(Duration::from_secs(10));
-                                socket.send_to(&output_stream, addr).unwrap();
-
-                                output_stream = Vec::new();
-                                record.serialize(&mut output_stream);
-                                for addr in &monitoring_clients {
-                                    socket.send_to(&output_stream, addr).unwrap();
-                                }
-                            },
-                            false => {
-                                println!("error in booking already booked");
-                                let booking_response: BookingResponse = BookingResponse { success: false, message: "Booking Failed, Slots not availaible".to_string(), confirmation_id: 0 };
-                                let mut output_stream: Vec<u8> = Vec::new();
-                                booking_response.serialize(&mut output_stream);
-                                socket.send_to(&output_stream, addr).unwrap();
-                            }
-                        }
+                let book_request: BookRequest = reader.get()?;
+                if let Some(redirect) = self.redirect(self.shard_map.owner(&book_request.booking.facility_name), version, format) {
+                    return Ok(Some(redirect));
+                }
+                let booking_response: BookingResponse = match (self.authenticate_as(book_request.auth.as_ref(), [book_request.booking.user_id]), &self.raft) {
+                    (Err(reason), _) => BookingResponse { success: false, error: ErrorCode::Unauthenticated, message: format!("Booking Failed, {}", reason), confirmation_id: 0 },
+                    // Logged in this build's version whatever the client's, execute() reads it in that one
+                    (Ok(()), Some(raft)) => match raft.propose(message::request(request_type, &book_request)) {
+                        Some(reply) => BookingResponse::from_bytes(&reply)?,
+                        None => return Ok(None),
                     },
-                    None => {
-                        // return error message
-                         let booking_response: BookingResponse = BookingResponse { success: false, message: "Booking Failed, Facility not availaible".to_string(), confirmation_id: 0 };
-                        let mut output_stream: Vec<u8> = Vec::new();
-                        booking_response.serialize(&mut output_stream);
-                        socket.send_to(&output_stream, addr).unwrap();
+                    (Ok(()), None) => self.book(book_request.booking),
+                };
+                if booking_response.success {
+                    delay = self.book_delay;
+                }
+                output_stream.put(&booking_response);
+            }
+
+            RequestType::UPDATE => {
+                let update_request: Update = reader.get()?;
+                if let Some(redirect) = self.redirect(self.shard_map.confirmation_owner(update_request.confirmation_id), version, format) {
+                    return Ok(Some(redirect));
+                }
+                let update_response: UpdateResponse = match (self.authenticate(update_request.auth.as_ref()), &self.raft) {
+                    (Err(reason), _) => UpdateResponse { success: false, error: ErrorCode::Unauthenticated, message: format!("Update Failed, {}", reason) },
+                    (Ok(_), Some(raft)) => match raft.propose(message::request(request_type, &update_request)) {
+                        Some(reply) => UpdateResponse::from_bytes(&reply)?,
+                        None => return Ok(None),
+                    },
+                    (Ok(_), None) => self.update(update_request),
+                };
+                if update_response.success {
+                    delay = self.update_delay;
+                }
+                output_stream.put(&update_response);
+            }
+
+            RequestType::MONITOR => {
+                let monitor_request: Monitor = reader.get()?;
+                self.monitoring_clients.lock().unwrap().push((peer.clone(), version, format));
+                println!("monitoring {:?} for duration {}", peer.addr(), monitor_request.duration);
+                return Ok(None);
+            }
+
+            RequestType::GET_BOOKING => {
+                let get_booking: GetBooking = reader.get()?;
+                if let Some(redirect) = self.redirect(self.shard_map.confirmation_owner(get_booking.confirmation_id), version, format) {
+                    return Ok(Some(redirect));
+                }
+                let response: GetBookingResponse = match self.bookings.lock().unwrap().list.get(&get_booking.confirmation_id) {
+                    Some(booking) => GetBookingResponse { success: true, error: ErrorCode::None, message: "Booking found".to_string(), booking: Some(booking.clone()) },
+                    None => GetBookingResponse { success: false, error: ErrorCode::BookingNotFound, message: "No such booking made".to_string(), booking: None },
+                };
+                output_stream.put(&response);
+            }
+
+            RequestType::LIST_BOOKINGS => {
+                let list_bookings: ListBookings = reader.get()?;
+                let bookings: Vec<(u8, Booking)> = user_bookings(&self.bookings.lock().unwrap().list, list_bookings.user_id);
+                let response: ListBookingsResponse = ListBookingsResponse { bookings };
+                output_stream.put(&response);
+            }
+
+            RequestType::EXPORT => {
+                let export: Export = reader.get()?;
+                if let Export::Facility(name) = &export
+                    && let Some(redirect) = self.redirect(self.shard_map.owner(name), version, format)
+                {
+                    return Ok(Some(redirect));
+                }
+                let response: ExportResponse = match export {
+                    Export::Facility(name) => match self.facilities.get(&name) {
+                        Some(record) => {
+                            let calendar = ical::facility_calendar(&name, &record.lock().unwrap());
+                            ExportResponse { success: true, error: ErrorCode::None, message: "Export Successful".to_string(), calendar }
+                        },
+                        None => ExportResponse { success: false, error: ErrorCode::FacilityNotFound, message: "Export Failed, Facility not availaible".to_string(), calendar: String::new() },
+                    },
+                    Export::User(user_id) => {
+                        let bookings: Vec<(u8, Booking)> = user_bookings(&self.bookings.lock().unwrap().list, user_id);
+                        ExportResponse { success: true, error: ErrorCode::None, message: "Export Successful".to_string(), calendar: ical::bookings_calendar(&bookings) }
                     }
+                };
+                output_stream.put(&response);
+            }
+
+            RequestType::STATS => {
+                let mut facilities: Vec<FacilityStats> = self.facilities.iter()
+                    .map(|(name, record)| FacilityStats::from_record(name, &record.lock().unwrap()))
+                    .collect();
+                facilities.sort_by(|a, b| a.name.cmp(&b.name));
+                let mut per_user: BTreeMap<u8, u32> = BTreeMap::new();
+                for booking in self.bookings.lock().unwrap().list.values() {
+                    *per_user.entry(booking.user_id).or_insert(0) += 1;
                 }
+                // Saturated to the u8 of the wire, which a shard's at most 255 bookings never reach
+                let users: Vec<(u8, u8)> = per_user.into_iter().map(|(user_id, count)| (user_id, count.min(u8::MAX as u32) as u8)).collect();
+                let response: StatsResponse = StatsResponse { facilities, users };
+                output_stream.put(&response);
+            }
 
+            RequestType::SHARD_MAP => {
+                output_stream.put(&self.shard_map);
             }
-            RequestType::UPDATE => {
-                let update_request: Update = Update::deserialize(&buf[..bytes], &mut pos);
-                let booking: Option<&mut Booking> = booking_list.get_mut(&update_request.confirmation_id);
-                match booking {
-                    Some(booking) => {
-                        let facility = all_facilities.get_mut(&booking.facility_name).unwrap();
-                        let update_status: bool = facility.update_booking(booking.day, booking.start_slot, booking.num_slots, booking.user_id, update_request.offset);
-                        match update_status {
-                            true => {
-                                let new_start = booking.start_slot as i8 + update_request.offset;
-
-                                booking.start_slot = new_start as u8; // check for subtraction
-                                let update_response: UpdateResponse = UpdateResponse { success: true, message: "Booking updated".to_string() };
-                                let mut output_stream: Vec<u8> = Vec::new();
-                                update_response.serialize(&mut output_stream);
-                                thread::sleep(Duration::from_secs(10));
-                                socket.send_to(&output_stream, addr).unwrap();
-
-                            },
-                            false => {
-                                //return error
-                                let update_response: UpdateResponse = UpdateResponse { success: false, message: "Update Failed, Slot not availaible".to_string() };
-                                let mut output_stream: Vec<u8> = Vec::new();
-                                update_response.serialize(&mut output_stream);
-                                socket.send_to(&output_stream, addr).unwrap();
-                            }
-                        }
+
+            RequestType::REPLICATE => {
+                let replicate: Replicate = reader.get()?;
+                let mut role = self.role.lock().unwrap();
+                match &mut *role {
+                    Role::Backup { primary } => *primary = replicate.primary,
+                    // Two primaries: keep serving and let the sender drop us as a backup
+                    Role::Primary { .. } => {
+                        return Ok(Some((ReplyStatus::NotLeader { leader: self.address.clone() }.to_bytes_as(version, format), Duration::ZERO)));
+                    }
+                }
+                drop(role);
+                self.apply(replicate.mutation);
+            }
+
+            RequestType::PROMOTE => {
+                let promote: Promote = reader.get()?;
+                println!("promoted to primary, replicating to {:?}", promote.backups);
+                *self.role.lock().unwrap() = Role::Primary { backups: promote.backups.iter().map(|address| BackupLink::new(address, self.peer_key.clone())).collect() };
+                // Bring the backups in line with this server straight away
+                for mutation in self.snapshot() {
+                    self.replicate(mutation);
+                }
+            }
+
+            RequestType::MULTI_BOOK => {
+                let request: MultiBook = reader.get()?;
+                let response: MultiBookResponse = match self.authenticate_as(request.auth.as_ref(), request.bookings.iter().map(|booking| booking.user_id)) {
+                    Err(reason) => MultiBookResponse { success: false, error: ErrorCode::Unauthenticated, message: format!("Booking Failed, {}", reason), confirmation_ids: Vec::new() },
+                    Ok(()) => self.multi_book(request),
+                };
+                if response.success {
+                    delay = self.book_delay;
+                }
+                output_stream.put(&response);
+            }
+
+            RequestType::PREPARE | RequestType::COMMIT | RequestType::ABORT => {
+                // Participant steps change booking state, so under Raft they go through the log like BOOK
+                let command: Vec<u8> = match request_type {
+                    RequestType::PREPARE => message::request(request_type, &reader.get::<Prepare>()?),
+                    _ => message::request(request_type, &reader.get::<TxnId>()?),
+                };
+                let reply: Vec<u8> = match &self.raft {
+                    Some(raft) => match raft.propose(command) {
+                        Some(reply) => reply,
+                        None => return Ok(None),
                     },
-                    None => {
-                        // send error
-                        let update_response: UpdateResponse = UpdateResponse { success: false, message: "Update Failed, no such booking made".to_string() };
-                        let mut output_stream: Vec<u8> = Vec::new();
-                        update_response.serialize(&mut output_stream);
-                        socket.send_to(&output_stream, addr).unwrap();
+                    None => self.execute(&command)?,
+                };
+                if let RequestType::PREPARE = request_type {
+                    output_stream.put(&PrepareResponse::from_bytes(&reply)?);
+                }
+            }
+
+            RequestType::TXN_STATUS => {
+                // Answered by the coordinator itself, whatever its role, as only it has the log
+                let txn: TxnId = reader.get()?;
+                output_stream.put(&self.coordinator.lock().unwrap().status(txn.id));
+            }
+
+            RequestType::RAFT => {
+                let Some(raft) = &self.raft else {
+                    return Ok(None);
+                };
+                match raft.handle(reader.get()?) {
+                    Some(reply) => output_stream.put(&reply),
+                    None => return Ok(None),
+                };
+            }
+
+            RequestType::LOGIN => {
+                let login: Login = reader.get()?;
+                let refuse = |error: ErrorCode, message: &str| LoginResponse { success: false, error, message: message.to_string(), token: None };
+                let response: LoginResponse = match &self.users {
+                    None => refuse(ErrorCode::InvalidRequest, "Login Failed, this server takes requests without logging in"),
+                    Some(_) if !self.may_try_login(peer.addr().ip()) => refuse(ErrorCode::Unauthenticated, "Login Failed, too many attempts, try again in a second"),
+                    Some(users) => match users.login(login.user_id, &login.password) {
+                        Some(token) => LoginResponse { success: true, error: ErrorCode::None, message: "Login Successful".to_string(), token: Some(token) },
+                        None => refuse(ErrorCode::Unauthenticated, "Login Failed, unknown user or wrong password"),
+                    },
+                };
+                output_stream.put(&response);
+            }
+        }
+        Ok(Some((output_stream.into_bytes(), delay)))
+    }
+
+    /// Whether `ip` may LOGIN now, counting this attempt. Each one takes the
+    /// worker through every PBKDF2 round, so an address gets one per LOGIN_INTERVAL.
+    fn may_try_login(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut attempts = self.login_attempts.lock().unwrap();
+        attempts.retain(|_, last| now.duration_since(*last) < LOGIN_INTERVAL);
+        if attempts.contains_key(&ip) {
+            return false;
+        }
+        attempts.insert(ip, now);
+        true
+    }
+
+    /// Pushes the record to every monitoring client, as message 0 over UDP
+    /// since it answers no request. Clients whose connection has closed are
+    /// forgotten.
+    fn notify_monitors(&self, record: &FacilityRecord) {
+        self.monitoring_clients.lock().unwrap().retain(|(peer, version, format)| {
+            let mut output_stream = Writer::versioned(*version, *format);
+            output_stream.put(record);
+            match peer {
+                Peer::Udp(addr) => {
+                    for datagram in fragment::fragments(0, &output_stream.into_bytes()) {
+                        send_datagram(&self.socket, &datagram, *addr);
                     }
+                    true
                 }
+                Peer::Tcp(_, stream) => tcp::write_frame(&mut stream.lock().unwrap(), &output_stream.into_bytes()).is_ok(),
             }
-            RequestType::MONITOR => {
-                let monitor_request: Monitor = Monitor::deserialize(&buf[..bytes], &mut pos);
-                monitoring_clients.push(addr);
-                println!("monitoring {:?} for duration {}", addr, monitor_request.duration);
+        });
+    }
+}
+
+fn main() {
+    let cli = Cli::parse();
+    if let (Some(path), Some(user_id), Some(password)) = (&cli.users, cli.add_user, &cli.password) {
+        Users::add(path, user_id, password, cli.admin).unwrap_or_else(|e| panic!("{}", e));
+        println!("added user {} to {}", user_id, path);
+        return;
+    }
+    let users: Option<Users> = cli.users.as_deref().map(|path| Users::load(path).unwrap_or_else(|e| panic!("{}", e)));
+    let peer_key: Option<PeerKey> = users.as_ref().map(Users::peer_key);
+
+    let shard_map: ShardMap = match &cli.shard_map {
+        Some(path) => ShardMap::load(path).unwrap_or_else(|e| panic!("{}", e)),
+        None => ShardMap::single(cli.address.as_deref().unwrap_or("127.0.0.1:5000")),
+    };
+    if cli.shard >= shard_map.shards.len() {
+        panic!("Shard {} is not in the shard map", cli.shard);
+    }
+    let address: String = cli.address.clone().unwrap_or_else(|| shard_map.shards[cli.shard].clone());
+
+    let mut all_facilities: HashMap<String, Mutex<FacilityRecord>> = HashMap::new();
+    for name in cli.facilities.iter().filter(|name| shard_map.owner(name) == cli.shard) {
+        all_facilities.insert(name.clone(), Mutex::new(FacilityRecord::new()));
+    }
+    let socket: UdpSocket = UdpSocket::bind(&address).unwrap();
+
+    let mut facility_names: Vec<&String> = all_facilities.keys().collect();
+    facility_names.sort();
+    println!("server running on {} with {} workers, shard {} of {} owning {:?}",
+        &address, cli.workers, cli.shard, shard_map.shards.len(), facility_names);
+
+    let role: Role = match &cli.backup_of {
+        Some(primary) => Role::Backup { primary: primary.clone() },
+        None => Role::Primary { backups: cli.backups.iter().map(|address| BackupLink::new(address, peer_key.clone())).collect() },
+    };
+    match &role {
+        Role::Primary { backups } if !backups.is_empty() => println!("primary replicating to {:?}", cli.backups),
+        Role::Backup { primary } => println!("backup of {}", primary),
+        _ => {}
+    }
+    if let Some(path) = &cli.users {
+        println!("mutating requests need a token of a user in {}, requests between servers a MAC under its secret", path);
+    }
+    let raft: Option<Arc<Raft>> = if cli.raft_peers.is_empty() {
+        None
+    } else {
+        let id = cli.raft_peers.iter().position(|peer| *peer == address)
+            .unwrap_or_else(|| panic!("{} is not one of the raft peers {:?}", address, cli.raft_peers));
+        println!("raft node {} of {:?}", id, cli.raft_peers);
+        Some(Arc::new(Raft::new(id, cli.raft_peers.clone(), cli.raft_state.as_deref(), peer_key.clone()).unwrap_or_else(|e| panic!("{}", e))))
+    };
+    let (coordinator, in_doubt) = Coordinator::open(&address, cli.txn_log.as_deref()).unwrap_or_else(|e| panic!("{}", e));
+    let listener: TcpListener = TcpListener::bind(&address).unwrap();
+
+    let server = Arc::new(Server {
+        socket,
+        address,
+        role: Mutex::new(role),
+        raft,
+        bookings: Mutex::new(Bookings { next_id: Some(cli.shard as u8 + 1), list: HashMap::new() }),
+        prepared: Mutex::new(HashMap::new()),
+        coordinator: Mutex::new(coordinator),
+        crash_point: cli.crash_point,
+        users,
+        peer_key,
+        peer_requests: Mutex::new(SeenRequests::default()),
+        login_attempts: Mutex::new(HashMap::new()),
+        shard: cli.shard,
+        shard_map,
+        facilities: all_facilities,
+        monitoring_clients: Mutex::new(Vec::new()),
+        reassembler: Mutex::new(Reassembler::default()),
+        sent: Mutex::new(SentCache::default()),
+        query_delay: Duration::from_millis(cli.query_delay_ms),
+        book_delay: Duration::from_millis(cli.book_delay_ms),
+        update_delay: Duration::from_millis(cli.update_delay_ms),
+    });
+
+    let replication_server = Arc::clone(&server);
+    thread::spawn(move || {
+        for stream in listener.incoming().flatten() {
+            let server = Arc::clone(&replication_server);
+            thread::spawn(move || server.serve_connection(stream));
+        }
+    });
+
+    let reassembly_server = Arc::clone(&server);
+    thread::spawn(move || reassembly_server.run_reassembly_timer());
+    let recovery_server = Arc::clone(&server);
+    thread::spawn(move || recovery_server.recover(in_doubt));
+    let txn_server = Arc::clone(&server);
+    thread::spawn(move || txn_server.run_txn_timer());
+
+    if let Some(raft) = &server.raft {
+        raft.start();
+        let server = Arc::clone(&server);
+        let raft = Arc::clone(raft);
+        thread::spawn(move || server.run_applier(&raft));
+    }
+
+    let workers: Vec<thread::JoinHandle<()>> = (0..cli.workers.max(1)).map(|_| {
+        let server = Arc::clone(&server);
+        thread::spawn(move || server.run_worker())
+    }).collect();
+    for worker in workers {
+        worker.join().unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use message::Day;
+    use testing::TempFile;
+
+    /// Credentials of user 1, user 2 and admin 3. Tokens are checked before
+    /// `update` is called, so the keys never matter here.
+    fn users() -> Users {
+        let user = |id: u8, role: &str| format!("user {} {} {}{}\n", id, auth::to_hex(&[id; 16]), auth::to_hex(&[id; 32]), role);
+        Users::parse(&format!("secret {}\n{}{}{}", auth::to_hex(&[9; 32]), user(1, ""), user(2, ""), user(3, " admin")), "users.conf").unwrap()
+    }
+
+    /// A lone primary owning facility1, with no delays and nothing on disk.
+    fn server(users: Option<Users>) -> Server {
+        server_on(UdpSocket::bind("127.0.0.1:0").unwrap(), users)
+    }
+
+    fn server_on(socket: UdpSocket, users: Option<Users>) -> Server {
+        let address = socket.local_addr().unwrap().to_string();
+        let (coordinator, _) = Coordinator::open(&address, None).unwrap();
+        Server {
+            socket,
+            role: Mutex::new(Role::Primary { backups: Vec::new() }),
+            raft: None,
+            shard_map: ShardMap::single(&address),
+            shard: 0,
+            facilities: HashMap::from([("facility1".to_string(), Mutex::new(FacilityRecord::new()))]),
+            bookings: Mutex::new(Bookings { next_id: Some(1), list: HashMap::new() }),
+            prepared: Mutex::new(HashMap::new()),
+            coordinator: Mutex::new(coordinator),
+            crash_point: None,
+            peer_key: users.as_ref().map(Users::peer_key),
+            peer_requests: Mutex::new(SeenRequests::default()),
+            login_attempts: Mutex::new(HashMap::new()),
+            users,
+            monitoring_clients: Mutex::new(Vec::new()),
+            reassembler: Mutex::new(Reassembler::default()),
+            sent: Mutex::new(SentCache::default()),
+            query_delay: Duration::ZERO,
+            book_delay: Duration::ZERO,
+            update_delay: Duration::ZERO,
+            address,
+        }
+    }
+
+    /// Like `server`, also serving TCP on its address, as other servers reach it.
+    fn listening() -> Arc<Server> {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let server = Arc::new(server_on(UdpSocket::bind(listener.local_addr().unwrap()).unwrap(), None));
+        let serving = Arc::clone(&server);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let server = Arc::clone(&serving);
+                thread::spawn(move || server.serve_connection(stream));
             }
+        });
+        server
+    }
+
+    fn token(user_id: u8) -> Token {
+        Token { user_id, expires: 0, mac: [0; 32] }
+    }
+
+    /// Books two slots of facility1 on Monday for user 1 and returns the confirmation id.
+    fn booked(server: &Server) -> u8 {
+        let response = server.book(Booking { facility_name: "facility1".to_string(), day: Day::Monday, start_slot: 2, num_slots: 2, user_id: 1 });
+        assert!(response.success, "{:?}", response);
+        response.confirmation_id
+    }
+
+    fn update(server: &Server, auth: Option<Token>, confirmation_id: u8) -> UpdateResponse {
+        server.update(Update { auth, confirmation_id, offset: 1 })
+    }
+
+    fn start_slot(server: &Server, confirmation_id: u8) -> u8 {
+        server.bookings.lock().unwrap().list[&confirmation_id].start_slot
+    }
+
+    /// The reply status `server` answers `request` with.
+    fn status(server: &Server, request: &[u8]) -> ReplyStatus {
+        let peer = Peer::Udp("127.0.0.1:9".parse().unwrap());
+        let (reply, _) = server.handle_request(request, &peer, PROTOCOL_VERSION, Format::Binary).unwrap().unwrap();
+        Reader::with_format(&reply, PROTOCOL_VERSION, Format::Binary).get().unwrap()
+    }
+
+    #[test]
+    fn logins_are_throttled_per_address() {
+        let server = server(Some(users()));
+        let login = |addr: &str| {
+            let peer = Peer::Udp(addr.parse().unwrap());
+            // An unknown user, so no key is derived and the attempts come within LOGIN_INTERVAL
+            let request = message::request(RequestType::LOGIN, &Login { user_id: 9, password: "wrong".to_string() });
+            let (reply, _) = server.handle_request(&request, &peer, PROTOCOL_VERSION, Format::Binary).unwrap().unwrap();
+            let mut reader = Reader::with_format(&reply, PROTOCOL_VERSION, Format::Binary);
+            assert_eq!(reader.get::<ReplyStatus>().unwrap(), ReplyStatus::Ok);
+            reader.get::<LoginResponse>().unwrap().message
+        };
+        assert_eq!(login("127.0.0.1:9"), "Login Failed, unknown user or wrong password");
+        // Another port of the same host counts as the same address
+        assert_eq!(login("127.0.0.1:10"), "Login Failed, too many attempts, try again in a second");
+        assert_eq!(login("127.0.0.2:9"), "Login Failed, unknown user or wrong password");
+        thread::sleep(LOGIN_INTERVAL);
+        assert_eq!(login("127.0.0.1:9"), "Login Failed, unknown user or wrong password");
+    }
+
+    #[test]
+    fn peer_requests_are_accepted_once() {
+        let server = server(Some(users()));
+        let peer = Peer::Udp("127.0.0.1:9".parse().unwrap());
+        let request = || message::versioned(&message::request(RequestType::TXN_STATUS, &txn(&server.address, 1)));
+        assert!(server.handle(&request(), &peer).is_none());
+        let signed = auth::sign_request(server.peer_key.as_ref(), request());
+        assert!(server.handle(&signed, &peer).is_some());
+        assert!(server.handle(&signed, &peer).is_none());
+        assert!(server.handle(&auth::sign_request(server.peer_key.as_ref(), request()), &peer).is_some());
+    }
+
+    #[test]
+    fn requests_for_other_shards_are_redirected() {
+        let mut server = server(None);
+        let other = "127.0.0.1:1".to_string();
+        server.shard_map = ShardMap::new(vec![server.address.clone(), other.clone()], vec![("facility1".to_string(), 0), ("gym".to_string(), 1)]);
+        let wrong_shard = ReplyStatus::WrongShard { owner: other };
+        let query = |name: &str| message::request(RequestType::QUERY, &QueryRequest { name: name.to_string(), days: vec![Day::Monday] });
+        assert_eq!(status(&server, &query("gym")), wrong_shard);
+        assert_eq!(status(&server, &query("facility1")), ReplyStatus::Ok);
+        let book = BookRequest { auth: None, booking: Booking { facility_name: "gym".to_string(), day: Day::Monday, start_slot: 2, num_slots: 2, user_id: 1 } };
+        assert_eq!(status(&server, &message::request(RequestType::BOOK, &book)), wrong_shard);
+        // Shard 1 of 2 hands out the even confirmation ids
+        let update = |confirmation_id: u8| message::request(RequestType::UPDATE, &Update { auth: None, confirmation_id, offset: 1 });
+        assert_eq!(status(&server, &update(2)), wrong_shard);
+        assert_eq!(status(&server, &message::request(RequestType::GET_BOOKING, &GetBooking { confirmation_id: 4 })), wrong_shard);
+        assert_eq!(status(&server, &update(3)), ReplyStatus::Ok);
+        assert!(server.bookings.lock().unwrap().list.is_empty());
+    }
+
+    #[test]
+    fn queries_must_fit_the_reply() {
+        let server = server(None);
+        let peer = Peer::Udp("127.0.0.1:9".parse().unwrap());
+        let query = |days: usize| {
+            let request = message::request(RequestType::QUERY, &QueryRequest { name: "facility1".to_string(), days: vec![Day::Monday; days] });
+            let (reply, _) = server.handle_request(&request, &peer, PROTOCOL_VERSION, Format::Binary).unwrap().unwrap();
+            let mut reader = Reader::with_format(&reply, PROTOCOL_VERSION, Format::Binary);
+            assert_eq!(reader.get::<ReplyStatus>(), Ok(ReplyStatus::Ok));
+            reader.get::<QueryResponse>().unwrap()
+        };
+        let response = query(4095);
+        assert_eq!((response.error, response.availaible.len()), (ErrorCode::None, 4095 * 16));
+        let response = query(4096);
+        assert_eq!((response.error, response.availaible.len()), (ErrorCode::InvalidRequest, 0));
+    }
+
+    fn monday(server: &Server) -> [u8; 16] {
+        server.facilities["facility1"].lock().unwrap().day_slots(Day::Monday)
+    }
+
+    fn monday_booking(start_slot: u8, user_id: u8) -> Booking {
+        Booking { facility_name: "facility1".to_string(), day: Day::Monday, start_slot, num_slots: 2, user_id }
+    }
+
+    #[test]
+    fn snapshots_replace_the_booking_state() {
+        let server = server(None);
+        booked(&server);
+        server.apply(Mutation::Snapshot { next_id: Some(5), bookings: vec![(4, monday_booking(6, 2))] });
+        let bookings = server.bookings.lock().unwrap();
+        assert_eq!(bookings.next_id, Some(5));
+        assert_eq!(bookings.list.keys().collect::<Vec<&u8>>(), [&4]);
+        drop(bookings);
+        assert_eq!(monday(&server), [0, 0, 0, 0, 0, 0, 2, 2, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn replayed_mutations_change_nothing() {
+        let server = server(None);
+        server.apply(Mutation::Snapshot { next_id: Some(2), bookings: vec![(1, monday_booking(0, 1))] });
+        // Resent by a primary that did not hear the first reply
+        for _ in 0..2 {
+            server.apply(Mutation::Book { confirmation_id: 1, booking: monday_booking(0, 1) });
+            server.apply(Mutation::Book { confirmation_id: 2, booking: monday_booking(4, 2) });
+            server.apply(Mutation::Move { confirmation_id: 2, start_slot: 5 });
+        }
+        assert_eq!(server.bookings.lock().unwrap().next_id, Some(3));
+        assert_eq!(start_slot(&server, 2), 5);
+        assert_eq!(monday(&server), [1, 1, 0, 0, 0, 2, 2, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        let txn = TxnId { coordinator: "127.0.0.1:1".to_string(), id: 7 };
+        for _ in 0..2 {
+            server.apply(Mutation::Hold { txn: txn.clone(), bookings: vec![(3, monday_booking(8, 3))] });
         }
+        assert_eq!(server.bookings.lock().unwrap().next_id, Some(4));
+        for _ in 0..2 {
+            server.apply(Mutation::Finish { txn: txn.clone(), outcome: Outcome::Committed });
+        }
+        assert!(server.prepared.lock().unwrap().is_empty());
+        assert_eq!(server.bookings.lock().unwrap().list.len(), 3);
+        assert_eq!(monday(&server), [1, 1, 0, 0, 0, 2, 2, 0, 3, 3, 0, 0, 0, 0, 0, 0]);
+    }
+
+    fn txn(coordinator: &str, id: u32) -> TxnId {
+        TxnId { coordinator: coordinator.to_string(), id }
+    }
+
+    fn prepare(server: &Server, txn: &TxnId, bookings: Vec<Booking>) -> PrepareResponse {
+        server.prepare(Prepare { txn: txn.clone(), bookings })
+    }
+
+    #[test]
+    fn prepared_slots_are_held_until_committed() {
+        let server = server(None);
+        let first = txn("127.0.0.1:1", 1);
+        let vote = prepare(&server, &first, vec![monday_booking(0, 1), monday_booking(2, 1)]);
+        assert!(vote.vote, "{:?}", vote);
+        assert_eq!(vote.confirmation_ids, [1, 2]);
+        // A resent prepare is answered with the same ids
+        assert_eq!(prepare(&server, &first, vec![monday_booking(0, 1), monday_booking(2, 1)]).confirmation_ids, [1, 2]);
+        assert!(server.bookings.lock().unwrap().list.is_empty());
+        assert_eq!(monday(&server), [1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        // Held slots are taken for other transactions and bookings alike
+        let vote = prepare(&server, &txn("127.0.0.1:1", 2), vec![monday_booking(6, 2), monday_booking(3, 2)]);
+        assert!(!vote.vote);
+        assert_eq!(vote.error, ErrorCode::SlotTaken);
+        assert!(!server.book(monday_booking(1, 2)).success);
+        assert_eq!(monday(&server), [1, 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        server.finish(&first, Outcome::Committed);
+        assert!(server.prepared.lock().unwrap().is_empty());
+        assert_eq!(server.bookings.lock().unwrap().list[&2], monday_booking(2, 1));
+        assert_eq!(server.book(monday_booking(4, 2)).confirmation_id, 3);
+    }
+
+    #[test]
+    fn aborted_holds_are_released() {
+        let server = server(None);
+        let aborted = txn("127.0.0.1:1", 1);
+        assert!(prepare(&server, &aborted, vec![monday_booking(0, 1), monday_booking(4, 1)]).vote);
+        server.finish(&aborted, Outcome::Aborted);
+        assert!(server.prepared.lock().unwrap().is_empty());
+        assert!(server.bookings.lock().unwrap().list.is_empty());
+        assert_eq!(monday(&server), [0; 16]);
+        // A late commit of a finished transaction changes nothing
+        server.finish(&aborted, Outcome::Committed);
+        assert!(server.bookings.lock().unwrap().list.is_empty());
+        // The reserved ids are not handed out again
+        assert_eq!(server.bookings.lock().unwrap().next_id, Some(3));
+    }
+
+    #[test]
+    fn multi_book_needs_a_transaction_log() {
+        let server = listening();
+        let request = || MultiBook { auth: None, bookings: vec![monday_booking(0, 1), monday_booking(4, 1)] };
+        let response = server.multi_book(request());
+        assert_eq!((response.success, response.error), (false, ErrorCode::InvalidRequest));
+        assert_eq!(monday(&server), [0; 16]);
+        assert!(server.prepared.lock().unwrap().is_empty());
+
+        let log = TempFile::new("server-txn-log");
+        *server.coordinator.lock().unwrap() = Coordinator::open(&server.address, Some(log.path())).unwrap().0;
+        let response = server.multi_book(request());
+        assert!(response.success, "{:?}", response);
+        assert_eq!(response.confirmation_ids, [1, 2]);
+        assert_eq!(monday(&server), [1, 1, 0, 0, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn recover_finishes_transactions_in_doubt() {
+        let participant = listening();
+        let coordinator = server(None);
+        let committed = txn(&coordinator.address, 1);
+        let pending = txn(&coordinator.address, 2);
+        assert!(prepare(&participant, &committed, vec![monday_booking(0, 1)]).vote);
+        assert!(prepare(&participant, &pending, vec![monday_booking(4, 2)]).vote);
+        coordinator.recover(vec![
+            InDoubt { id: 1, participants: vec![participant.address.clone()], outcome: Outcome::Committed },
+            InDoubt { id: 2, participants: vec![participant.address.clone()], outcome: Outcome::Pending },
+        ]);
+        assert!(participant.prepared.lock().unwrap().is_empty());
+        assert_eq!(participant.bookings.lock().unwrap().list.keys().collect::<Vec<&u8>>(), [&1]);
+        assert_eq!(monday(&participant), [1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(coordinator.coordinator.lock().unwrap().status(2), Outcome::Aborted);
+    }
+
+    #[test]
+    fn owners_may_update_their_bookings() {
+        let server = server(Some(users()));
+        let confirmation_id = booked(&server);
+        assert!(server.may_change(Some(&token(1)), 1));
+        let response = update(&server, Some(token(1)), confirmation_id);
+        assert!(response.success, "{:?}", response);
+        assert_eq!(start_slot(&server, confirmation_id), 3);
+    }
+
+    #[test]
+    fn other_users_are_forbidden() {
+        let server = server(Some(users()));
+        let confirmation_id = booked(&server);
+        assert!(!server.may_change(Some(&token(2)), 1));
+        // Users missing from the file are no better off
+        assert!(!server.may_change(Some(&token(4)), 1));
+        let response = update(&server, Some(token(2)), confirmation_id);
+        assert!(!response.success);
+        assert_eq!(response.error, ErrorCode::Forbidden);
+        assert_eq!(start_slot(&server, confirmation_id), 2);
+    }
+
+    #[test]
+    fn admins_may_update_any_booking() {
+        let server = server(Some(users()));
+        let confirmation_id = booked(&server);
+        assert!(server.may_change(Some(&token(3)), 1));
+        let response = update(&server, Some(token(3)), confirmation_id);
+        assert!(response.success, "{:?}", response);
+        assert_eq!(start_slot(&server, confirmation_id), 3);
+    }
+
+    #[test]
+    fn missing_tokens_are_refused() {
+        let server = server(Some(users()));
+        let confirmation_id = booked(&server);
+        assert_eq!(server.authenticate(None), Err("log in first".to_string()));
+        assert!(!server.may_change(None, 1));
+        let response = update(&server, None, confirmation_id);
+        assert_eq!(response.error, ErrorCode::Forbidden);
+        assert_eq!(start_slot(&server, confirmation_id), 2);
+        // Without credentials anyone may change anything
+        let open = self::server(None);
+        let confirmation_id = booked(&open);
+        assert!(update(&open, None, confirmation_id).success);
+    }
+}
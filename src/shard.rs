@@ -0,0 +1,198 @@
+use std::fmt;
+use std::fs;
+use serde::{Deserialize, Deserializer, Serialize};
+use crate::codec::{Decode, DecodeError, Encode, Reader, Writer};
+
+/// Points each shard gets on the hash ring; more points spread facilities more evenly.
+const VIRTUAL_NODES: u32 = 64;
+
+/// How facilities are partitioned across server processes. Facilities named in
+/// `assignments` live on the given shard, every other facility is placed by
+/// consistent hashing over the shard addresses. Shard `i` hands out the
+/// confirmation ids `i+1, i+1+n, i+1+2n, ...` so any id leads back to its shard.
+/// Ids are a single byte, so a deployment of `n` shards has 255 of them in all
+/// and each shard about `255/n`, after which its bookings fail with
+/// `ErrorCode::IdsExhausted`; cancelled bookings do not give theirs back.
+///
+/// Only `shards` and `assignments` go on the wire; the ring is built from
+/// them by `new`, which every other constructor goes through.
+#[derive(Clone, PartialEq, Eq, Serialize)]
+pub struct ShardMap {
+    pub shards: Vec<String>,
+    pub assignments: Vec<(String, u8)>,
+    /// (point, shard) for every virtual node, sorted by point
+    #[serde(skip)]
+    ring: Vec<(u64, usize)>,
+}
+
+/// FNV-1a, stable across processes and Rust versions unlike `DefaultHasher`,
+/// followed by MurmurHash3's finalizer: the ring is ordered by the high bits,
+/// which FNV-1a barely changes between names differing in their last bytes.
+fn hash(text: &str) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for byte in text.as_bytes() {
+        hash ^= *byte as u64;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+impl ShardMap {
+    pub fn new(shards: Vec<String>, assignments: Vec<(String, u8)>) -> Self {
+        let mut ring: Vec<(u64, usize)> = shards.iter().enumerate()
+            .flat_map(|(shard, address)| (0..VIRTUAL_NODES).map(move |i| (hash(&format!("{}#{}", address, i)), shard)))
+            .collect();
+        ring.sort_unstable();
+        Self { shards, assignments, ring }
+    }
+
+    /// A deployment where one server owns everything.
+    pub fn single(address: &str) -> Self {
+        Self::new(vec![address.to_string()], Vec::new())
+    }
+
+    /// Reads a shard map file, see `parse`.
+    pub fn load(path: &str) -> Result<Self, String> {
+        let text = fs::read_to_string(path).map_err(|e| format!("Cannot read shard map {}: {}", path, e))?;
+        Self::parse(&text, path)
+    }
+
+    /// Parses `shard <address>` lines, in shard order, and optional `facility
+    /// <name> <shard index>` lines. `#` starts a comment. Errors name `origin`.
+    pub fn parse(text: &str, origin: &str) -> Result<Self, String> {
+        let mut shards: Vec<String> = Vec::new();
+        let mut assignments: Vec<(String, u8)> = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let line = line.split('#').next().unwrap().trim();
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [] => {}
+                ["shard", address] => shards.push(address.to_string()),
+                ["facility", name, shard] => {
+                    let shard: u8 = shard.parse().map_err(|_| format!("{}:{}: invalid shard index {}", origin, number + 1, shard))?;
+                    assignments.push((name.to_string(), shard));
+                }
+                _ => return Err(format!("{}:{}: cannot parse '{}'", origin, number + 1, line)),
+            }
+        }
+        if shards.is_empty() {
+            return Err(format!("{}: no shards listed", origin));
+        }
+        if let Some((name, shard)) = assignments.iter().find(|(_, shard)| *shard as usize >= shards.len()) {
+            return Err(format!("{}: facility {} assigned to unknown shard {}", origin, name, shard));
+        }
+        Ok(Self::new(shards, assignments))
+    }
+
+    /// Index of the shard that owns `facility_name`.
+    pub fn owner(&self, facility_name: &str) -> usize {
+        if let Some((_, shard)) = self.assignments.iter().find(|(name, _)| name == facility_name) {
+            return *shard as usize;
+        }
+        let key = hash(facility_name);
+        // The first point at or after the key; past the last one the ring wraps around to the first
+        let successor = self.ring.partition_point(|(point, _)| *point < key);
+        self.ring.get(successor).or(self.ring.first()).map_or(0, |(_, shard)| *shard)
+    }
+
+    /// Index of the shard that issued `confirmation_id`.
+    pub fn confirmation_owner(&self, confirmation_id: u8) -> usize {
+        (confirmation_id as usize + self.shards.len() - 1) % self.shards.len()
+    }
+}
+
+impl fmt::Debug for ShardMap {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ShardMap").field("shards", &self.shards).field("assignments", &self.assignments).finish()
+    }
+}
+
+impl Encode for ShardMap {
+    fn encode(&self, writer: &mut Writer) {
+        writer.put(&self.shards).put(&self.assignments);
+    }
+}
+
+impl Decode for ShardMap {
+    fn decode(reader: &mut Reader) -> Result<Self, DecodeError> {
+        Ok(Self::new(reader.get()?, reader.get()?))
+    }
+}
+
+impl<'de> Deserialize<'de> for ShardMap {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        struct Fields {
+            shards: Vec<String>,
+            assignments: Vec<(String, u8)>,
+        }
+        let fields = Fields::deserialize(deserializer)?;
+        Ok(Self::new(fields.shards, fields.assignments))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shards(n: usize) -> ShardMap {
+        ShardMap::new((0..n).map(|i| format!("127.0.0.1:{}", 5000 + i)).collect(), Vec::new())
+    }
+
+    fn parse(text: &str) -> Result<ShardMap, String> {
+        ShardMap::parse(text, "shards.conf")
+    }
+
+    #[test]
+    fn parse_reads_shards_and_assignments() {
+        let map = parse("# two shards\nshard 127.0.0.1:5000\nshard 127.0.0.1:5001 # second\n\nfacility gym 1\n").unwrap();
+        assert_eq!(map.shards, ["127.0.0.1:5000", "127.0.0.1:5001"]);
+        assert_eq!(map.assignments, [("gym".to_string(), 1)]);
+    }
+
+    #[test]
+    fn parse_refuses_bad_maps() {
+        assert!(parse("# nothing\n").unwrap_err().ends_with(": no shards listed"));
+        assert!(parse("shard a\nfacility gym 1\n").unwrap_err().ends_with(": facility gym assigned to unknown shard 1"));
+        assert!(parse("shard a\nfacility gym one\n").unwrap_err().ends_with(":2: invalid shard index one"));
+        assert!(parse("shard a b\n").unwrap_err().ends_with(":1: cannot parse 'shard a b'"));
+    }
+
+    #[test]
+    fn pinned_facilities_stay_on_their_shard() {
+        let hashed = shards(3).owner("gym");
+        let pinned = (hashed + 1) % 3;
+        let map = ShardMap::new(shards(3).shards, vec![("gym".to_string(), pinned as u8)]);
+        assert_eq!(map.owner("gym"), pinned);
+    }
+
+    #[test]
+    fn hashing_spreads_facilities_and_moves_few_of_them() {
+        let names: Vec<String> = (0..200).map(|i| format!("facility{}", i)).collect();
+        let three = shards(3);
+        let mut per_shard = [0; 3];
+        for name in &names {
+            per_shard[three.owner(name)] += 1;
+        }
+        assert!(per_shard.iter().all(|&count| count > 20), "{:?}", per_shard);
+        // A new shard only takes facilities, it never moves them between the old ones
+        let four = shards(4);
+        let moved = names.iter().filter(|name| four.owner(name) != three.owner(name)).count();
+        assert!(moved > 0 && moved < names.len() / 2, "{} moved", moved);
+        assert!(names.iter().all(|name| four.owner(name) == 3 || four.owner(name) == three.owner(name)));
+        assert!(names.iter().all(|name| ShardMap::single("a").owner(name) == 0));
+    }
+
+    #[test]
+    fn confirmation_ids_are_strided_by_shard() {
+        let map = shards(3);
+        for (confirmation_id, shard) in [(1, 0), (2, 1), (3, 2), (4, 0), (5, 1), (6, 2), (253, 0), (254, 1), (255, 2)] {
+            assert_eq!(map.confirmation_owner(confirmation_id), shard, "{}", confirmation_id);
+        }
+        assert_eq!(ShardMap::single("a").confirmation_owner(255), 0);
+    }
+}
@@ -0,0 +1,54 @@
+//! Length-prefixed framing over TCP, used between servers and by clients where
+//! UDP is filtered. A frame is a u32 little endian length followed by that
+//! many bytes of one message, so messages need no fragments or checksums.
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
+/// Largest frame `read_frame` accepts, anything longer is refused before it is read.
+pub const MAX_FRAME: usize = 1024 * 1024;
+
+/// Writes one length-prefixed frame (u32 little endian length, then the bytes).
+pub fn write_frame(stream: &mut TcpStream, frame: &[u8]) -> io::Result<()> {
+    stream.write_all(&(frame.len() as u32).to_le_bytes())?;
+    stream.write_all(frame)
+}
+
+/// Reads one frame written by `write_frame`. A length over MAX_FRAME is
+/// InvalidData, the stream can't be trusted to be framed after that.
+pub fn read_frame(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut len: [u8; 4] = [0; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_le_bytes(len) as usize;
+    if len > MAX_FRAME {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes is over {}", len, MAX_FRAME)));
+    }
+    let mut frame: Vec<u8> = vec![0; len];
+    stream.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+/// Opens a connection to `address`, giving up on connecting, reading and
+/// writing after `timeout`.
+pub fn connect(address: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let addr = address.to_socket_addrs()?.next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "address does not resolve"))?;
+    let stream = TcpStream::connect_timeout(&addr, timeout)?;
+    stream.set_nodelay(true)?;
+    stream.set_read_timeout(Some(timeout))?;
+    stream.set_write_timeout(Some(timeout))?;
+    Ok(stream)
+}
+
+/// Sends `message` over a new connection and waits up to `timeout` for the
+/// reply, `Ok(None)` if it did not come in time. The TCP counterpart of
+/// fragment::request.
+pub fn request(address: &str, message: &[u8], timeout: Duration) -> io::Result<Option<Vec<u8>>> {
+    let mut stream = connect(address, timeout)?;
+    write_frame(&mut stream, message)?;
+    match read_frame(&mut stream) {
+        Ok(reply) => Ok(Some(reply)),
+        Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
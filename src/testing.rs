@@ -0,0 +1,36 @@
+//! Fixtures shared by the server's unit tests.
+use std::{fs, path::PathBuf, process, sync::atomic::{AtomicUsize, Ordering}};
+
+/// A path in the temp directory no other test, in this process or another,
+/// is using, removed again when dropped. Nothing is created until the test
+/// writes there.
+pub struct TempFile {
+    path: PathBuf,
+}
+
+impl TempFile {
+    pub fn new(name: &str) -> Self {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let unique = NEXT.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("facility-test-{}-{}-{}", process::id(), unique, name));
+        let _ = fs::remove_file(&path);
+        Self { path }
+    }
+
+    /// One already holding `contents`.
+    pub fn with(name: &str, contents: impl AsRef<[u8]>) -> Self {
+        let file = Self::new(name);
+        fs::write(&file.path, contents).unwrap();
+        file
+    }
+
+    pub fn path(&self) -> &str {
+        self.path.to_str().unwrap()
+    }
+}
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
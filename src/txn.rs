@@ -0,0 +1,205 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::time::Duration;
+use crate::auth::{sign_request, PeerKey};
+use crate::message::{reply_status, versioned, Outcome, ReplyStatus, TxnId};
+use crate::tcp::{self, read_frame, write_frame};
+
+/// How long a participant holds slots for a prepared transaction before
+/// asking the coordinator what became of it.
+pub const PREPARE_TIMEOUT: Duration = Duration::from_secs(5);
+/// How long the coordinator and participants wait on each other per call.
+const CALL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Sends one request over a fresh TCP connection and returns the reply
+/// payload, following shard and leader redirects. The request is signed with
+/// `key` when the deployment has credentials.
+pub fn call(address: &str, request: &[u8], key: Option<&PeerKey>) -> io::Result<Vec<u8>> {
+    let mut address: String = address.to_string();
+    for _ in 0..4 {
+        let mut stream = tcp::connect(&address, CALL_TIMEOUT)?;
+        write_frame(&mut stream, &sign_request(key, versioned(request)))?;
+        let reply = read_frame(&mut stream)?;
+        let (status, mut reader) = reply_status(&reply)?;
+        match status {
+            ReplyStatus::Ok => return Ok(reader.rest().to_vec()),
+            ReplyStatus::WrongShard { owner } => address = owner,
+            ReplyStatus::NotLeader { leader } => address = leader,
+            status => return Err(io::Error::other(format!("{}: {}", address, status))),
+        }
+    }
+    Err(io::Error::other("too many redirects"))
+}
+
+/// A transaction the coordinator had not finished when it stopped.
+pub struct InDoubt {
+    pub id: u32,
+    pub participants: Vec<String>,
+    pub outcome: Outcome,
+}
+
+/// The coordinator's side of two-phase commit. Every step is written to a log
+/// of `begin <id> <participants>`, `commit <id>`, `abort <id>` and `done <id>`
+/// lines so a restarted coordinator can finish what it started. A transaction
+/// without a commit line is aborted, which is also the answer for ids it has
+/// forgotten, including those of finished transactions: no participant holds
+/// anything for them any more. Without a log a restart would forget commits
+/// participants have yet to hear of and turn them into aborts, so servers
+/// without one refuse to coordinate.
+pub struct Coordinator {
+    address: String,
+    log: Option<File>,
+    next_id: u32,
+    outcomes: HashMap<u32, Outcome>,
+}
+
+impl Coordinator {
+    /// Replays the log at `path`, if any, and returns the transactions left unfinished.
+    pub fn open(address: &str, path: Option<&str>) -> io::Result<(Self, Vec<InDoubt>)> {
+        let mut coordinator = Self { address: address.to_string(), log: None, next_id: 1, outcomes: HashMap::new() };
+        let Some(path) = path else {
+            return Ok((coordinator, Vec::new()));
+        };
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let mut unfinished: BTreeMap<u32, InDoubt> = BTreeMap::new();
+        for line in text.lines() {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("{}: cannot parse '{}'", path, line));
+            let id: u32 = fields.get(1).and_then(|id| id.parse().ok()).ok_or_else(invalid)?;
+            coordinator.next_id = coordinator.next_id.max(id + 1);
+            match fields[0] {
+                "begin" => {
+                    let participants = fields.get(2).map(|list| list.split(',').map(str::to_string).collect()).unwrap_or_default();
+                    unfinished.insert(id, InDoubt { id, participants, outcome: Outcome::Pending });
+                }
+                "commit" | "abort" => {
+                    let outcome = if fields[0] == "commit" { Outcome::Committed } else { Outcome::Aborted };
+                    coordinator.outcomes.insert(id, outcome);
+                    if let Some(txn) = unfinished.get_mut(&id) {
+                        txn.outcome = outcome;
+                    }
+                }
+                "done" => {
+                    unfinished.remove(&id);
+                    coordinator.outcomes.remove(&id);
+                }
+                _ => return Err(invalid()),
+            }
+        }
+        coordinator.log = Some(OpenOptions::new().create(true).append(true).open(path)?);
+        Ok((coordinator, unfinished.into_values().collect()))
+    }
+
+    /// Whether outcomes survive a restart.
+    pub fn is_logged(&self) -> bool {
+        self.log.is_some()
+    }
+
+    fn write(&mut self, line: String) {
+        if let Some(log) = &mut self.log {
+            writeln!(log, "{}", line).and_then(|_| log.sync_data()).expect("cannot write the transaction log");
+        }
+    }
+
+    pub fn begin(&mut self, participants: &[String]) -> TxnId {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.outcomes.insert(id, Outcome::Pending);
+        self.write(format!("begin {} {}", id, participants.join(",")));
+        TxnId { coordinator: self.address.clone(), id }
+    }
+
+    /// Records the outcome; once this returns a commit survives a crash.
+    pub fn decide(&mut self, id: u32, outcome: Outcome) {
+        self.outcomes.insert(id, outcome);
+        self.write(format!("{} {}", if outcome == Outcome::Committed { "commit" } else { "abort" }, id));
+    }
+
+    /// Records that every participant has acknowledged the outcome, which can
+    /// then be forgotten.
+    pub fn done(&mut self, id: u32) {
+        self.write(format!("done {}", id));
+        self.outcomes.remove(&id);
+    }
+
+    pub fn status(&self, id: u32) -> Outcome {
+        self.outcomes.get(&id).copied().unwrap_or(Outcome::Aborted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempFile;
+
+    #[test]
+    fn open_replays_the_log() {
+        let log = TempFile::with("txn-replay", "begin 1 a,b\ncommit 1\ndone 1\nbegin 2 a\ncommit 2\nbegin 3 b\nbegin 4 a,b\nabort 4\n");
+        let (mut coordinator, in_doubt) = Coordinator::open("c", Some(log.path())).unwrap();
+        let in_doubt: Vec<(u32, Vec<String>, Outcome)> = in_doubt.into_iter().map(|txn| (txn.id, txn.participants, txn.outcome)).collect();
+        assert_eq!(in_doubt, [
+            (2, vec!["a".to_string()], Outcome::Committed),
+            (3, vec!["b".to_string()], Outcome::Pending),
+            (4, vec!["a".to_string(), "b".to_string()], Outcome::Aborted),
+        ]);
+        assert_eq!(coordinator.status(2), Outcome::Committed);
+        assert_eq!(coordinator.status(3), Outcome::Aborted);
+        assert_eq!(coordinator.status(4), Outcome::Aborted);
+        assert!(!coordinator.outcomes.contains_key(&1));
+
+        // New transactions follow the logged ones and are appended to the log
+        let txn = coordinator.begin(&["a".to_string()]);
+        assert_eq!(txn, TxnId { coordinator: "c".to_string(), id: 5 });
+        coordinator.decide(5, Outcome::Committed);
+        coordinator.done(5);
+        drop(coordinator);
+        let text = fs::read_to_string(log.path()).unwrap();
+        assert!(text.ends_with("abort 4\nbegin 5 a\ncommit 5\ndone 5\n"), "{}", text);
+    }
+
+    #[test]
+    fn open_refuses_bad_lines() {
+        let log = TempFile::with("txn-bad", "begin 1 a\nfinish 1\n");
+        let error = Coordinator::open("c", Some(log.path())).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn finished_outcomes_are_forgotten() {
+        let (mut coordinator, _) = Coordinator::open("c", None).unwrap();
+        let txn = coordinator.begin(&["a".to_string()]);
+        assert_eq!(coordinator.status(txn.id), Outcome::Pending);
+        coordinator.decide(txn.id, Outcome::Committed);
+        assert_eq!(coordinator.status(txn.id), Outcome::Committed);
+        coordinator.done(txn.id);
+        assert!(coordinator.outcomes.is_empty());
+    }
+
+    #[test]
+    fn commits_survive_a_crash_only_with_a_log() {
+        let (mut unlogged, _) = Coordinator::open("c", None).unwrap();
+        assert!(!unlogged.is_logged());
+        let txn = unlogged.begin(&["a".to_string()]);
+        unlogged.decide(txn.id, Outcome::Committed);
+        drop(unlogged);
+        // Restarted, it would tell the participants to release what they committed to
+        let (restarted, in_doubt) = Coordinator::open("c", None).unwrap();
+        assert!(in_doubt.is_empty());
+        assert_eq!(restarted.status(txn.id), Outcome::Aborted);
+
+        let log = TempFile::new("txn-crash");
+        let (mut logged, _) = Coordinator::open("c", Some(log.path())).unwrap();
+        assert!(logged.is_logged());
+        let txn = logged.begin(&["a".to_string()]);
+        logged.decide(txn.id, Outcome::Committed);
+        drop(logged);
+        let (restarted, in_doubt) = Coordinator::open("c", Some(log.path())).unwrap();
+        assert_eq!(in_doubt.iter().map(|txn| (txn.id, txn.outcome)).collect::<Vec<_>>(), [(txn.id, Outcome::Committed)]);
+        assert_eq!(restarted.status(txn.id), Outcome::Committed);
+    }
+}
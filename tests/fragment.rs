@@ -0,0 +1,59 @@
+// Splits messages into datagrams and puts them back together. Run with
+// `cargo test --test fragment`.
+use std::net::SocketAddr;
+
+#[path = "../src/fragment.rs"]
+pub mod fragment;
+
+use fragment::{fragments, parse, Datagram, Reassembler, MAX_MESSAGE, MAX_PARTIAL_PER_PEER, MAX_PAYLOAD};
+
+fn peer(port: u16) -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], port))
+}
+
+/// Adds one datagram made by `fragments` to `reassembler`.
+fn add(reassembler: &mut Reassembler, from: SocketAddr, datagram: &[u8]) -> Option<Vec<u8>> {
+    let Ok(Datagram::Fragment { message_id, index, count, payload }) = parse(datagram) else {
+        panic!("unparsable fragment {:?}", datagram);
+    };
+    reassembler.add(from, message_id, index, count, payload)
+}
+
+/// Feeds the datagrams of `message` to `reassembler`, returning what it completed.
+fn deliver(reassembler: &mut Reassembler, from: SocketAddr, message_id: u32, message: &[u8]) -> Option<Vec<u8>> {
+    fragments(message_id, message).iter().fold(None, |_, datagram| add(reassembler, from, datagram))
+}
+
+#[test]
+fn messages_are_put_back_together() {
+    let message: Vec<u8> = (0..3 * MAX_PAYLOAD + 5).map(|i| i as u8).collect();
+    let mut reassembler = Reassembler::default();
+    assert_eq!(deliver(&mut reassembler, peer(1), 7, &message), Some(message));
+    assert_eq!(deliver(&mut reassembler, peer(1), 8, &[]), Some(Vec::new()));
+}
+
+#[test]
+fn oversized_messages_are_dropped() {
+    let mut reassembler = Reassembler::default();
+    let count = MAX_MESSAGE.div_ceil(MAX_PAYLOAD) as u16 + 1;
+    assert_eq!(reassembler.add(peer(1), 1, 0, count, &[0; MAX_PAYLOAD]), None);
+    assert_eq!(reassembler.add(peer(1), 2, 0, u16::MAX, &[0; MAX_PAYLOAD]), None);
+    assert!(reassembler.poll().is_empty());
+    let largest = vec![1; MAX_MESSAGE];
+    assert_eq!(deliver(&mut reassembler, peer(1), 3, &largest), Some(largest));
+}
+
+#[test]
+fn partial_messages_are_capped_per_peer() {
+    let mut reassembler = Reassembler::default();
+    let message = vec![3; 2 * MAX_PAYLOAD];
+    let halves: Vec<Vec<Vec<u8>>> = (0..=MAX_PARTIAL_PER_PEER as u32).map(|message_id| fragments(message_id, &message)).collect();
+    for halves in &halves {
+        assert_eq!(add(&mut reassembler, peer(1), &halves[0]), None);
+    }
+    // The message past the cap was never started, the others can still finish
+    assert_eq!(add(&mut reassembler, peer(1), &halves[MAX_PARTIAL_PER_PEER][1]), None);
+    assert_eq!(add(&mut reassembler, peer(1), &halves[0][1]), Some(message.clone()));
+    // Other peers are not held back by this one
+    assert_eq!(deliver(&mut reassembler, peer(2), 0, &message), Some(message));
+}
@@ -0,0 +1,112 @@
+"""Checks src/message.py against the byte fixtures in tests/golden, the same
+ones tests/golden.rs pins the Rust codec to, in every version they hold.
+
+Run with `python3 tests/golden.py`.
+"""
+import os
+import sys
+import unittest
+
+sys.path.insert(0, os.path.join(os.path.dirname(__file__), "..", "src"))
+from codec import Reader  # noqa: E402
+from message import *  # noqa: E402,F401,F403
+
+GOLDEN = os.path.join(os.path.dirname(__file__), "golden")
+
+
+def fixtures(case: str) -> dict:
+    """The encodings of `case` by version; see read_fixture in golden.rs for the layout."""
+    encodings = {}
+    with open(os.path.join(GOLDEN, f"{case}.txt")) as f:
+        for line in f:
+            if line.startswith("#") or not line.strip():
+                continue
+            version, hex_bytes = line.split(":", 1)
+            encodings[int(version)] = bytes.fromhex(hex_bytes)
+    return encodings
+
+
+def booking() -> Booking:
+    return Booking(facility_name="facility1", day=Day.TUESDAY, start_slot=2, num_slots=3, user_id=7)
+
+
+def token() -> Token:
+    return Token(user_id=7, expires=1_800_000_000, mac=bytes([0xAB] * 32))
+
+
+def record() -> FacilityRecord:
+    schedule = {day: bytes(16) for day in Day}
+    schedule[Day.MONDAY] = bytes([1, 1] + [0] * 14)
+    schedule[Day.FRIDAY] = bytes([0] * 14 + [9, 9])
+    return FacilityRecord(schedule=schedule)
+
+
+class Golden(unittest.TestCase):
+    def golden(self, case: str, value):
+        """Like golden() in golden.rs: the encoding matches in every version and
+        decodes back to itself, and to `value` in the current version."""
+        for version, data in fixtures(case).items():
+            with self.subTest(case=case, version=version):
+                self.assertEqual(value.to_bytes(version).hex(" "), data.hex(" "))
+                reader = Reader(data, version)
+                decoded = type(value).decode(reader)
+                self.assertEqual(reader.remaining(), 0)
+                self.assertEqual(decoded.to_bytes(version), data)
+                if version == PROTOCOL_VERSION:
+                    self.assertEqual(decoded, value)
+
+    def test_query(self):
+        self.golden("day", Day.FRIDAY)
+        self.golden("query_request", QueryRequest(name="facility1", days=[Day.MONDAY, Day.THURSDAY]))
+        self.golden("query_response", QueryResponse(name="facility1", error=ErrorCode.NONE, availaible=bytes([1, 1] + [0] * 14)))
+        self.golden("query_response_not_found", QueryResponse(name="gym", error=ErrorCode.FACILITY_NOT_FOUND, availaible=b""))
+
+    def test_book_and_update(self):
+        self.golden("booking", booking())
+        self.golden("book_request", BookRequest(auth=token(), booking=booking()))
+        self.golden("book_request_anonymous", BookRequest(auth=None, booking=booking()))
+        self.golden("booking_response", BookingResponse(success=True, error=ErrorCode.NONE, message="Booking Successful", confirmation_id=4))
+        self.golden("booking_response_taken", BookingResponse(success=False, error=ErrorCode.SLOT_TAKEN, message="Slots taken", confirmation_id=0))
+        self.golden("update", Update(auth=token(), confirmation_id=4, offset=-2))
+        self.golden("update_response", UpdateResponse(success=False, error=ErrorCode.OUT_OF_RANGE, message="Out of range"))
+
+    def test_login(self):
+        self.golden("token", token())
+        self.golden("login", Login(user_id=7, password="hunter2"))
+        self.golden("login_response", LoginResponse(success=True, error=ErrorCode.NONE, message="Login Successful", token=token()))
+        self.golden("login_response_refused", LoginResponse(success=False, error=ErrorCode.UNAUTHENTICATED, message="Login Failed", token=None))
+
+    def test_lookups(self):
+        self.golden("get_booking_response", GetBookingResponse(success=True, error=ErrorCode.NONE, message="Found", booking=booking()))
+        self.golden("get_booking_response_missing", GetBookingResponse(success=False, error=ErrorCode.BOOKING_NOT_FOUND, message="Missing", booking=None))
+        self.golden("list_bookings_response", ListBookingsResponse(bookings=[(4, booking()), (9, booking())]))
+        self.golden("export_user", Export.User(7))
+        self.golden("stats_response", StatsResponse(
+            facilities=[FacilityStats(name="facility1", booked_by_day=bytes([2, 0, 0, 0, 2]), booked_by_hour=bytes([2, 0, 0, 0, 0, 0, 0, 2]))],
+            users=[(1, 2), (9, 2)]))
+
+    def test_monitor(self):
+        self.golden("monitor", Monitor(duration=600))
+        self.golden("facility_record", record())
+
+    def test_replication_and_raft(self):
+        self.golden("mutation_snapshot", Mutation.Snapshot(next_id=5, bookings=[(4, booking())]))
+        self.golden("mutation_snapshot_exhausted", Mutation.Snapshot(next_id=None, bookings=[]))
+        txn = TxnId(coordinator="127.0.0.1:5000", id=77)
+        self.golden("mutation_hold", Mutation.Hold(txn=txn, bookings=[(4, booking())]))
+        self.golden("mutation_finish", Mutation.Finish(txn=txn, outcome=Outcome.ABORTED))
+        entry = LogEntry(term=2, command=request(RequestType.UPDATE, Update(auth=None, confirmation_id=4, offset=1))[2:])
+        self.golden("log_entry", entry)
+        self.golden("raft_append_entries", RaftMessage.AppendEntries(
+            term=3, leader=0, prev_log_index=9, prev_log_term=2, leader_commit=8, entries=[entry]))
+
+    def test_envelope(self):
+        self.golden("request_type", RequestType.BOOK)
+        self.golden("reply_status_ok", ReplyStatus.Ok())
+        self.golden("reply_status_wrong_shard", ReplyStatus.WrongShard(owner="127.0.0.1:5001"))
+        self.golden("reply_status_not_leader", ReplyStatus.NotLeader(leader="127.0.0.1:5002"))
+        self.golden("reply_status_unsupported_version", ReplyStatus.UnsupportedVersion(min=1, max=3))
+
+
+if __name__ == "__main__":
+    unittest.main()
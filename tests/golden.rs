@@ -0,0 +1,203 @@
+// Pins the wire format to the byte fixtures in tests/golden: one file per
+// message case, one line per protocol version. Run with
+// `cargo test --test golden`; `UPDATE_GOLDEN=1` rewrites the fixtures from
+// the current encoders, to be reviewed like any other change of the format.
+// tests/golden.py checks src/message.py against the same files, and
+// python_module_matches_idl that src/message_gen.py is the one build.rs
+// generates from the IDL.
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::fs;
+use std::path::PathBuf;
+
+use serde::de::DeserializeOwned;
+
+#[path = "../src/codec.rs"]
+pub mod codec;
+#[path = "../src/message.rs"]
+pub mod message;
+#[path = "../src/schedule.rs"]
+pub mod schedule;
+#[path = "../src/shard.rs"]
+pub mod shard;
+
+use codec::{Decode, Encode, Reader};
+use message::*;
+use shard::ShardMap;
+
+fn fixture_path(case: &str) -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/golden").join(format!("{}.txt", case))
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<Vec<_>>().join(" ")
+}
+
+/// The encodings of a fixture file by version. Lines are `version: hex bytes`,
+/// `#` starts a comment.
+fn read_fixture(case: &str) -> BTreeMap<u8, Vec<u8>> {
+    let path = fixture_path(case);
+    let text = fs::read_to_string(&path)
+        .unwrap_or_else(|e| panic!("{}: {}, run with UPDATE_GOLDEN=1 to create it", path.display(), e));
+    text.lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let (version, bytes) = line.split_once(':').unwrap_or_else(|| panic!("{}: malformed line {:?}", path.display(), line));
+            let bytes = bytes.split_whitespace().map(|byte| u8::from_str_radix(byte, 16).unwrap()).collect();
+            (version.trim().parse().unwrap(), bytes)
+        })
+        .collect()
+}
+
+fn write_fixture<T: Debug>(case: &str, value: &T, encodings: &BTreeMap<u8, Vec<u8>>) {
+    let mut text = format!("# {:?}\n", value);
+    for (version, bytes) in encodings {
+        text += &format!("{}: {}\n", version, hex(bytes)).replace(": \n", ":\n");
+    }
+    fs::write(fixture_path(case), text).unwrap();
+}
+
+/// Decodes `bytes` laid out as in protocol `version`, checking nothing is left over.
+fn decode_in<T: Decode + DeserializeOwned + Debug>(bytes: &[u8], version: u8) -> T {
+    let mut reader = Reader::with_version(bytes, version);
+    let value: T = reader.get().unwrap();
+    assert_eq!(reader.remaining(), 0, "{:?} left bytes unread in version {}", value, version);
+    value
+}
+
+/// Checks that `value` encodes to the fixture of `case` in every supported
+/// version and that each fixture decodes and encodes back to itself. Older
+/// versions may lack fields, so the value itself is only compared in the
+/// current one.
+fn golden<T: Encode + Decode + DeserializeOwned + PartialEq + Debug>(case: &str, value: T) {
+    let encodings: BTreeMap<u8, Vec<u8>> = SUPPORTED_VERSIONS.map(|version| (version, value.to_bytes_in(version))).collect();
+    if std::env::var_os("UPDATE_GOLDEN").is_some() {
+        write_fixture(case, &value, &encodings);
+        return;
+    }
+    let fixtures = read_fixture(case);
+    for (version, bytes) in &encodings {
+        let fixture = fixtures.get(version).unwrap_or_else(|| panic!("{} has no version {} line", case, version));
+        assert_eq!(hex(bytes), hex(fixture), "{} encodes differently in version {}", case, version);
+        assert_eq!(decode_in::<T>(fixture, *version).to_bytes_in(*version), *fixture, "{} in version {}", case, version);
+    }
+    assert_eq!(decode_in::<T>(&fixtures[&PROTOCOL_VERSION], PROTOCOL_VERSION), value, "{}", case);
+}
+
+fn booking() -> Booking {
+    Booking { facility_name: "facility1".to_string(), day: Day::Tuesday, start_slot: 2, num_slots: 3, user_id: 7 }
+}
+
+fn token() -> Token {
+    Token { user_id: 7, expires: 1_800_000_000, mac: [0xab; 32] }
+}
+
+fn record() -> FacilityRecord {
+    let mut record = FacilityRecord::new();
+    record.is_slot_availaible(Day::Monday, 0, 2, 1);
+    record.is_slot_availaible(Day::Friday, 14, 2, 9);
+    record
+}
+
+#[test]
+fn query() {
+    golden("day", Day::Friday);
+    golden("query_request", QueryRequest { name: "facility1".to_string(), days: vec![Day::Monday, Day::Thursday] });
+    golden("query_response", QueryResponse { name: "facility1".to_string(), error: ErrorCode::None, availaible: record().day_slots(Day::Monday).to_vec() });
+    golden("query_response_not_found", QueryResponse { name: "gym".to_string(), error: ErrorCode::FacilityNotFound, availaible: vec![] });
+}
+
+#[test]
+fn book_and_update() {
+    golden("booking", booking());
+    golden("book_request", BookRequest { auth: Some(token()), booking: booking() });
+    golden("book_request_anonymous", BookRequest { auth: None, booking: booking() });
+    golden("booking_response", BookingResponse { success: true, error: ErrorCode::None, message: "Booking Successful".to_string(), confirmation_id: 4 });
+    golden("booking_response_taken", BookingResponse { success: false, error: ErrorCode::SlotTaken, message: "Slots taken".to_string(), confirmation_id: 0 });
+    golden("update", Update { auth: Some(token()), confirmation_id: 4, offset: -2 });
+    golden("update_response", UpdateResponse { success: false, error: ErrorCode::OutOfRange, message: "Out of range".to_string() });
+}
+
+#[test]
+fn login() {
+    golden("token", token());
+    golden("login", Login { user_id: 7, password: "hunter2".to_string() });
+    golden("login_response", LoginResponse { success: true, error: ErrorCode::None, message: "Login Successful".to_string(), token: Some(token()) });
+    golden("login_response_refused", LoginResponse { success: false, error: ErrorCode::Unauthenticated, message: "Login Failed".to_string(), token: None });
+}
+
+#[test]
+fn lookups() {
+    golden("get_booking", GetBooking { confirmation_id: 4 });
+    golden("get_booking_response", GetBookingResponse { success: true, error: ErrorCode::None, message: "Found".to_string(), booking: Some(booking()) });
+    golden("get_booking_response_missing", GetBookingResponse { success: false, error: ErrorCode::BookingNotFound, message: "Missing".to_string(), booking: None });
+    golden("list_bookings", ListBookings { user_id: 7 });
+    golden("list_bookings_response", ListBookingsResponse { bookings: vec![(4, booking()), (9, booking())] });
+    golden("export_facility", Export::Facility("facility1".to_string()));
+    golden("export_user", Export::User(7));
+    golden("export_response", ExportResponse { success: true, error: ErrorCode::None, message: String::new(), calendar: "BEGIN:VCALENDAR\r\nEND:VCALENDAR\r\n".to_string() });
+    golden("stats_response", StatsResponse { facilities: vec![FacilityStats::from_record("facility1", &record())], users: vec![(1, 2), (9, 2)] });
+}
+
+#[test]
+fn monitor() {
+    golden("monitor", Monitor { duration: 600 });
+    golden("facility_record", record());
+}
+
+#[test]
+fn replication() {
+    golden("mutation_snapshot", Mutation::Snapshot { next_id: Some(5), bookings: vec![(4, booking())] });
+    golden("mutation_snapshot_exhausted", Mutation::Snapshot { next_id: None, bookings: vec![] });
+    golden("mutation_book", Mutation::Book { confirmation_id: 4, booking: booking() });
+    golden("mutation_move", Mutation::Move { confirmation_id: 4, start_slot: 6 });
+    let txn = TxnId { coordinator: "127.0.0.1:5000".to_string(), id: 77 };
+    golden("mutation_hold", Mutation::Hold { txn: txn.clone(), bookings: vec![(4, booking())] });
+    golden("mutation_finish", Mutation::Finish { txn, outcome: Outcome::Aborted });
+    golden("replicate", Replicate { primary: "127.0.0.1:5000".to_string(), mutation: Mutation::Move { confirmation_id: 4, start_slot: 6 } });
+    golden("promote", Promote { backups: vec!["127.0.0.1:5001".to_string(), "127.0.0.1:5002".to_string()] });
+}
+
+#[test]
+fn two_phase_commit() {
+    let txn = TxnId { coordinator: "127.0.0.1:5000".to_string(), id: 77 };
+    golden("multi_book", MultiBook { auth: Some(token()), bookings: vec![booking(), Booking { facility_name: "facility2".to_string(), ..booking() }] });
+    golden("multi_book_response", MultiBookResponse { success: true, error: ErrorCode::None, message: "Booked".to_string(), confirmation_ids: vec![4, 5] });
+    golden("txn_id", txn.clone());
+    golden("prepare", Prepare { txn, bookings: vec![booking()] });
+    golden("prepare_response", PrepareResponse { vote: false, error: ErrorCode::Unavailable, message: "No".to_string(), confirmation_ids: vec![] });
+    golden("outcome", Outcome::Committed);
+}
+
+#[test]
+fn raft() {
+    let entry = LogEntry { term: 2, command: message::request(RequestType::UPDATE, &Update { auth: None, confirmation_id: 4, offset: 1 }) };
+    golden("log_entry", entry.clone());
+    golden("raft_request_vote", RaftMessage::RequestVote { term: 3, candidate: 1, last_log_index: 10, last_log_term: 2 });
+    golden("raft_vote", RaftMessage::Vote { term: 3, granted: true });
+    golden("raft_append_entries", RaftMessage::AppendEntries {
+        term: 3, leader: 0, prev_log_index: 9, prev_log_term: 2, leader_commit: 8, entries: vec![entry],
+    });
+    golden("raft_appended", RaftMessage::Appended { term: 3, success: false, match_index: 7 });
+}
+
+#[test]
+fn envelope() {
+    golden("request_type", RequestType::BOOK);
+    golden("reply_status_ok", ReplyStatus::Ok);
+    golden("reply_status_wrong_shard", ReplyStatus::WrongShard { owner: "127.0.0.1:5001".to_string() });
+    golden("reply_status_not_leader", ReplyStatus::NotLeader { leader: "127.0.0.1:5002".to_string() });
+    golden("reply_status_unsupported_version", ReplyStatus::UnsupportedVersion { min: 1, max: 3 });
+    golden("shard_map", ShardMap::new(vec!["127.0.0.1:5000".to_string(), "127.0.0.1:5001".to_string()], vec![("facility1".to_string(), 1)]));
+}
+
+#[test]
+fn python_module_matches_idl() {
+    let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/message_gen.py");
+    let checked_in = fs::read_to_string(&path).unwrap_or_else(|e| panic!("{}: {}", path.display(), e));
+    assert!(
+        checked_in == include_str!(concat!(env!("OUT_DIR"), "/message_gen.py")),
+        "{} differs from src/messages.idl, run `cargo run --bin gen_python`",
+        path.display()
+    );
+}
@@ -0,0 +1,291 @@
+// Round trips every message through the derived codec and the serde
+// formats. Run with `cargo test --test messages`.
+use std::fmt::Debug;
+
+use serde::{de::DeserializeOwned, Serialize};
+
+#[path = "../src/codec.rs"]
+pub mod codec;
+#[path = "../src/message.rs"]
+pub mod message;
+#[path = "../src/schedule.rs"]
+pub mod schedule;
+#[path = "../src/shard.rs"]
+pub mod shard;
+
+use codec::{Decode, DecodeError, Encode, Format, Reader, Writer, FORMAT_BYTE};
+use message::*;
+use shard::ShardMap;
+
+/// Decodes `bytes` laid out as in protocol `version`, checking nothing is left over.
+fn decode_in<T: Decode + DeserializeOwned + Debug>(bytes: &[u8], version: u8) -> T {
+    let mut reader = Reader::with_version(bytes, version);
+    let value: T = reader.get().unwrap();
+    assert_eq!(reader.remaining(), 0, "{:?} left bytes unread in version {}", value, version);
+    value
+}
+
+/// Decodes the encoding of `value` back in every supported version and in
+/// the JSON and CBOR formats. Older versions may lack fields, so for them the
+/// encoding is compared instead. Returns the encoding in the current version.
+fn round_trip<T: Encode + Decode + Serialize + DeserializeOwned + PartialEq + Debug>(value: T) -> Vec<u8> {
+    for version in SUPPORTED_VERSIONS {
+        let bytes = value.to_bytes_in(version);
+        assert_eq!(decode_in::<T>(&bytes, version).to_bytes_in(version), bytes, "{:?} in version {}", value, version);
+    }
+    for format in [Format::Json, Format::Cbor] {
+        let bytes = value.to_bytes_as(PROTOCOL_VERSION, format);
+        let mut reader = Reader::with_format(&bytes, PROTOCOL_VERSION, format);
+        assert_eq!(reader.get::<T>().as_ref(), Ok(&value), "{:?}", format);
+        assert_eq!(reader.remaining(), 0, "{:?} left bytes unread in {:?}", value, format);
+    }
+    let bytes = value.to_bytes();
+    assert_eq!(decode_in::<T>(&bytes, PROTOCOL_VERSION), value);
+    bytes
+}
+
+fn booking(facility_name: &str, user_id: u8) -> Booking {
+    Booking { facility_name: facility_name.to_string(), day: Day::Wednesday, start_slot: 3, num_slots: 2, user_id }
+}
+
+fn token(user_id: u8) -> Token {
+    Token { user_id, expires: 1_800_000_000, mac: [0xab; 32] }
+}
+
+#[test]
+fn day() {
+    for day in Day::ALL {
+        assert_eq!(round_trip(day), vec![day as u8]);
+    }
+}
+
+#[test]
+fn booking_and_response() {
+    round_trip(booking("gym", 7));
+    round_trip(BookRequest { auth: Some(token(7)), booking: booking("gym", 7) });
+    // Without a token the request is the booking behind an absence marker, and just the booking before version 5
+    let request = BookRequest { auth: None, booking: booking("gym", 7) };
+    assert_eq!(round_trip(request.clone()), [b"\x00".as_slice(), &booking("gym", 7).to_bytes()].concat());
+    assert_eq!(request.to_bytes_in(4), booking("gym", 7).to_bytes_in(4));
+    round_trip(BookingResponse { success: true, error: ErrorCode::None, message: "Booking Successful".to_string(), confirmation_id: 4 });
+    round_trip(BookingResponse { success: false, error: ErrorCode::SlotTaken, message: String::new(), confirmation_id: 0 });
+}
+
+#[test]
+fn booking_display() {
+    assert_eq!(booking("gym", 7).to_string(), "gym on Wednesday 09:30-10:30 (booked by 7)");
+    // A malformed reply may run past any slot of the day
+    let mut booking = booking("gym", 7);
+    booking.start_slot = 250;
+    booking.num_slots = 10;
+    assert_eq!(booking.to_string(), "gym on Wednesday 133:00-135:30 (booked by 7)");
+}
+
+#[test]
+fn update_and_response() {
+    round_trip(Update { auth: None, confirmation_id: 9, offset: -2 });
+    round_trip(Update { auth: Some(token(3)), confirmation_id: 9, offset: -2 });
+    round_trip(UpdateResponse { success: false, error: ErrorCode::BookingNotFound, message: "Update Failed, no such booking made".to_string() });
+}
+
+#[test]
+fn login_and_response() {
+    round_trip(Login { user_id: 4, password: "hunter2".to_string() });
+    let bytes = round_trip(token(4));
+    assert_eq!(bytes.len(), 1 + 4 + 32);
+    round_trip(LoginResponse { success: true, error: ErrorCode::None, message: "Login Successful".to_string(), token: Some(token(4)) });
+    round_trip(LoginResponse { success: false, error: ErrorCode::Unauthenticated, message: String::new(), token: None });
+}
+
+#[test]
+fn get_booking_and_response() {
+    round_trip(GetBooking { confirmation_id: 1 });
+    round_trip(GetBookingResponse { success: true, error: ErrorCode::None, message: "Found".to_string(), booking: Some(booking("pool", 3)) });
+    // A failed lookup carries no booking, not even an absence marker
+    let bytes = round_trip(GetBookingResponse { success: false, error: ErrorCode::BookingNotFound, message: "No".to_string(), booking: None });
+    assert_eq!(bytes, [b"\x00".as_slice(), &[ErrorCode::BookingNotFound as u8], b"\x02\x00\x00\x00No"].concat());
+}
+
+#[test]
+fn list_bookings_and_response() {
+    round_trip(ListBookings { user_id: 5 });
+    round_trip(ListBookingsResponse { bookings: vec![] });
+    round_trip(ListBookingsResponse { bookings: vec![(1, booking("gym", 5)), (4, booking("pool", 5))] });
+}
+
+#[test]
+fn export_and_response() {
+    round_trip(Export::Facility("gym".to_string()));
+    round_trip(Export::User(12));
+    round_trip(ExportResponse { success: true, error: ErrorCode::None, message: String::new(), calendar: "BEGIN:VCALENDAR\r\n".to_string() });
+}
+
+#[test]
+fn stats_response() {
+    let mut record = FacilityRecord::new();
+    record.is_slot_availaible(Day::Monday, 0, 4, 2);
+    round_trip(FacilityStats::from_record("gym", &record));
+    round_trip(StatsResponse { facilities: vec![FacilityStats::from_record("gym", &record)], users: vec![(2, 1)] });
+}
+
+#[test]
+fn mutation_and_replicate() {
+    let bytes = round_trip(Mutation::Snapshot { next_id: None, bookings: vec![(3, booking("gym", 1))] });
+    assert_eq!(bytes[..2], [0, 0]);
+    round_trip(Mutation::Snapshot { next_id: Some(4), bookings: vec![] });
+    round_trip(Mutation::Book { confirmation_id: 2, booking: booking("gym", 1) });
+    round_trip(Mutation::Move { confirmation_id: 2, start_slot: 6 });
+    round_trip(Replicate { primary: "127.0.0.1:5000".to_string(), mutation: Mutation::Move { confirmation_id: 1, start_slot: 0 } });
+}
+
+#[test]
+fn promote_and_monitor() {
+    round_trip(Promote { backups: vec!["127.0.0.1:5001".to_string(), "127.0.0.1:5002".to_string()] });
+    round_trip(Monitor { duration: 600 });
+}
+
+#[test]
+fn multi_book_and_two_phase_commit() {
+    let txn = TxnId { coordinator: "127.0.0.1:5000".to_string(), id: 77 };
+    round_trip(MultiBook { auth: Some(token(1)), bookings: vec![booking("gym", 1), booking("pool", 1)] });
+    round_trip(MultiBookResponse { success: true, error: ErrorCode::None, message: String::new(), confirmation_ids: vec![1, 2] });
+    round_trip(txn.clone());
+    round_trip(Prepare { txn, bookings: vec![booking("gym", 1)] });
+    round_trip(PrepareResponse { vote: false, error: ErrorCode::SlotTaken, message: "Slot taken".to_string(), confirmation_ids: vec![] });
+    for outcome in [Outcome::Pending, Outcome::Committed, Outcome::Aborted] {
+        round_trip(outcome);
+    }
+}
+
+#[test]
+fn raft() {
+    let entry = LogEntry { term: 3, command: vec![1; 300] };
+    let bytes = round_trip(entry.clone());
+    assert_eq!(bytes[4..8], 300u32.to_le_bytes());
+    round_trip(LogEntry { term: 0, command: vec![] });
+    round_trip(RaftMessage::RequestVote { term: 2, candidate: 1, last_log_index: 10, last_log_term: 1 });
+    round_trip(RaftMessage::Vote { term: 2, granted: true });
+    round_trip(RaftMessage::Appended { term: 2, success: false, match_index: 7 });
+    let bytes = round_trip(RaftMessage::AppendEntries {
+        term: 2, leader: 0, prev_log_index: 9, prev_log_term: 1, leader_commit: 8, entries: vec![entry; 256],
+    });
+    // leader_commit precedes the u32 entry count
+    assert_eq!(bytes[14..18], 8u32.to_le_bytes());
+    assert_eq!(bytes[18..22], 256u32.to_le_bytes());
+}
+
+#[test]
+fn request_type_and_reply_status() {
+    round_trip(RequestType::QUERY);
+    assert_eq!(round_trip(RequestType::TXN_STATUS), vec![16]);
+    round_trip(ReplyStatus::Ok);
+    round_trip(ReplyStatus::WrongShard { owner: "127.0.0.1:5001".to_string() });
+    round_trip(ReplyStatus::NotLeader { leader: "127.0.0.1:5002".to_string() });
+    round_trip(ReplyStatus::UnsupportedVersion { min: 1, max: 2 });
+}
+
+#[test]
+fn query_and_response() {
+    let request = QueryRequest { name: "gym".to_string(), days: vec![Day::Monday, Day::Friday] };
+    assert_eq!(round_trip(request.clone()), b"\x03\x00\x00\x00gym\x02\x00\x00\x04");
+    // Version 1 had no count, the days ran to the end of the message
+    assert_eq!(request.to_bytes_in(1), b"gym\x00\x00\x04");
+    round_trip(QueryRequest { name: "gym".to_string(), days: vec![] });
+    let mut record = FacilityRecord::new();
+    record.is_slot_availaible(Day::Tuesday, 2, 3, 9);
+    let bytes = round_trip(QueryResponse { name: "gym".to_string(), error: ErrorCode::None, availaible: record.day_slots(Day::Tuesday).to_vec() });
+    assert_eq!(bytes.len(), 4 + 3 + 1 + 2 + 16);
+}
+
+#[test]
+fn facility_record() {
+    let mut record = FacilityRecord::new();
+    record.is_slot_availaible(Day::Thursday, 10, 6, 200);
+    assert_eq!(round_trip(record).len(), 5 * 16);
+}
+
+#[test]
+fn shard_map() {
+    round_trip(ShardMap::new(vec!["127.0.0.1:5000".to_string()], vec![("gym".to_string(), 0)]));
+}
+
+#[test]
+fn error_codes() {
+    for error in [ErrorCode::None, ErrorCode::FacilityNotFound, ErrorCode::SlotTaken, ErrorCode::Forbidden, ErrorCode::OutOfRange,
+        ErrorCode::InvalidRequest, ErrorCode::BookingNotFound, ErrorCode::IdsExhausted, ErrorCode::Unavailable, ErrorCode::Unauthenticated]
+    {
+        round_trip(error);
+    }
+    // Clients of version 2 and older get no error code, it decodes as None
+    let response = UpdateResponse { success: false, error: ErrorCode::SlotTaken, message: "Taken".to_string() };
+    assert_eq!(response.to_bytes_in(2), b"\x00\x05\x00\x00\x00Taken");
+    assert_eq!(decode_in::<UpdateResponse>(&response.to_bytes_in(2), 2).error, ErrorCode::None);
+}
+
+#[test]
+fn strings_may_hold_nul() {
+    let export = Export::Facility("a\0b".to_string());
+    assert_eq!(Export::from_bytes(&export.to_bytes()), Ok(export));
+}
+
+#[test]
+fn option_has_presence_byte() {
+    assert_eq!(round_trip(Some(5u8)), vec![1, 5]);
+    assert_eq!(round_trip(None::<u8>), vec![0]);
+}
+
+#[test]
+fn format_byte() {
+    let booking = booking("gym", 4);
+    let mut writer = Writer::versioned(PROTOCOL_VERSION, Format::Json);
+    writer.put(&RequestType::BOOK).put(&booking);
+    let bytes = writer.into_bytes();
+    assert_eq!(bytes[..2], [PROTOCOL_VERSION, 1]);
+    assert_eq!(
+        &bytes[2..],
+        b"\"BOOK\"\n{\"facility_name\":\"gym\",\"day\":\"Wednesday\",\"start_slot\":3,\"num_slots\":2,\"user_id\":4}\n"
+    );
+    let mut reader = Reader::versioned(&bytes).unwrap();
+    assert_eq!(reader.format(), Format::Json);
+    assert_eq!(reader.get::<RequestType>(), Ok(RequestType::BOOK));
+    assert_eq!(reader.get::<Booking>(), Ok(booking));
+    assert_eq!(reader.remaining(), 0);
+    // Versions before the format byte are always binary
+    let mut writer = Writer::versioned(FORMAT_BYTE - 1, Format::Binary);
+    writer.put(&RequestType::BOOK);
+    assert_eq!(writer.into_bytes(), [FORMAT_BYTE - 1, 1]);
+}
+
+#[test]
+fn counts_hold_their_lists() {
+    assert_eq!(vec![7u8; 255].to_bytes_in(1)[0], 255);
+    assert_eq!(vec![7u8; 65535].to_bytes()[..2], [0xff, 0xff]);
+}
+
+#[test]
+#[should_panic(expected = "a list of 256 items does not fit a u8 count")]
+fn version_1_lists_hold_255_items() {
+    vec![7u8; 256].to_bytes_in(1);
+}
+
+#[test]
+#[should_panic(expected = "a list of 65536 items does not fit a u16 count")]
+fn lists_hold_65535_items() {
+    vec![7u8; 65536].to_bytes();
+}
+
+#[test]
+fn malformed_input_is_an_error() {
+    let bytes = booking("gym", 4).to_bytes();
+    for len in 0..bytes.len() {
+        assert_eq!(Booking::from_bytes(&bytes[..len]), Err(DecodeError::Truncated), "{} bytes", len);
+    }
+    assert_eq!(Day::from_bytes(&[9]), Err(DecodeError::InvalidTag { type_name: "Day", tag: 9 }));
+    assert_eq!(String::from_bytes(b"\x02\x00\x00\x00\xff\xfe"), Err(DecodeError::InvalidUtf8));
+    // Version 1 strings end at a NUL
+    assert_eq!(Reader::with_version(b"gym", 1).get::<String>(), Err(DecodeError::Truncated));
+    assert_eq!(Reader::versioned(&[PROTOCOL_VERSION, 7]).err(), Some(DecodeError::InvalidFormat(7)));
+    assert!(matches!(Reader::with_format(b"{\"name\": 1}", PROTOCOL_VERSION, Format::Json).get::<QueryRequest>(), Err(DecodeError::Malformed(_))));
+    assert!(matches!(Reader::with_format(&[0xff], PROTOCOL_VERSION, Format::Cbor).get::<QueryRequest>(), Err(DecodeError::Malformed(_))));
+    assert_eq!(Reader::with_format(b"  ", PROTOCOL_VERSION, Format::Json).get::<QueryRequest>(), Err(DecodeError::Truncated));
+}
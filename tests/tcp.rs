@@ -0,0 +1,34 @@
+// Length-prefixed framing over a loopback connection. Run with
+// `cargo test --test tcp`.
+use std::io::{ErrorKind, Write};
+use std::net::{TcpListener, TcpStream};
+
+#[path = "../src/tcp.rs"]
+pub mod tcp;
+
+use tcp::{read_frame, write_frame, MAX_FRAME};
+
+/// Both ends of a fresh loopback connection.
+fn connection() -> (TcpStream, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    (client, listener.accept().unwrap().0)
+}
+
+#[test]
+fn frames_round_trip() {
+    let (mut client, mut server) = connection();
+    write_frame(&mut client, b"").unwrap();
+    write_frame(&mut client, b"hello").unwrap();
+    assert_eq!(read_frame(&mut server).unwrap(), b"");
+    assert_eq!(read_frame(&mut server).unwrap(), b"hello");
+}
+
+#[test]
+fn oversized_frames_are_refused_unread() {
+    let (mut client, mut server) = connection();
+    client.write_all(&(MAX_FRAME as u32 + 1).to_le_bytes()).unwrap();
+    assert_eq!(read_frame(&mut server).unwrap_err().kind(), ErrorKind::InvalidData);
+    client.write_all(&u32::MAX.to_le_bytes()).unwrap();
+    assert_eq!(read_frame(&mut server).unwrap_err().kind(), ErrorKind::InvalidData);
+}